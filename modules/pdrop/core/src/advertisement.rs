@@ -0,0 +1,527 @@
+//! Versioned binary self-description of a peer. The same bytes go into BLE
+//! service data and, signed, into UDP beacons; only the latter have room
+//! for a payment request. `CompactAdvertisement` is the few bytes that fit
+//! beside the service UUID; BLE advertisers go on air with its hex form as
+//! local name, the one field every platform lets them set.
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+
+use crate::{
+    codec::{self, Reader},
+    identity::{Capabilities, PeerInfo, ProtocolVersion, TransportAddress},
+    request::{PaymentRequest, RequestError},
+    rotation::{AdvertisementToken, TOKEN_LEN},
+    types::PeerId,
+};
+
+pub const ADVERTISEMENT_VERSION: u8 = 2;
+pub const COMPACT_ADVERTISEMENT_VERSION: u8 = 1;
+pub const COMPACT_ADVERTISEMENT_LEN: usize = 3 + TOKEN_LEN;
+
+const BEACON_CONTEXT: &[u8] = b"pdrop-beacon";
+const SIGNATURE_LEN: usize = 64;
+const KEY_LEN: usize = 32;
+
+const BLE_ADDRESS: u8 = 1;
+const IPV4_ADDRESS: u8 = 4;
+const IPV6_ADDRESS: u8 = 6;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdvertisementError {
+    Truncated,
+    UnsupportedVersion(u8),
+    FieldTooLong,
+    InvalidUtf8,
+    UnknownAddressKind(u8),
+    /// A compact advertisement's text form is not hex of the right length.
+    InvalidHex,
+    InvalidKey,
+    BadSignature,
+    IdMismatch,
+    InvalidRequest(RequestError),
+    /// The payment request is not the advertising peer's own.
+    RequestMismatch,
+}
+
+impl fmt::Display for AdvertisementError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdvertisementError::Truncated => write!(f, "advertisement is truncated"),
+            AdvertisementError::UnsupportedVersion(version) => {
+                write!(f, "unsupported advertisement version {version}")
+            }
+            AdvertisementError::FieldTooLong => write!(f, "advertisement field exceeds 255 bytes"),
+            AdvertisementError::InvalidUtf8 => write!(f, "advertisement field is not utf-8"),
+            AdvertisementError::UnknownAddressKind(kind) => {
+                write!(f, "unknown transport address kind {kind}")
+            }
+            AdvertisementError::InvalidHex => write!(f, "compact advertisement is not hex"),
+            AdvertisementError::InvalidKey => write!(f, "beacon carries an invalid public key"),
+            AdvertisementError::BadSignature => write!(f, "beacon signature does not verify"),
+            AdvertisementError::IdMismatch => {
+                write!(f, "beacon peer id is not the signer's fingerprint")
+            }
+            AdvertisementError::InvalidRequest(err) => write!(f, "invalid payment request: {err}"),
+            AdvertisementError::RequestMismatch => {
+                write!(f, "advertised payment request is not the peer's own")
+            }
+        }
+    }
+}
+
+impl std::error::Error for AdvertisementError {}
+
+impl From<codec::Truncated> for AdvertisementError {
+    fn from(_: codec::Truncated) -> Self {
+        AdvertisementError::Truncated
+    }
+}
+
+/// `version | id (32) | capabilities | n | versions (n) | name_len | name |
+/// n | addresses (n) [| request_len (u16) | payment request]`, counts and
+/// lengths one byte each. An empty name means none. Each address is a kind
+/// byte followed by a BLE address, or an IP address and big-endian port. A
+/// payment request, if any, must be the peer's own. Signal strength and
+/// last-seen time are properties of the reception, not of the peer, and are
+/// not encoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Advertisement {
+    pub info: PeerInfo,
+    /// Payment request the peer announces along with itself.
+    pub payment_request: Option<PaymentRequest>,
+}
+
+fn push_len(out: &mut Vec<u8>, len: usize) -> Result<(), AdvertisementError> {
+    out.push(u8::try_from(len).map_err(|_| AdvertisementError::FieldTooLong)?);
+    Ok(())
+}
+
+fn read_address(
+    reader: &mut Reader<'_, AdvertisementError>,
+) -> Result<TransportAddress, AdvertisementError> {
+    let kind = reader.byte()?;
+    let ip: IpAddr = match kind {
+        BLE_ADDRESS => return Ok(TransportAddress::Ble(reader.array()?)),
+        IPV4_ADDRESS => Ipv4Addr::from(reader.array::<4>()?).into(),
+        IPV6_ADDRESS => Ipv6Addr::from(reader.array::<16>()?).into(),
+        kind => return Err(AdvertisementError::UnknownAddressKind(kind)),
+    };
+    let port = u16::from_be_bytes(reader.array()?);
+    Ok(TransportAddress::Socket(SocketAddr::new(ip, port)))
+}
+
+fn push_address(out: &mut Vec<u8>, address: &TransportAddress) {
+    match address {
+        TransportAddress::Ble(address) => {
+            out.push(BLE_ADDRESS);
+            out.extend_from_slice(address);
+        }
+        TransportAddress::Socket(socket) => {
+            match socket.ip() {
+                IpAddr::V4(ip) => {
+                    out.push(IPV4_ADDRESS);
+                    out.extend_from_slice(&ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    out.push(IPV6_ADDRESS);
+                    out.extend_from_slice(&ip.octets());
+                }
+            }
+            out.extend_from_slice(&socket.port().to_be_bytes());
+        }
+    }
+}
+
+impl Advertisement {
+    pub fn new(info: PeerInfo) -> Self {
+        Advertisement {
+            info,
+            payment_request: None,
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, AdvertisementError> {
+        let info = &self.info;
+        let name = info.display_name.as_deref().unwrap_or_default().as_bytes();
+        let mut out = Vec::with_capacity(64 + name.len());
+        out.push(ADVERTISEMENT_VERSION);
+        out.extend_from_slice(info.id.as_bytes());
+        out.push(info.capabilities.bits());
+        push_len(&mut out, info.protocol_versions.len())?;
+        out.extend_from_slice(&info.protocol_versions);
+        push_len(&mut out, name.len())?;
+        out.extend_from_slice(name);
+        push_len(&mut out, info.addresses.len())?;
+        for address in &info.addresses {
+            push_address(&mut out, address);
+        }
+        if let Some(request) = &self.payment_request {
+            let request = request.encode();
+            let len = u16::try_from(request.len()).map_err(|_| AdvertisementError::FieldTooLong)?;
+            out.extend_from_slice(&len.to_be_bytes());
+            out.extend_from_slice(&request);
+        }
+        Ok(out)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, AdvertisementError> {
+        let mut reader = Reader::<AdvertisementError>::new(bytes);
+        let version = reader.byte()?;
+        if version != ADVERTISEMENT_VERSION {
+            return Err(AdvertisementError::UnsupportedVersion(version));
+        }
+        let mut info = PeerInfo::new(PeerId::from_bytes(reader.array()?));
+        info.capabilities = Capabilities::from_bits(reader.byte()?);
+        info.protocol_versions = reader.counted()?.to_vec();
+        let name =
+            std::str::from_utf8(reader.counted()?).map_err(|_| AdvertisementError::InvalidUtf8)?;
+        info.display_name = (!name.is_empty()).then(|| name.to_string());
+        let addresses = reader.byte()?;
+        info.addresses = (0..addresses)
+            .map(|_| read_address(&mut reader))
+            .collect::<Result<_, _>>()?;
+        let mut payment_request = None;
+        if !reader.is_empty() {
+            let len = u16::from_be_bytes(reader.array()?);
+            let request = PaymentRequest::decode(reader.take(len as usize)?)
+                .map_err(AdvertisementError::InvalidRequest)?;
+            if request.requester_id() != info.id {
+                return Err(AdvertisementError::RequestMismatch);
+            }
+            payment_request = Some(request);
+        }
+        Ok(Advertisement {
+            info,
+            payment_request,
+        })
+    }
+}
+
+/// `version | protocol version | capabilities | token (8)`: just enough for
+/// a scanner to tell a pdrop peer and what it can do, in the room BLE leaves
+/// beside the service UUID. Who it is only peers able to resolve the
+/// rotating token learn. Decoding ignores trailing bytes, which later
+/// versions may use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactAdvertisement {
+    /// Highest protocol version the peer speaks.
+    pub protocol_version: ProtocolVersion,
+    pub capabilities: Capabilities,
+    pub token: AdvertisementToken,
+}
+
+impl CompactAdvertisement {
+    pub fn new(info: &PeerInfo, token: AdvertisementToken) -> Self {
+        CompactAdvertisement {
+            protocol_version: info.protocol_versions.iter().copied().max().unwrap_or(0),
+            capabilities: info.capabilities,
+            token,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; COMPACT_ADVERTISEMENT_LEN] {
+        let mut out = [0; COMPACT_ADVERTISEMENT_LEN];
+        out[0] = COMPACT_ADVERTISEMENT_VERSION;
+        out[1] = self.protocol_version;
+        out[2] = self.capabilities.bits();
+        out[3..].copy_from_slice(self.token.as_bytes());
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, AdvertisementError> {
+        let mut reader = Reader::<AdvertisementError>::new(bytes);
+        let version = reader.byte()?;
+        if version != COMPACT_ADVERTISEMENT_VERSION {
+            return Err(AdvertisementError::UnsupportedVersion(version));
+        }
+        Ok(CompactAdvertisement {
+            protocol_version: reader.byte()?,
+            capabilities: Capabilities::from_bits(reader.byte()?),
+            token: AdvertisementToken::from_bytes(reader.array()?),
+        })
+    }
+}
+
+/// Hex of the encoded bytes, 22 characters: short enough for the local name
+/// in a scan response.
+impl fmt::Display for CompactAdvertisement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.encode()))
+    }
+}
+
+impl FromStr for CompactAdvertisement {
+    type Err = AdvertisementError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bytes = [0; COMPACT_ADVERTISEMENT_LEN];
+        hex::decode_to_slice(s, &mut bytes).map_err(|_| AdvertisementError::InvalidHex)?;
+        Self::decode(&bytes)
+    }
+}
+
+/// An advertisement signed by the advertiser's ed25519 key, for media with
+/// room to spare: `advertisement | public key | signature`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Beacon {
+    pub advertisement: Advertisement,
+    pub public_key: VerifyingKey,
+}
+
+impl Beacon {
+    pub fn encode(
+        advertisement: &Advertisement,
+        key: &SigningKey,
+    ) -> Result<Vec<u8>, AdvertisementError> {
+        let mut out = advertisement.encode()?;
+        let public_key = key.verifying_key();
+        let signature = key.sign(&Self::signed_message(&out, &public_key));
+        out.extend_from_slice(public_key.as_bytes());
+        out.extend_from_slice(&signature.to_bytes());
+        Ok(out)
+    }
+
+    /// Decodes a beacon, rejecting it unless the signature verifies and the
+    /// advertised id is the signer's fingerprint.
+    pub fn decode(bytes: &[u8]) -> Result<Self, AdvertisementError> {
+        let body_len = bytes
+            .len()
+            .checked_sub(KEY_LEN + SIGNATURE_LEN)
+            .ok_or(AdvertisementError::Truncated)?;
+        let (body, trailer) = bytes.split_at(body_len);
+        let (key, signature) = trailer.split_at(KEY_LEN);
+        let public_key = VerifyingKey::from_bytes(key.try_into().unwrap())
+            .map_err(|_| AdvertisementError::InvalidKey)?;
+        let signature = Signature::from_bytes(signature.try_into().unwrap());
+        public_key
+            .verify(&Self::signed_message(body, &public_key), &signature)
+            .map_err(|_| AdvertisementError::BadSignature)?;
+        let advertisement = Advertisement::decode(body)?;
+        if advertisement.info.id != PeerId::from_public_key(public_key.as_bytes()) {
+            return Err(AdvertisementError::IdMismatch);
+        }
+        Ok(Beacon {
+            advertisement,
+            public_key,
+        })
+    }
+
+    fn signed_message(body: &[u8], public_key: &VerifyingKey) -> Vec<u8> {
+        [BEACON_CONTEXT, public_key.as_bytes(), body].concat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        identity::{DeviceIdentity, PROTOCOL_VERSION},
+        note::Fr,
+        rotation::RotationSecret,
+    };
+    use proptest::prelude::*;
+    use std::time::{Duration, SystemTime};
+
+    fn key() -> SigningKey {
+        SigningKey::from_bytes(&[7; 32])
+    }
+
+    fn advertisement(name: Option<&str>) -> Advertisement {
+        let mut info = PeerInfo::new(PeerId::from_public_key(key().verifying_key().as_bytes()));
+        info.display_name = name.map(str::to_string);
+        Advertisement::new(info)
+    }
+
+    #[test]
+    fn advertisement_round_trips() {
+        let mut rich = advertisement(Some("Alice"));
+        rich.info.capabilities = Capabilities::RECEIVE | Capabilities::RELAY;
+        rich.info.protocol_versions = vec![PROTOCOL_VERSION, PROTOCOL_VERSION + 1];
+        rich.info.addresses = vec![
+            TransportAddress::Ble([0xc0, 1, 2, 3, 4, 5]),
+            TransportAddress::Socket("192.168.1.20:7070".parse().unwrap()),
+            TransportAddress::Socket("[fe80::1]:7071".parse().unwrap()),
+        ];
+        for ad in [rich, advertisement(Some("Alice")), advertisement(None)] {
+            assert_eq!(Advertisement::decode(&ad.encode().unwrap()).unwrap(), ad);
+        }
+    }
+
+    #[test]
+    fn advertisement_carries_only_the_peers_own_payment_request() {
+        let issue = |seed| {
+            let requester = DeviceIdentity::from_secret_keys([seed; 32], [1; 32], [2; 32]);
+            let expiry = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+            PaymentRequest::issue(&requester, Fr::from(7u64), Fr::from(25u64), expiry, None)
+                .unwrap()
+        };
+        let mut ad = advertisement(Some("Alice"));
+        ad.payment_request = Some(issue(7));
+        let bytes = ad.encode().unwrap();
+        assert_eq!(Advertisement::decode(&bytes).unwrap(), ad);
+        let beacon = Beacon::encode(&ad, &key()).unwrap();
+        assert_eq!(Beacon::decode(&beacon).unwrap().advertisement, ad);
+
+        let mut tampered = bytes;
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            Advertisement::decode(&tampered),
+            Err(AdvertisementError::InvalidRequest(
+                RequestError::BadSignature
+            ))
+        );
+        ad.payment_request = Some(issue(8));
+        assert_eq!(
+            Advertisement::decode(&ad.encode().unwrap()),
+            Err(AdvertisementError::RequestMismatch)
+        );
+    }
+
+    #[test]
+    fn advertisement_rejects_other_versions_and_truncation() {
+        let mut bytes = advertisement(Some("Alice")).encode().unwrap();
+        assert_eq!(
+            Advertisement::decode(&bytes[..bytes.len() - 1]),
+            Err(AdvertisementError::Truncated)
+        );
+        bytes[0] = ADVERTISEMENT_VERSION + 1;
+        assert_eq!(
+            Advertisement::decode(&bytes),
+            Err(AdvertisementError::UnsupportedVersion(
+                ADVERTISEMENT_VERSION + 1
+            ))
+        );
+    }
+
+    #[test]
+    fn beacon_round_trips_and_carries_the_signer() {
+        let ad = advertisement(Some("Alice"));
+        let beacon = Beacon::decode(&Beacon::encode(&ad, &key()).unwrap()).unwrap();
+        assert_eq!(beacon.advertisement, ad);
+        assert_eq!(beacon.public_key, key().verifying_key());
+    }
+
+    #[test]
+    fn beacon_rejects_tampering() {
+        let mut bytes = Beacon::encode(&advertisement(Some("Alice")), &key()).unwrap();
+        bytes[3] ^= 1;
+        assert_eq!(
+            Beacon::decode(&bytes),
+            Err(AdvertisementError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn beacon_rejects_an_id_that_is_not_the_signers() {
+        let other = SigningKey::from_bytes(&[8; 32]);
+        let bytes = Beacon::encode(&advertisement(None), &other).unwrap();
+        assert_eq!(Beacon::decode(&bytes), Err(AdvertisementError::IdMismatch));
+    }
+
+    #[test]
+    fn compact_advertisement_round_trips() {
+        let mut info = advertisement(Some("Alice")).info;
+        info.capabilities = Capabilities::RECEIVE | Capabilities::SEND;
+        info.protocol_versions = vec![PROTOCOL_VERSION, PROTOCOL_VERSION + 2];
+        let token = AdvertisementToken::derive(&RotationSecret::from_bytes([7; 32]), 7);
+        let compact = CompactAdvertisement::new(&info, token);
+        assert_eq!(compact.protocol_version, PROTOCOL_VERSION + 2);
+
+        let bytes = compact.encode();
+        assert_eq!(CompactAdvertisement::decode(&bytes), Ok(compact));
+        assert_eq!(
+            CompactAdvertisement::decode(&[bytes.as_slice(), &[9, 9]].concat()),
+            Ok(compact)
+        );
+        assert_eq!(
+            CompactAdvertisement::decode(&bytes[..COMPACT_ADVERTISEMENT_LEN - 1]),
+            Err(AdvertisementError::Truncated)
+        );
+
+        let name = compact.to_string();
+        assert_eq!(name.len(), 2 * COMPACT_ADVERTISEMENT_LEN);
+        assert_eq!(name.parse(), Ok(compact));
+        assert_eq!(
+            name[2..].parse::<CompactAdvertisement>(),
+            Err(AdvertisementError::InvalidHex)
+        );
+    }
+
+    fn arbitrary_address() -> impl Strategy<Value = TransportAddress> {
+        prop_oneof![
+            any::<[u8; 6]>().prop_map(TransportAddress::Ble),
+            // flow info and scope ids are not advertised
+            (any::<IpAddr>(), any::<u16>())
+                .prop_map(|(ip, port)| TransportAddress::Socket(SocketAddr::new(ip, port))),
+        ]
+    }
+
+    fn arbitrary_info() -> impl Strategy<Value = PeerInfo> {
+        (
+            any::<[u8; 32]>(),
+            any::<u8>(),
+            proptest::collection::vec(any::<u8>(), 0..8),
+            proptest::option::of("[^\\x00]{1,20}"),
+            proptest::collection::vec(arbitrary_address(), 0..4),
+        )
+            .prop_map(|(id, capabilities, versions, name, addresses)| PeerInfo {
+                capabilities: Capabilities::from_bits(capabilities),
+                protocol_versions: versions,
+                display_name: name,
+                addresses,
+                ..PeerInfo::new(PeerId::from_bytes(id))
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn any_advertisement_round_trips(info in arbitrary_info()) {
+            let ad = Advertisement::new(info);
+            prop_assert_eq!(Advertisement::decode(&ad.encode().unwrap()).unwrap(), ad);
+        }
+
+        #[test]
+        fn any_compact_advertisement_round_trips(
+            protocol_version in any::<u8>(),
+            capabilities in any::<u8>(),
+            token in any::<[u8; TOKEN_LEN]>(),
+        ) {
+            let compact = CompactAdvertisement {
+                protocol_version,
+                capabilities: Capabilities::from_bits(capabilities),
+                token: AdvertisementToken::from_bytes(token),
+            };
+            prop_assert_eq!(CompactAdvertisement::decode(&compact.encode()), Ok(compact));
+        }
+
+        #[test]
+        fn decoding_arbitrary_bytes_never_panics(
+            bytes in proptest::collection::vec(any::<u8>(), 0..256),
+        ) {
+            let _ = Advertisement::decode(&bytes);
+            let _ = CompactAdvertisement::decode(&bytes);
+            let _ = Beacon::decode(&bytes);
+        }
+
+        #[test]
+        fn decoding_mutated_advertisements_never_panics(
+            info in arbitrary_info(),
+            flips in proptest::collection::vec((any::<usize>(), any::<u8>()), 1..4),
+            cut in any::<usize>(),
+        ) {
+            let mut bytes = Advertisement::new(info).encode().unwrap();
+            for (index, value) in flips {
+                let len = bytes.len();
+                bytes[index % len] ^= value;
+            }
+            bytes.truncate(cut % (bytes.len() + 1));
+            let _ = Advertisement::decode(&bytes);
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! Reading the binary formats of this crate. A `Reader` walks a byte slice
+//! front to back and reports running out of bytes as `Truncated`, field
+//! elements out of range as `InvalidField`, and timestamps no `SystemTime`
+//! can hold as `InvalidTime`, which each format's error type converts from.
+
+use std::{
+    marker::PhantomData,
+    time::{Duration, SystemTime},
+};
+
+use crate::note::{FIELD_LEN, Fr, field_from_bytes};
+
+/// The input ended before the value being read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Truncated;
+
+/// A field element is not below the BN254 modulus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidField;
+
+/// A timestamp lies further from the unix epoch than this platform's
+/// `SystemTime` reaches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidTime;
+
+/// Whole seconds since the unix epoch, as timestamps are encoded; times
+/// before it count as the epoch.
+pub(crate) fn unix_time(at: SystemTime) -> u64 {
+    at.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The time `secs` seconds after the unix epoch, if `SystemTime` can hold
+/// it.
+pub(crate) fn unix_seconds(secs: u64) -> Option<SystemTime> {
+    SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+}
+
+/// `at` as it reads back once encoded.
+pub(crate) fn whole_seconds(at: SystemTime) -> SystemTime {
+    // never later than `at`, so it cannot overflow
+    SystemTime::UNIX_EPOCH + Duration::from_secs(unix_time(at))
+}
+
+/// Cursor over encoded bytes, failing with `E`.
+pub(crate) struct Reader<'a, E> {
+    bytes: &'a [u8],
+    error: PhantomData<E>,
+}
+
+impl<'a, E: From<Truncated>> Reader<'a, E> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Reader {
+            bytes,
+            error: PhantomData,
+        }
+    }
+
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8], E> {
+        let (value, rest) = self.bytes.split_at_checked(len).ok_or(Truncated)?;
+        self.bytes = rest;
+        Ok(value)
+    }
+
+    pub(crate) fn array<const N: usize>(&mut self) -> Result<[u8; N], E> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    pub(crate) fn byte(&mut self) -> Result<u8, E> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    /// Bytes prefixed with their one-byte length.
+    pub(crate) fn counted(&mut self) -> Result<&'a [u8], E> {
+        let len = self.byte()?;
+        self.take(len as usize)
+    }
+
+    pub(crate) fn field(&mut self) -> Result<Fr, E>
+    where
+        E: From<InvalidField>,
+    {
+        Ok(field_from_bytes(&self.array::<FIELD_LEN>()?).ok_or(InvalidField)?)
+    }
+
+    /// Big-endian seconds since the unix epoch.
+    pub(crate) fn timestamp(&mut self) -> Result<SystemTime, E>
+    where
+        E: From<InvalidTime>,
+    {
+        let secs = u64::from_be_bytes(self.array()?);
+        Ok(unix_seconds(secs).ok_or(InvalidTime)?)
+    }
+
+    /// Everything not read yet.
+    pub(crate) fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.bytes)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    enum Error {
+        Truncated,
+        InvalidField,
+        InvalidTime,
+    }
+
+    impl From<Truncated> for Error {
+        fn from(_: Truncated) -> Self {
+            Error::Truncated
+        }
+    }
+
+    impl From<InvalidField> for Error {
+        fn from(_: InvalidField) -> Self {
+            Error::InvalidField
+        }
+    }
+
+    impl From<InvalidTime> for Error {
+        fn from(_: InvalidTime) -> Self {
+            Error::InvalidTime
+        }
+    }
+
+    #[test]
+    fn reads_front_to_back_until_the_bytes_run_out() {
+        let mut reader = Reader::<Error>::new(&[1, 2, 3, 4, 2, 5, 6, 7]);
+        assert_eq!(reader.byte(), Ok(1));
+        assert_eq!(reader.array(), Ok([2, 3, 4]));
+        assert_eq!(reader.counted(), Ok(&[5, 6][..]));
+        assert_eq!(reader.take(2), Err(Error::Truncated));
+        assert_eq!(reader.rest(), [7]);
+        assert!(reader.is_empty());
+
+        let mut reader = Reader::<Error>::new(&[0xff; FIELD_LEN]);
+        assert_eq!(reader.field(), Err(Error::InvalidField));
+    }
+
+    #[test]
+    fn timestamps_beyond_system_time_are_refused() {
+        let at = SystemTime::UNIX_EPOCH + Duration::from_millis(1_000_500);
+        let bytes = unix_time(at).to_be_bytes();
+        let mut reader = Reader::<Error>::new(&bytes);
+        assert_eq!(reader.timestamp(), Ok(whole_seconds(at)));
+        assert_eq!(whole_seconds(at), unix_seconds(1_000).unwrap());
+
+        let mut reader = Reader::<Error>::new(&[0xff; 8]);
+        assert_eq!(reader.timestamp(), Err(Error::InvalidTime));
+    }
+}
@@ -0,0 +1,551 @@
+//! Known peers: their keys, what the user calls them and how far they are
+//! trusted, and the contact cards they are exchanged with.
+//!
+//! A card is self-issued and signed: `version | ed25519 key | x25519 key |
+//! rotation secret | name_len | name | signature`. The rotation secret lets
+//! contacts, and only them, recognise the device's advertisement tokens, so
+//! a card is for people the device wants to be found by. As text it is
+//! `PDROP:` followed by the card in unpadded base32, which QR codes carry in
+//! their compact alphanumeric mode.
+//!
+//! Book layout: `magic | version | count (u16) | entries`, each entry
+//! `card_len (u16) | card | trust | nickname_len | nickname`, lengths
+//! big-endian.
+
+use std::{collections::BTreeMap, fmt, fs, io, path::Path, str::FromStr, time::SystemTime};
+
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use x25519_dalek::PublicKey;
+
+use crate::{
+    codec::{self, Reader},
+    identity::{DeviceIdentity, PeerInfo},
+    persist::atomic_write,
+    rotation::{ROTATION_SECRET_LEN, RotationConfig, RotationSecret},
+    types::PeerId,
+};
+
+pub const CARD_VERSION: u8 = 2;
+pub const CONTACTS_VERSION: u8 = 1;
+pub const CARD_PREFIX: &str = "PDROP:";
+/// Longest name or nickname, in bytes.
+pub const MAX_NAME_LEN: usize = 64;
+
+const CARD_CONTEXT: &[u8] = b"pdrop-contact-card";
+const MAGIC: &[u8; 6] = b"PDROPC";
+const KEY_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+#[derive(Debug)]
+pub enum ContactsError {
+    IoError(io::Error),
+    NotAContactBook,
+    NotACard,
+    UnsupportedVersion(u8),
+    Truncated,
+    NameTooLong(usize),
+    InvalidUtf8,
+    InvalidKey,
+    BadSignature,
+    InvalidTrust(u8),
+}
+
+impl fmt::Display for ContactsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContactsError::IoError(err) => write!(f, "contact book i/o failed: {err}"),
+            ContactsError::NotAContactBook => write!(f, "not a pdrop contact book"),
+            ContactsError::NotACard => write!(f, "not a pdrop contact card"),
+            ContactsError::UnsupportedVersion(version) => {
+                write!(f, "unsupported contact format version {version}")
+            }
+            ContactsError::Truncated => write!(f, "contact data is truncated"),
+            ContactsError::NameTooLong(len) => {
+                write!(f, "name of {len} bytes exceeds {MAX_NAME_LEN}")
+            }
+            ContactsError::InvalidUtf8 => write!(f, "contact name is not utf-8"),
+            ContactsError::InvalidKey => write!(f, "contact card carries an invalid key"),
+            ContactsError::BadSignature => write!(f, "contact card signature does not verify"),
+            ContactsError::InvalidTrust(trust) => write!(f, "unknown trust level {trust}"),
+        }
+    }
+}
+
+impl std::error::Error for ContactsError {}
+
+impl From<codec::Truncated> for ContactsError {
+    fn from(_: codec::Truncated) -> Self {
+        ContactsError::Truncated
+    }
+}
+
+impl From<io::Error> for ContactsError {
+    fn from(err: io::Error) -> Self {
+        ContactsError::IoError(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Trust {
+    /// Known, and not to be dealt with.
+    Blocked,
+    /// Card imported, keys not checked in person.
+    Unverified,
+    /// Keys confirmed out of band, e.g. by scanning the card face to face.
+    Verified,
+}
+
+impl Trust {
+    fn to_byte(self) -> u8 {
+        match self {
+            Trust::Blocked => 0,
+            Trust::Unverified => 1,
+            Trust::Verified => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<Self, ContactsError> {
+        match byte {
+            0 => Ok(Trust::Blocked),
+            1 => Ok(Trust::Unverified),
+            2 => Ok(Trust::Verified),
+            other => Err(ContactsError::InvalidTrust(other)),
+        }
+    }
+}
+
+fn check_name(name: &str) -> Result<(), ContactsError> {
+    if name.len() > MAX_NAME_LEN {
+        return Err(ContactsError::NameTooLong(name.len()));
+    }
+    Ok(())
+}
+
+fn read_name(reader: &mut Reader<'_, ContactsError>) -> Result<String, ContactsError> {
+    let len = reader.byte()? as usize;
+    if len > MAX_NAME_LEN {
+        return Err(ContactsError::NameTooLong(len));
+    }
+    let name = std::str::from_utf8(reader.take(len)?).map_err(|_| ContactsError::InvalidUtf8)?;
+    Ok(name.to_string())
+}
+
+/// What a device tells others about itself, signed with its own key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContactCard {
+    pub signing_key: VerifyingKey,
+    pub agreement_key: PublicKey,
+    /// Key of the device's advertisement tokens.
+    pub rotation_secret: RotationSecret,
+    /// The name the device goes by.
+    pub name: String,
+    signature: Signature,
+}
+
+impl ContactCard {
+    /// The card of `identity`, to hand out as text. Contacts' cards are not
+    /// passed on: each carries a rotation secret its owner gave to the
+    /// user alone.
+    pub fn issue(identity: &DeviceIdentity, name: &str) -> Result<Self, ContactsError> {
+        check_name(name)?;
+        let (signing_key, agreement_key, rotation_secret) = (
+            identity.verifying_key(),
+            identity.agreement_public_key(),
+            identity.rotation_secret().clone(),
+        );
+        let body = Self::body(&signing_key, &agreement_key, &rotation_secret, name);
+        Ok(ContactCard {
+            signing_key,
+            agreement_key,
+            rotation_secret,
+            name: name.to_string(),
+            signature: identity.sign(&[CARD_CONTEXT, &body].concat()),
+        })
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        PeerId::from_public_key(self.signing_key.as_bytes())
+    }
+
+    fn body(
+        signing_key: &VerifyingKey,
+        agreement_key: &PublicKey,
+        rotation_secret: &RotationSecret,
+        name: &str,
+    ) -> Vec<u8> {
+        let mut out = Vec::with_capacity(2 + 2 * KEY_LEN + ROTATION_SECRET_LEN + name.len());
+        out.push(CARD_VERSION);
+        out.extend_from_slice(signing_key.as_bytes());
+        out.extend_from_slice(agreement_key.as_bytes());
+        out.extend_from_slice(rotation_secret.as_bytes());
+        out.push(name.len() as u8);
+        out.extend_from_slice(name.as_bytes());
+        out
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Self::body(
+            &self.signing_key,
+            &self.agreement_key,
+            &self.rotation_secret,
+            &self.name,
+        );
+        out.extend_from_slice(&self.signature.to_bytes());
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, ContactsError> {
+        let body_len = bytes
+            .len()
+            .checked_sub(SIGNATURE_LEN)
+            .ok_or(ContactsError::Truncated)?;
+        let (body, signature) = bytes.split_at(body_len);
+        let mut reader = Reader::<ContactsError>::new(body);
+        let version = reader.byte()?;
+        if version != CARD_VERSION {
+            return Err(ContactsError::UnsupportedVersion(version));
+        }
+        let signing_key =
+            VerifyingKey::from_bytes(&reader.array()?).map_err(|_| ContactsError::InvalidKey)?;
+        let agreement_key = PublicKey::from(reader.array::<KEY_LEN>()?);
+        let rotation_secret = RotationSecret::from_bytes(reader.array()?);
+        let name = read_name(&mut reader)?;
+        if !reader.is_empty() {
+            return Err(ContactsError::NotACard);
+        }
+        let signature = Signature::from_bytes(signature.try_into().unwrap());
+        signing_key
+            .verify(&[CARD_CONTEXT, body].concat(), &signature)
+            .map_err(|_| ContactsError::BadSignature)?;
+        Ok(ContactCard {
+            signing_key,
+            agreement_key,
+            rotation_secret,
+            name,
+            signature,
+        })
+    }
+}
+
+impl fmt::Display for ContactCard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{CARD_PREFIX}{}", base32_encode(&self.encode()))
+    }
+}
+
+impl FromStr for ContactCard {
+    type Err = ContactsError;
+
+    /// Parses the text form, as scanned. Case and surrounding whitespace
+    /// do not matter.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_ascii_uppercase();
+        let encoded = s.strip_prefix(CARD_PREFIX).ok_or(ContactsError::NotACard)?;
+        Self::decode(&base32_decode(encoded).ok_or(ContactsError::NotACard)?)
+    }
+}
+
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    let (mut buffer, mut bits) = (0u16, 0);
+    for &byte in bytes {
+        buffer = (buffer << 8) | byte as u16;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32[(buffer >> bits) as usize & 31] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32[(buffer << (5 - bits)) as usize & 31] as char);
+    }
+    out
+}
+
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u16, 0);
+    for c in text.bytes() {
+        let value = BASE32.iter().position(|&b| b == c)? as u16;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Contact {
+    pub card: ContactCard,
+    /// What the user calls the contact, whatever the card says.
+    pub nickname: String,
+    pub trust: Trust,
+}
+
+impl Contact {
+    pub fn peer_id(&self) -> PeerId {
+        self.card.peer_id()
+    }
+}
+
+/// The user's contacts, by peer id.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ContactBook {
+    contacts: BTreeMap<PeerId, Contact>,
+}
+
+impl ContactBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the card's owner under the card's own name, or updates their
+    /// card if already known, keeping nickname and trust.
+    pub fn import(&mut self, card: ContactCard, trust: Trust) -> &Contact {
+        self.contacts
+            .entry(card.peer_id())
+            .and_modify(|contact| contact.card = card.clone())
+            .or_insert_with(|| Contact {
+                nickname: card.name.clone(),
+                card,
+                trust,
+            })
+    }
+
+    pub fn get(&self, id: &PeerId) -> Option<&Contact> {
+        self.contacts.get(id)
+    }
+
+    pub fn remove(&mut self, id: &PeerId) -> Option<Contact> {
+        self.contacts.remove(id)
+    }
+
+    pub fn rename(&mut self, id: &PeerId, nickname: &str) -> Result<bool, ContactsError> {
+        check_name(nickname)?;
+        let Some(contact) = self.contacts.get_mut(id) else {
+            return Ok(false);
+        };
+        contact.nickname = nickname.to_string();
+        Ok(true)
+    }
+
+    pub fn set_trust(&mut self, id: &PeerId, trust: Trust) -> bool {
+        let Some(contact) = self.contacts.get_mut(id) else {
+            return false;
+        };
+        contact.trust = trust;
+        true
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Contact> {
+        self.contacts.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.contacts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.contacts.is_empty()
+    }
+
+    /// The contact a discovered peer is, by id or, for peers sighted under a
+    /// rotating token, by the contact whose card's rotation secret derives
+    /// that token around the time of the sighting.
+    pub fn resolve(&self, peer: &PeerInfo, rotation: &RotationConfig) -> Option<&Contact> {
+        if let Some(contact) = self.contacts.get(&peer.id) {
+            return Some(contact);
+        }
+        let token = peer.token.as_ref()?;
+        let at = peer.last_seen.unwrap_or_else(SystemTime::now);
+        self.iter()
+            .find(|contact| rotation.matches(&contact.card.rotation_secret, token, at))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.push(CONTACTS_VERSION);
+        out.extend_from_slice(&(self.contacts.len() as u16).to_be_bytes());
+        for contact in self.iter() {
+            let card = contact.card.encode();
+            out.extend_from_slice(&(card.len() as u16).to_be_bytes());
+            out.extend_from_slice(&card);
+            out.push(contact.trust.to_byte());
+            out.push(contact.nickname.len() as u8);
+            out.extend_from_slice(contact.nickname.as_bytes());
+        }
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, ContactsError> {
+        let mut reader = Reader::<ContactsError>::new(bytes);
+        if reader.take(MAGIC.len()).ok() != Some(MAGIC) {
+            return Err(ContactsError::NotAContactBook);
+        }
+        let version = reader.byte()?;
+        if version != CONTACTS_VERSION {
+            return Err(ContactsError::UnsupportedVersion(version));
+        }
+        let mut book = ContactBook::new();
+        for _ in 0..u16::from_be_bytes(reader.array()?) {
+            let card_len = u16::from_be_bytes(reader.array()?) as usize;
+            let card = ContactCard::decode(reader.take(card_len)?)?;
+            let trust = Trust::from_byte(reader.byte()?)?;
+            let nickname = read_name(&mut reader)?;
+            book.contacts.insert(
+                card.peer_id(),
+                Contact {
+                    card,
+                    nickname,
+                    trust,
+                },
+            );
+        }
+        Ok(book)
+    }
+
+    /// Writes the book to `path` through a temporary file renamed over it,
+    /// like the keystore. Cards carry rotation secrets, so the file is the
+    /// owner's only.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), ContactsError> {
+        Ok(atomic_write(path.as_ref(), &self.encode())?)
+    }
+
+    /// Loads the book at `path`, or an empty one if there is none yet.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ContactsError> {
+        match fs::read(path) {
+            Ok(bytes) => Self::decode(&bytes),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn identity(seed: u8) -> DeviceIdentity {
+        DeviceIdentity::from_secret_keys([seed; 32], [seed + 100; 32], [seed + 200; 32])
+    }
+
+    fn card(seed: u8, name: &str) -> ContactCard {
+        ContactCard::issue(&identity(seed), name).unwrap()
+    }
+
+    #[test]
+    fn cards_round_trip_through_text() {
+        let alice = card(1, "Alice");
+        let text = alice.to_string();
+        assert!(text.starts_with(CARD_PREFIX));
+        assert!(
+            text.bytes()
+                .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || b == b':')
+        );
+        assert_eq!(text.parse::<ContactCard>().unwrap(), alice);
+        assert_eq!(
+            format!(" {} \n", text.to_lowercase())
+                .parse::<ContactCard>()
+                .unwrap(),
+            alice
+        );
+        assert_eq!(alice.peer_id(), identity(1).peer_id());
+        assert_eq!(alice.agreement_key, identity(1).agreement_public_key());
+
+        let unnamed = card(2, "");
+        assert_eq!(unnamed.to_string().parse::<ContactCard>().unwrap(), unnamed);
+    }
+
+    #[test]
+    fn tampered_or_foreign_cards_are_rejected() {
+        let mut bytes = card(1, "Alice").encode();
+        let name_at = 2 + 2 * KEY_LEN + ROTATION_SECRET_LEN;
+        bytes[name_at] = b'E';
+        assert!(matches!(
+            ContactCard::decode(&bytes),
+            Err(ContactsError::BadSignature)
+        ));
+        assert!(matches!(
+            ContactCard::decode(&bytes[..SIGNATURE_LEN]),
+            Err(ContactsError::Truncated)
+        ));
+        assert!(matches!(
+            "WIFI:S:cafe;;".parse::<ContactCard>(),
+            Err(ContactsError::NotACard)
+        ));
+        assert!(matches!(
+            ContactCard::issue(&identity(1), &"x".repeat(MAX_NAME_LEN + 1)),
+            Err(ContactsError::NameTooLong(_))
+        ));
+    }
+
+    #[test]
+    fn book_keeps_nicknames_and_trust_across_saves() {
+        let mut book = ContactBook::new();
+        let alice = book.import(card(1, "Alice"), Trust::Unverified).peer_id();
+        let bob = book.import(card(2, "Bob"), Trust::Verified).peer_id();
+        assert!(book.rename(&alice, "Mum").unwrap());
+        assert!(book.set_trust(&alice, Trust::Verified));
+        assert!(book.set_trust(&bob, Trust::Blocked));
+        assert!(!book.set_trust(&identity(3).peer_id(), Trust::Verified));
+
+        // a newer card updates the keys' name but not what the user chose
+        book.import(card(1, "Alice B."), Trust::Unverified);
+        let contact = book.get(&alice).unwrap();
+        assert_eq!(
+            (contact.nickname.as_str(), contact.card.name.as_str()),
+            ("Mum", "Alice B.")
+        );
+        assert_eq!(contact.trust, Trust::Verified);
+
+        let dir = std::env::temp_dir().join(format!("pdrop-contacts-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("contacts");
+        let _ = fs::remove_file(&path);
+        assert!(ContactBook::load(&path).unwrap().is_empty());
+        book.save(&path).unwrap();
+        assert_eq!(ContactBook::load(&path).unwrap(), book);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(matches!(
+            ContactBook::decode(b"PDROPK"),
+            Err(ContactsError::NotAContactBook)
+        ));
+    }
+
+    #[test]
+    fn discovered_peers_resolve_by_id_or_rotating_token() {
+        let mut book = ContactBook::new();
+        book.import(card(1, "Alice"), Trust::Verified);
+        let rotation = RotationConfig {
+            interval: Duration::from_secs(60),
+            skew_epochs: 1,
+        };
+
+        let by_id = PeerInfo::new(identity(1).peer_id());
+        assert_eq!(book.resolve(&by_id, &rotation).unwrap().nickname, "Alice");
+
+        let heard = SystemTime::UNIX_EPOCH + Duration::from_secs(6_000);
+        let sighted = |secret: &RotationSecret, at: SystemTime| PeerInfo {
+            token: Some(rotation.token(secret, heard)),
+            last_seen: Some(at),
+            ..PeerInfo::new(PeerId::provisional(b"rotated"))
+        };
+        let alice_secret = identity(1).rotation_secret().clone();
+        let alice = sighted(&alice_secret, heard + Duration::from_secs(60));
+        assert_eq!(book.resolve(&alice, &rotation).unwrap().nickname, "Alice");
+        let stale = sighted(&alice_secret, heard + Duration::from_secs(180));
+        assert_eq!(book.resolve(&stale, &rotation), None);
+        let stranger = sighted(identity(2).rotation_secret(), heard);
+        assert_eq!(book.resolve(&stranger, &rotation), None);
+        // knowing alice's public key is not enough to follow her
+        let public = RotationSecret::from_bytes(identity(1).verifying_key().to_bytes());
+        assert_eq!(book.resolve(&sighted(&public, heard), &rotation), None);
+    }
+}
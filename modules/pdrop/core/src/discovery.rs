@@ -7,12 +7,18 @@
 
 use crate::{
     identity::PeerInfo,
+    request::PaymentRequest,
     types::{BoxFutureResponse, BoxStreamResponse},
 };
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum DiscoveryEvent {
     PeerDiscovered(PeerInfo),
     PeerLost(PeerInfo),
+    /// A peer in range advertises this request, asking whoever sees it to
+    /// pay. Reported after the peer's discovery, and again whenever the
+    /// peer advertises another one.
+    RequestAdvertised(Box<PaymentRequest>),
 }
 
 pub trait Discovery {
@@ -30,6 +36,17 @@ pub trait Advertiser {
 
     fn broadcast(&self) -> BoxFutureResponse<(), Self::Error>;
     fn stop_broadcast(&self) -> BoxFutureResponse<(), Self::Error>;
+
+    /// Attaches `request` to what `broadcast` announces, or withdraws it
+    /// with `None`, from the next broadcast on. Media without room for a
+    /// request ignore it; the request can still be sent over a session.
+    fn set_payment_request(
+        &self,
+        request: Option<PaymentRequest>,
+    ) -> BoxFutureResponse<(), Self::Error> {
+        let _ = request;
+        Box::pin(async { Ok(()) })
+    }
 }
 
 pub trait DiscoveryAdvertiser: Discovery + Advertiser {}
@@ -0,0 +1,321 @@
+//! Local history of the notes this device dropped and received, with the
+//! delivery receipts that prove the drops.
+//!
+//! File layout: `magic | version | count (u32) | entries`, each entry
+//! `direction | peer (32) | commitment | amount | at (u64 unix seconds) |
+//! memo_len | memo | has_receipt [| receipt]`, integers big-endian.
+
+use std::{fmt, fs, io, path::Path, time::SystemTime};
+
+use crate::{
+    codec::{self, Reader, unix_time},
+    note::{Fr, field_to_bytes},
+    persist::atomic_write,
+    receipt::{DeliveryReceipt, RECEIPT_LEN, ReceiptError},
+    types::PeerId,
+};
+
+pub const HISTORY_VERSION: u8 = 1;
+
+const MAGIC: &[u8; 6] = b"PDROPH";
+
+#[derive(Debug)]
+pub enum HistoryError {
+    IoError(io::Error),
+    NotAHistory,
+    UnsupportedVersion(u8),
+    Truncated,
+    InvalidDirection(u8),
+    InvalidUtf8,
+    /// A field element is not below the BN254 modulus.
+    InvalidField,
+    /// A timestamp lies beyond what this platform's clock can represent.
+    InvalidTimestamp,
+    ReceiptError(ReceiptError),
+}
+
+impl fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HistoryError::IoError(err) => write!(f, "transfer history i/o failed: {err}"),
+            HistoryError::NotAHistory => write!(f, "not a pdrop transfer history"),
+            HistoryError::UnsupportedVersion(version) => {
+                write!(f, "unsupported transfer history version {version}")
+            }
+            HistoryError::Truncated => write!(f, "transfer history is truncated"),
+            HistoryError::InvalidDirection(direction) => {
+                write!(f, "unknown transfer direction {direction}")
+            }
+            HistoryError::InvalidUtf8 => write!(f, "memo is not utf-8"),
+            HistoryError::InvalidField => write!(f, "history carries an out-of-range field"),
+            HistoryError::InvalidTimestamp => write!(f, "history timestamp is out of range"),
+            HistoryError::ReceiptError(err) => write!(f, "invalid receipt: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for HistoryError {}
+
+impl From<codec::Truncated> for HistoryError {
+    fn from(_: codec::Truncated) -> Self {
+        HistoryError::Truncated
+    }
+}
+
+impl From<codec::InvalidField> for HistoryError {
+    fn from(_: codec::InvalidField) -> Self {
+        HistoryError::InvalidField
+    }
+}
+
+impl From<codec::InvalidTime> for HistoryError {
+    fn from(_: codec::InvalidTime) -> Self {
+        HistoryError::InvalidTimestamp
+    }
+}
+
+impl From<io::Error> for HistoryError {
+    fn from(err: io::Error) -> Self {
+        HistoryError::IoError(err)
+    }
+}
+
+impl From<ReceiptError> for HistoryError {
+    fn from(err: ReceiptError) -> Self {
+        HistoryError::ReceiptError(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+impl Direction {
+    fn to_byte(self) -> u8 {
+        match self {
+            Direction::Sent => 1,
+            Direction::Received => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<Self, HistoryError> {
+        match byte {
+            1 => Ok(Direction::Sent),
+            2 => Ok(Direction::Received),
+            byte => Err(HistoryError::InvalidDirection(byte)),
+        }
+    }
+}
+
+/// One note drop, in either direction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transfer {
+    pub direction: Direction,
+    /// The other side: the recipient of a sent note, the sender of a
+    /// received one.
+    pub peer: PeerId,
+    pub commitment: Fr,
+    pub amount: Fr,
+    pub memo: Option<String>,
+    /// When it completed, to the second.
+    pub at: SystemTime,
+    /// The recipient's receipt for it.
+    pub receipt: Option<DeliveryReceipt>,
+}
+
+/// Transfers in the order they were recorded.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransferHistory {
+    transfers: Vec<Transfer>,
+}
+
+impl TransferHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, transfer: Transfer) {
+        self.transfers.push(transfer);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Transfer> {
+        self.transfers.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.transfers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transfers.is_empty()
+    }
+
+    /// The transfer of the note committed to by `commitment`, latest first.
+    pub fn find(&self, commitment: &Fr) -> Option<&Transfer> {
+        self.transfers
+            .iter()
+            .rev()
+            .find(|transfer| transfer.commitment == *commitment)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.push(HISTORY_VERSION);
+        out.extend_from_slice(&(self.transfers.len() as u32).to_be_bytes());
+        for transfer in &self.transfers {
+            let memo = transfer.memo.as_deref().unwrap_or_default().as_bytes();
+            let at = unix_time(transfer.at);
+            out.push(transfer.direction.to_byte());
+            out.extend_from_slice(transfer.peer.as_bytes());
+            out.extend_from_slice(&field_to_bytes(&transfer.commitment));
+            out.extend_from_slice(&field_to_bytes(&transfer.amount));
+            out.extend_from_slice(&at.to_be_bytes());
+            // memos are bounded well below 256 bytes when a note is sealed
+            out.push(memo.len() as u8);
+            out.extend_from_slice(memo);
+            match &transfer.receipt {
+                Some(receipt) => {
+                    out.push(1);
+                    out.extend_from_slice(&receipt.encode());
+                }
+                None => out.push(0),
+            }
+        }
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, HistoryError> {
+        let mut reader = Reader::<HistoryError>::new(bytes);
+        if reader.take(MAGIC.len()).ok() != Some(MAGIC) {
+            return Err(HistoryError::NotAHistory);
+        }
+        let version = reader.byte()?;
+        if version != HISTORY_VERSION {
+            return Err(HistoryError::UnsupportedVersion(version));
+        }
+        let mut history = TransferHistory::new();
+        for _ in 0..u32::from_be_bytes(reader.array()?) {
+            let direction = Direction::from_byte(reader.byte()?)?;
+            let peer = PeerId::from_bytes(reader.array()?);
+            let commitment = reader.field()?;
+            let amount = reader.field()?;
+            let at = reader.timestamp()?;
+            let memo_len = reader.byte()? as usize;
+            let memo = std::str::from_utf8(reader.take(memo_len)?)
+                .map_err(|_| HistoryError::InvalidUtf8)?;
+            let receipt = match reader.byte()? {
+                0 => None,
+                _ => Some(DeliveryReceipt::decode(reader.take(RECEIPT_LEN)?)?),
+            };
+            history.record(Transfer {
+                direction,
+                peer,
+                commitment,
+                amount,
+                memo: (!memo.is_empty()).then(|| memo.to_string()),
+                at,
+                receipt,
+            });
+        }
+        Ok(history)
+    }
+
+    /// Writes the history to `path` through a temporary file renamed over
+    /// it, like the contact book.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), HistoryError> {
+        Ok(atomic_write(path.as_ref(), &self.encode())?)
+    }
+
+    /// Loads the history at `path`, or an empty one if there is none yet.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, HistoryError> {
+        match fs::read(path) {
+            Ok(bytes) => Self::decode(&bytes),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{identity::DeviceIdentity, note::FIELD_LEN};
+    use std::time::Duration;
+
+    fn history() -> TransferHistory {
+        let (alice, bob) = (
+            DeviceIdentity::from_secret_keys([1; 32], [2; 32], [5; 32]),
+            DeviceIdentity::from_secret_keys([3; 32], [4; 32], [6; 32]),
+        );
+        let at = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let commitment = Fr::from(99u64);
+        let receipt = DeliveryReceipt::issue(&bob, alice.peer_id(), commitment, at);
+        let mut history = TransferHistory::new();
+        history.record(Transfer {
+            direction: Direction::Sent,
+            peer: bob.peer_id(),
+            commitment,
+            amount: Fr::from(25u64),
+            memo: Some("coffee".to_string()),
+            at,
+            receipt: Some(receipt),
+        });
+        history.record(Transfer {
+            direction: Direction::Received,
+            peer: bob.peer_id(),
+            commitment: Fr::from(98u64),
+            amount: Fr::from(5u64),
+            memo: None,
+            at,
+            receipt: None,
+        });
+        history
+    }
+
+    #[test]
+    fn history_round_trips_with_verifiable_receipts() {
+        let history = history();
+        let decoded = TransferHistory::decode(&history.encode()).unwrap();
+        assert_eq!(decoded, history);
+
+        let sent = decoded.find(&Fr::from(99u64)).unwrap();
+        let receipt = sent.receipt.as_ref().unwrap();
+        let alice = DeviceIdentity::from_secret_keys([1; 32], [2; 32], [5; 32]).peer_id();
+        assert_eq!(receipt.verify(&sent.peer, &alice, &sent.commitment), Ok(()));
+        assert!(decoded.find(&Fr::from(97u64)).is_none());
+    }
+
+    #[test]
+    fn history_is_saved_and_loaded() {
+        let dir = std::env::temp_dir().join(format!("pdrop-history-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history");
+        assert!(TransferHistory::load(&path).unwrap().is_empty());
+
+        history().save(&path).unwrap();
+        assert_eq!(TransferHistory::load(&path).unwrap(), history());
+
+        let mut bytes = fs::read(&path).unwrap();
+        bytes.truncate(bytes.len() - 1);
+        assert!(matches!(
+            TransferHistory::decode(&bytes),
+            Err(HistoryError::Truncated)
+        ));
+        // the first entry's timestamp follows direction, peer and two fields
+        let at = MAGIC.len() + 1 + 4 + 1 + PeerId::LEN + 2 * FIELD_LEN;
+        let mut corrupted = history().encode();
+        corrupted[at..at + 8].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert!(matches!(
+            TransferHistory::decode(&corrupted),
+            Err(HistoryError::InvalidTimestamp)
+        ));
+        assert!(matches!(
+            TransferHistory::decode(b"PDROPC\x01"),
+            Err(HistoryError::NotAHistory)
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,5 +1,221 @@
-use crate::types::PeerId;
+use std::{
+    fmt,
+    net::SocketAddr,
+    ops::{BitOr, BitOrAssign},
+    time::SystemTime,
+};
 
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use rand_core::OsRng;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::{
+    rotation::{AdvertisementToken, ROTATION_SECRET_LEN, RotationSecret},
+    types::PeerId,
+};
+
+/// Long-term keys of this device: an ed25519 key that signs what the device
+/// says about itself, an x25519 key that peers agree session and payload
+/// keys with, and the rotation secret its advertisement tokens are keyed
+/// with. The peer id is the fingerprint of the signing key.
+#[derive(Clone)]
+pub struct DeviceIdentity {
+    signing: SigningKey,
+    agreement: StaticSecret,
+    rotation: RotationSecret,
+}
+
+impl DeviceIdentity {
+    pub fn generate() -> Self {
+        DeviceIdentity {
+            signing: SigningKey::generate(&mut OsRng),
+            agreement: StaticSecret::random_from_rng(OsRng),
+            rotation: RotationSecret::generate(),
+        }
+    }
+
+    pub fn from_secret_keys(
+        signing: [u8; 32],
+        agreement: [u8; 32],
+        rotation: [u8; ROTATION_SECRET_LEN],
+    ) -> Self {
+        DeviceIdentity {
+            signing: SigningKey::from_bytes(&signing),
+            agreement: StaticSecret::from(agreement),
+            rotation: RotationSecret::from_bytes(rotation),
+        }
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        PeerId::from_public_key(self.verifying_key().as_bytes())
+    }
+
+    pub fn signing_key(&self) -> &SigningKey {
+        &self.signing
+    }
+
+    pub fn verifying_key(&self) -> VerifyingKey {
+        self.signing.verifying_key()
+    }
+
+    pub fn agreement_key(&self) -> &StaticSecret {
+        &self.agreement
+    }
+
+    pub fn agreement_public_key(&self) -> PublicKey {
+        PublicKey::from(&self.agreement)
+    }
+
+    /// Key of the advertisement tokens, random and stored with the keys.
+    /// Contact cards hand it out.
+    pub fn rotation_secret(&self) -> &RotationSecret {
+        &self.rotation
+    }
+
+    /// Replaces the rotation secret with a fresh one, so that whoever got
+    /// the old one with a contact card can no longer follow the device. The
+    /// peer id stays; contacts to keep need a new card, and the identity
+    /// must be saved again for the change to last.
+    pub fn regenerate_rotation_secret(&mut self) {
+        self.rotation = RotationSecret::generate();
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Signature {
+        self.signing.sign(message)
+    }
+
+    /// X25519 shared secret with a peer's agreement key.
+    pub fn agree(&self, peer: &PublicKey) -> [u8; 32] {
+        self.agreement.diffie_hellman(peer).to_bytes()
+    }
+}
+
+impl fmt::Debug for DeviceIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeviceIdentity")
+            .field("peer_id", &self.peer_id())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Version of the pdrop application protocol a peer speaks.
+pub type ProtocolVersion = u8;
+
+pub const PROTOCOL_VERSION: ProtocolVersion = 1;
+
+/// What a peer is willing to do, as a compact bit set.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Capabilities(u8);
+
+impl Capabilities {
+    /// Accepts incoming note drops.
+    pub const RECEIVE: Capabilities = Capabilities(1);
+    /// Sends note drops.
+    pub const SEND: Capabilities = Capabilities(1 << 1);
+    /// Forwards traffic for other peers.
+    pub const RELAY: Capabilities = Capabilities(1 << 2);
+
+    pub const fn empty() -> Self {
+        Capabilities(0)
+    }
+
+    pub const fn from_bits(bits: u8) -> Self {
+        Capabilities(bits)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub const fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, rhs: Capabilities) -> Capabilities {
+        Capabilities(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for Capabilities {
+    fn bitor_assign(&mut self, rhs: Capabilities) {
+        self.0 |= rhs.0;
+    }
+}
+
+/// Where a peer can be reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransportAddress {
+    /// 48-bit Bluetooth device address, most significant byte first.
+    Ble([u8; 6]),
+    Socket(SocketAddr),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PeerInfo {
     pub id: PeerId,
+    /// Whether `id` is a stand-in from `PeerId::provisional`, to be replaced
+    /// by the real one once the peer proves its key.
+    pub provisional: bool,
+    pub display_name: Option<String>,
+    pub protocol_versions: Vec<ProtocolVersion>,
+    pub capabilities: Capabilities,
+    /// Received signal strength in dBm, where the medium reports one.
+    pub rssi: Option<i16>,
+    /// Advertised transmit power in dBm; with `rssi` it hints at distance.
+    pub tx_power: Option<i16>,
+    pub last_seen: Option<SystemTime>,
+    pub addresses: Vec<TransportAddress>,
+    /// Rotating token the peer was sighted under, when the medium did not
+    /// reveal its id. Holders of the peer's key can resolve it.
+    pub token: Option<AdvertisementToken>,
+}
+
+impl PeerInfo {
+    /// A peer known only by id, speaking the current protocol.
+    pub fn new(id: PeerId) -> Self {
+        PeerInfo {
+            id,
+            provisional: false,
+            display_name: None,
+            protocol_versions: vec![PROTOCOL_VERSION],
+            capabilities: Capabilities::empty(),
+            rssi: None,
+            tx_power: None,
+            last_seen: None,
+            addresses: Vec::new(),
+            token: None,
+        }
+    }
+
+    pub fn supports(&self, version: ProtocolVersion) -> bool {
+        self.protocol_versions.contains(&version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peer_id_is_the_signing_key_fingerprint() {
+        let identity = DeviceIdentity::generate();
+        assert_eq!(
+            identity.peer_id(),
+            PeerId::from_public_key(identity.verifying_key().as_bytes())
+        );
+        assert_ne!(identity.peer_id(), DeviceIdentity::generate().peer_id());
+    }
+
+    #[test]
+    fn both_sides_agree_on_the_same_secret() {
+        let (alice, bob) = (DeviceIdentity::generate(), DeviceIdentity::generate());
+        assert_eq!(
+            alice.agree(&bob.agreement_public_key()),
+            bob.agree(&alice.agreement_public_key())
+        );
+    }
 }
@@ -0,0 +1,422 @@
+//! Passphrase-protected file storage for a `DeviceIdentity`.
+//!
+//! File layout: `magic | version | m_cost | t_cost | p_cost | salt | nonce |
+//! ciphertext`, costs little-endian u32. The key is derived from the
+//! passphrase with Argon2id under the stored parameters; the secret keys and
+//! the rotation secret are sealed with XChaCha20-Poly1305, authenticating
+//! the header as well.
+//!
+//! Version 1 files hold the two keys alone, from when the rotation secret
+//! was derived from the signing key. They open with a fresh rotation secret,
+//! and `load_or_create` writes them back in the current version.
+
+use std::{fmt, fs, io, path::Path};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    KeyInit, XChaCha20Poly1305, XNonce,
+    aead::{Aead, Payload},
+};
+use rand_core::{OsRng, RngCore};
+use zeroize::Zeroizing;
+
+use crate::{identity::DeviceIdentity, persist::atomic_write, rotation::ROTATION_SECRET_LEN};
+
+pub const KEYSTORE_VERSION: u8 = 2;
+/// Shortest passphrase a keystore is sealed under, in characters. Argon2id
+/// only slows guessing down: a four-digit PIN falls in minutes, while even
+/// an all-digit passphrase of this length takes thousands of core-years
+/// under the default costs. Length is no measure of entropy, though; a
+/// passphrase picked by the user still wants to be more than digits.
+pub const MIN_PASSPHRASE_LEN: usize = 12;
+
+const MAGIC: &[u8; 6] = b"PDROPK";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 12 + SALT_LEN + NONCE_LEN;
+const KEYS_LEN: usize = 64;
+const SECRETS_LEN: usize = KEYS_LEN + ROTATION_SECRET_LEN;
+const TAG_LEN: usize = 16;
+
+#[derive(Debug)]
+pub enum KeystoreError {
+    IoError(io::Error),
+    NotAKeystore,
+    UnsupportedVersion(u8),
+    InvalidParams(argon2::Error),
+    /// Costs above `KdfParams::MAX`, as only a crafted file would carry.
+    ParamsTooHigh(KdfParams),
+    /// The passphrase to seal under is shorter than `MIN_PASSPHRASE_LEN`.
+    WeakPassphrase,
+    /// The passphrase is wrong or the file was modified.
+    DecryptionFailed,
+}
+
+impl fmt::Display for KeystoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeystoreError::IoError(err) => write!(f, "keystore i/o failed: {err}"),
+            KeystoreError::NotAKeystore => write!(f, "not a pdrop keystore"),
+            KeystoreError::UnsupportedVersion(version) => {
+                write!(f, "unsupported keystore version {version}")
+            }
+            KeystoreError::InvalidParams(err) => write!(f, "invalid key derivation params: {err}"),
+            KeystoreError::ParamsTooHigh(params) => {
+                write!(
+                    f,
+                    "key derivation params {params:?} exceed the allowed maximum"
+                )
+            }
+            KeystoreError::WeakPassphrase => write!(
+                f,
+                "passphrase is shorter than {MIN_PASSPHRASE_LEN} characters"
+            ),
+            KeystoreError::DecryptionFailed => {
+                write!(f, "wrong passphrase or corrupted keystore")
+            }
+        }
+    }
+}
+
+impl std::error::Error for KeystoreError {}
+
+impl From<io::Error> for KeystoreError {
+    fn from(err: io::Error) -> Self {
+        KeystoreError::IoError(err)
+    }
+}
+
+impl From<argon2::Error> for KeystoreError {
+    fn from(err: argon2::Error) -> Self {
+        KeystoreError::InvalidParams(err)
+    }
+}
+
+/// Argon2id cost parameters. The defaults follow the OWASP recommendation;
+/// they are stored with the keystore, so raising them later does not lock
+/// out existing files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    /// Memory in KiB.
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl KdfParams {
+    /// The highest costs accepted, four times the defaults. The costs are
+    /// read from the file before it is authenticated, so without a cap a
+    /// crafted keystore could demand gigabytes or an endless derivation.
+    pub const MAX: KdfParams = KdfParams {
+        m_cost: 4 * Params::DEFAULT_M_COST,
+        t_cost: 4 * Params::DEFAULT_T_COST,
+        p_cost: 4 * Params::DEFAULT_P_COST,
+    };
+
+    fn within_max(&self) -> bool {
+        self.m_cost <= Self::MAX.m_cost
+            && self.t_cost <= Self::MAX.t_cost
+            && self.p_cost <= Self::MAX.p_cost
+    }
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams {
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+fn derive_key(
+    passphrase: &[u8],
+    salt: &[u8],
+    params: KdfParams,
+) -> Result<Zeroizing<[u8; 32]>, KeystoreError> {
+    if !params.within_max() {
+        return Err(KeystoreError::ParamsTooHigh(params));
+    }
+    let params = Params::new(params.m_cost, params.t_cost, params.p_cost, Some(32))?;
+    let mut key = Zeroizing::new([0; 32]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase, salt, &mut *key)?;
+    Ok(key)
+}
+
+/// Encrypts `identity` under `passphrase`, which must be at least
+/// `MIN_PASSPHRASE_LEN` characters long. Keystores sealed under shorter
+/// ones before still open.
+pub fn seal(
+    identity: &DeviceIdentity,
+    passphrase: &str,
+    params: KdfParams,
+) -> Result<Vec<u8>, KeystoreError> {
+    let mut secrets = Zeroizing::new([0; SECRETS_LEN]);
+    secrets[..32].copy_from_slice(identity.signing_key().as_bytes());
+    secrets[32..KEYS_LEN].copy_from_slice(identity.agreement_key().as_bytes());
+    secrets[KEYS_LEN..].copy_from_slice(identity.rotation_secret().as_bytes());
+    seal_secrets(KEYSTORE_VERSION, &secrets[..], passphrase, params)
+}
+
+fn seal_secrets(
+    version: u8,
+    secrets: &[u8],
+    passphrase: &str,
+    params: KdfParams,
+) -> Result<Vec<u8>, KeystoreError> {
+    if passphrase.chars().count() < MIN_PASSPHRASE_LEN {
+        return Err(KeystoreError::WeakPassphrase);
+    }
+    let mut salt = [0; SALT_LEN];
+    let mut nonce = [0; NONCE_LEN];
+    OsRng.fill_bytes(&mut salt);
+    OsRng.fill_bytes(&mut nonce);
+
+    let mut out = Vec::with_capacity(HEADER_LEN + secrets.len() + TAG_LEN);
+    out.extend_from_slice(MAGIC);
+    out.push(version);
+    for cost in [params.m_cost, params.t_cost, params.p_cost] {
+        out.extend_from_slice(&cost.to_le_bytes());
+    }
+    out.extend_from_slice(&salt);
+    out.extend_from_slice(&nonce);
+
+    let key = derive_key(passphrase.as_bytes(), &salt, params)?;
+    let payload = Payload {
+        msg: secrets,
+        aad: &out,
+    };
+    let ciphertext = XChaCha20Poly1305::new((&*key).into())
+        .encrypt(XNonce::from_slice(&nonce), payload)
+        .map_err(|_| KeystoreError::DecryptionFailed)?;
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+}
+
+/// Decrypts a keystore produced by `seal`.
+pub fn open(bytes: &[u8], passphrase: &str) -> Result<DeviceIdentity, KeystoreError> {
+    if bytes.len() < HEADER_LEN || !bytes.starts_with(MAGIC) {
+        return Err(KeystoreError::NotAKeystore);
+    }
+    let (header, ciphertext) = bytes.split_at(HEADER_LEN);
+    let secrets_len = match header[MAGIC.len()] {
+        1 => KEYS_LEN,
+        KEYSTORE_VERSION => SECRETS_LEN,
+        version => return Err(KeystoreError::UnsupportedVersion(version)),
+    };
+    if ciphertext.len() != secrets_len + TAG_LEN {
+        return Err(KeystoreError::NotAKeystore);
+    }
+    let cost = |i: usize| {
+        let at = MAGIC.len() + 1 + 4 * i;
+        u32::from_le_bytes(header[at..at + 4].try_into().unwrap())
+    };
+    let params = KdfParams {
+        m_cost: cost(0),
+        t_cost: cost(1),
+        p_cost: cost(2),
+    };
+    let salt = &header[HEADER_LEN - NONCE_LEN - SALT_LEN..HEADER_LEN - NONCE_LEN];
+    let nonce = &header[HEADER_LEN - NONCE_LEN..];
+
+    let key = derive_key(passphrase.as_bytes(), salt, params)?;
+    let payload = Payload {
+        msg: ciphertext,
+        aad: header,
+    };
+    let secrets = Zeroizing::new(
+        XChaCha20Poly1305::new((&*key).into())
+            .decrypt(XNonce::from_slice(nonce), payload)
+            .map_err(|_| KeystoreError::DecryptionFailed)?,
+    );
+    let mut rotation = Zeroizing::new([0; ROTATION_SECRET_LEN]);
+    match secrets.get(KEYS_LEN..).filter(|secret| !secret.is_empty()) {
+        Some(secret) => rotation.copy_from_slice(secret),
+        None => OsRng.fill_bytes(&mut *rotation),
+    }
+    Ok(DeviceIdentity::from_secret_keys(
+        secrets[..32].try_into().unwrap(),
+        secrets[32..KEYS_LEN].try_into().unwrap(),
+        *rotation,
+    ))
+}
+
+/// Writes `identity` to `path`, replacing any previous keystore. The file is
+/// written next to its destination, synced and renamed over it, so a crash
+/// never leaves a half-written keystore behind.
+pub fn save(
+    path: impl AsRef<Path>,
+    identity: &DeviceIdentity,
+    passphrase: &str,
+    params: KdfParams,
+) -> Result<(), KeystoreError> {
+    let sealed = seal(identity, passphrase, params)?;
+    Ok(atomic_write(path.as_ref(), &sealed)?)
+}
+
+pub fn load(path: impl AsRef<Path>, passphrase: &str) -> Result<DeviceIdentity, KeystoreError> {
+    open(&fs::read(path)?, passphrase)
+}
+
+/// Loads the identity stored at `path`, or generates one and stores it
+/// there on first run. A keystore of an older version is written back in
+/// the current one, so that the rotation secret it opened with lasts.
+pub fn load_or_create(
+    path: impl AsRef<Path>,
+    passphrase: &str,
+    params: KdfParams,
+) -> Result<DeviceIdentity, KeystoreError> {
+    let path = path.as_ref();
+    match fs::read(path) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            let identity = DeviceIdentity::generate();
+            save(path, &identity, passphrase, params)?;
+            Ok(identity)
+        }
+        Err(err) => Err(err.into()),
+        Ok(bytes) => {
+            let identity = open(&bytes, passphrase)?;
+            if bytes[MAGIC.len()] != KEYSTORE_VERSION {
+                save(path, &identity, passphrase, params)?;
+            }
+            Ok(identity)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cheap enough for debug-build tests.
+    const TEST_PARAMS: KdfParams = KdfParams {
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    fn same_keys(a: &DeviceIdentity, b: &DeviceIdentity) -> bool {
+        a.signing_key().as_bytes() == b.signing_key().as_bytes()
+            && a.agreement_key().as_bytes() == b.agreement_key().as_bytes()
+            && a.rotation_secret() == b.rotation_secret()
+    }
+
+    #[test]
+    fn sealed_identity_opens_with_the_passphrase_only() {
+        let identity = DeviceIdentity::generate();
+        let sealed = seal(&identity, PASSPHRASE, TEST_PARAMS).unwrap();
+
+        assert!(same_keys(&open(&sealed, PASSPHRASE).unwrap(), &identity));
+        assert!(matches!(
+            open(&sealed, "correct horse battery stapler"),
+            Err(KeystoreError::DecryptionFailed)
+        ));
+    }
+
+    #[test]
+    fn short_passphrases_are_refused() {
+        let identity = DeviceIdentity::generate();
+        assert!(matches!(
+            seal(&identity, "1234", TEST_PARAMS),
+            Err(KeystoreError::WeakPassphrase)
+        ));
+        // counted in characters, not bytes
+        assert!(matches!(
+            seal(&identity, &"é".repeat(MIN_PASSPHRASE_LEN - 1), TEST_PARAMS),
+            Err(KeystoreError::WeakPassphrase)
+        ));
+        assert!(seal(&identity, &"9".repeat(MIN_PASSPHRASE_LEN), TEST_PARAMS).is_ok());
+    }
+
+    #[test]
+    fn tampered_header_or_body_is_rejected() {
+        let sealed = seal(&DeviceIdentity::generate(), PASSPHRASE, TEST_PARAMS).unwrap();
+        // a salt bit and a ciphertext bit
+        for at in [MAGIC.len() + 13, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[at] ^= 1;
+            assert!(matches!(
+                open(&tampered, PASSPHRASE),
+                Err(KeystoreError::DecryptionFailed)
+            ));
+        }
+        assert!(matches!(
+            open(&sealed[1..], PASSPHRASE),
+            Err(KeystoreError::NotAKeystore)
+        ));
+    }
+
+    #[test]
+    fn costs_above_the_cap_are_refused_before_deriving() {
+        let identity = DeviceIdentity::generate();
+        let greedy = KdfParams {
+            m_cost: KdfParams::MAX.m_cost + 1,
+            ..TEST_PARAMS
+        };
+        assert!(matches!(
+            seal(&identity, PASSPHRASE, greedy),
+            Err(KeystoreError::ParamsTooHigh(params)) if params == greedy
+        ));
+
+        let mut crafted = seal(&identity, PASSPHRASE, TEST_PARAMS).unwrap();
+        // t_cost, the second cost in the header
+        let at = MAGIC.len() + 1 + 4;
+        crafted[at..at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            open(&crafted, PASSPHRASE),
+            Err(KeystoreError::ParamsTooHigh(params)) if params.t_cost == u32::MAX
+        ));
+        assert!(KdfParams::default().within_max());
+    }
+
+    #[test]
+    fn identity_survives_a_round_trip_through_a_file() {
+        let dir = std::env::temp_dir().join(format!("pdrop-keystore-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("identity.key");
+        let _ = fs::remove_file(&path);
+
+        let created = load_or_create(&path, PASSPHRASE, TEST_PARAMS).unwrap();
+        let loaded = load_or_create(&path, PASSPHRASE, TEST_PARAMS).unwrap();
+        assert!(same_keys(&created, &loaded));
+        assert_eq!(loaded.peer_id(), created.peer_id());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn version_1_keystores_are_upgraded_with_a_fresh_rotation_secret() {
+        let dir = std::env::temp_dir().join(format!("pdrop-keystore-v1-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("identity.key");
+
+        let identity = DeviceIdentity::generate();
+        let mut keys = Zeroizing::new([0; KEYS_LEN]);
+        keys[..32].copy_from_slice(identity.signing_key().as_bytes());
+        keys[32..].copy_from_slice(identity.agreement_key().as_bytes());
+        let sealed = seal_secrets(1, &keys[..], PASSPHRASE, TEST_PARAMS).unwrap();
+        fs::write(&path, &sealed).unwrap();
+
+        let upgraded = load_or_create(&path, PASSPHRASE, TEST_PARAMS).unwrap();
+        assert_eq!(upgraded.peer_id(), identity.peer_id());
+        assert_ne!(upgraded.rotation_secret(), identity.rotation_secret());
+        assert_eq!(fs::read(&path).unwrap()[MAGIC.len()], KEYSTORE_VERSION);
+        assert!(same_keys(&load(&path, PASSPHRASE).unwrap(), &upgraded));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_regenerated_rotation_secret_lasts_once_saved() {
+        let mut identity = DeviceIdentity::generate();
+        let old = identity.rotation_secret().clone();
+        identity.regenerate_rotation_secret();
+        assert_ne!(identity.rotation_secret(), &old);
+        let sealed = seal(&identity, PASSPHRASE, TEST_PARAMS).unwrap();
+        assert!(same_keys(&open(&sealed, PASSPHRASE).unwrap(), &identity));
+    }
+}
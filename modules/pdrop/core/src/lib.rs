@@ -1,3 +1,20 @@
+pub mod advertisement;
+pub mod codec;
+pub mod contacts;
 pub mod discovery;
+pub mod history;
 pub mod identity;
+pub mod keystore;
+pub mod note;
+pub mod payload;
+mod persist;
+mod poseidon2;
+pub mod protocol;
+pub mod receipt;
+pub mod request;
+pub mod rotation;
+pub mod session;
+pub mod spend;
+pub mod transfer;
+pub mod transport;
 pub mod types;
@@ -0,0 +1,109 @@
+//! payv notes as the circuits in `circuit/payv_logic` see them. A note is
+//! committed to on chain by a Poseidon2 hash of its fields and spent by
+//! revealing its nullifier; both must come out exactly as in `models.nr`.
+
+use std::fmt;
+
+use ark_ff::{BigInteger, PrimeField};
+
+pub use ark_bn254::Fr;
+
+use crate::poseidon2;
+
+/// Length of a field element in its canonical big-endian encoding.
+pub const FIELD_LEN: usize = 32;
+
+pub fn field_to_bytes(value: &Fr) -> [u8; FIELD_LEN] {
+    value.into_bigint().to_bytes_be().try_into().unwrap()
+}
+
+/// Decodes a big-endian field element, or `None` if `bytes` is not below
+/// the modulus.
+pub fn field_from_bytes(bytes: &[u8; FIELD_LEN]) -> Option<Fr> {
+    let value = Fr::from_be_bytes_mod_order(bytes);
+    (field_to_bytes(&value) == *bytes).then_some(value)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Note {
+    pub owner: Fr,
+    pub value: Fr,
+    pub secret: Fr,
+}
+
+impl Note {
+    pub fn new(owner: impl Into<Fr>, value: impl Into<Fr>, secret: impl Into<Fr>) -> Self {
+        Note {
+            owner: owner.into(),
+            value: value.into(),
+            secret: secret.into(),
+        }
+    }
+
+    /// `poseidon2([owner, value, secret])`, the note's on-chain commitment.
+    pub fn commit(&self) -> Fr {
+        poseidon2::hash(&[self.owner, self.value, self.secret])
+    }
+
+    /// `poseidon2([commitment, secret])`, revealed when the note is spent.
+    pub fn nullifier(&self) -> Fr {
+        poseidon2::hash(&[self.commit(), self.secret])
+    }
+
+    pub fn verify_commitment(&self, commitment: &Fr) -> bool {
+        self.commit() == *commitment
+    }
+}
+
+impl fmt::Debug for Note {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Note")
+            .field("owner", &self.owner)
+            .field("value", &self.value)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::poseidon2::tests::fr;
+
+    // Outputs of the tests in circuit/payv_logic/src/models.nr.
+    const COMMITMENT: &str = "1b16b78f13bd9d7adb91446d4dd2385c76b504f27c07f9fab70e20bda02a3ffc";
+    const NULLIFIER: &str = "1e1faac8497b9f42fc805dc55f7c868fc1aeda123119bff6dfb90674e1a60687";
+
+    #[test]
+    fn commitment_and_nullifier_match_the_circuit() {
+        let note = Note::new(1u64, 100u64, 1234u64);
+        assert_eq!(note.commit(), fr(COMMITMENT));
+        assert_eq!(note.nullifier(), fr(NULLIFIER));
+        assert!(note.verify_commitment(&fr(COMMITMENT)));
+
+        let other = Note::new(1u64, 100u64, 1233u64);
+        assert_eq!(
+            other.commit(),
+            fr("1696c04d9ad460b41c60a7e82b0acd3660fe9f3f6a0367922333e379edce0ea2")
+        );
+        assert_eq!(
+            other.nullifier(),
+            fr("2f471efadb604e3d406c125816dc275b3619f5199afe21db50c7004432480c6d")
+        );
+        assert!(!other.verify_commitment(&fr(COMMITMENT)));
+    }
+
+    #[test]
+    fn field_bytes_round_trip_and_reject_values_over_the_modulus() {
+        let commitment = fr(COMMITMENT);
+        let bytes = field_to_bytes(&commitment);
+        assert_eq!(hex::encode(bytes), COMMITMENT);
+        assert_eq!(field_from_bytes(&bytes), Some(commitment));
+        assert_eq!(field_from_bytes(&[0xff; FIELD_LEN]), None);
+    }
+
+    #[test]
+    fn debug_leaves_out_the_secret() {
+        let note = Note::new(1u64, 100u64, 1234u64);
+        assert!(!format!("{note:?}").contains("1234"));
+    }
+}
@@ -0,0 +1,332 @@
+//! The note drop: a note handed to a nearby recipient, sealed so only they
+//! can read it and so they know who sent it.
+//!
+//! Wire layout: `version | ephemeral x25519 key | ciphertext`. The
+//! ciphertext is ChaCha20-Poly1305 under a key derived from an ephemeral
+//! Diffie-Hellman with the recipient's agreement key, with the version and
+//! ephemeral key as associated data. The plaintext is `owner | value |
+//! secret | commitment | sender ed25519 key | memo_len | memo | signature`,
+//! field elements 32 bytes big-endian, the signature covering everything
+//! before it and the recipient's agreement key.
+//!
+//! Opening checks the signature and that the note hashes to the carried
+//! commitment, so a payload that decrypts but was not made from a real note
+//! is rejected before anything of it is shown.
+
+use std::fmt;
+
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce, aead::Aead, aead::Payload};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use rand_core::OsRng;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+use zeroize::Zeroizing;
+
+use crate::{
+    identity::DeviceIdentity,
+    note::{FIELD_LEN, Fr, Note, field_from_bytes, field_to_bytes},
+    types::PeerId,
+};
+
+pub const PAYLOAD_VERSION: u8 = 1;
+
+/// Longest memo in bytes. Keeps a full payload within a few BLE writes.
+pub const MAX_MEMO_LEN: usize = 140;
+
+const KEY_CONTEXT: &[u8] = b"pdrop-payload-key";
+const SIGNATURE_CONTEXT: &[u8] = b"pdrop-payload";
+const KEY_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+const TAG_LEN: usize = 16;
+const HEADER_LEN: usize = 1 + KEY_LEN;
+const FIXED_BODY_LEN: usize = 4 * FIELD_LEN + KEY_LEN + 1;
+
+/// Largest sealed payload, with a memo of `MAX_MEMO_LEN` bytes.
+pub const MAX_PAYLOAD_LEN: usize =
+    HEADER_LEN + FIXED_BODY_LEN + MAX_MEMO_LEN + SIGNATURE_LEN + TAG_LEN;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PayloadError {
+    Truncated,
+    TooLarge(usize),
+    UnsupportedVersion(u8),
+    MemoTooLong(usize),
+    InvalidUtf8,
+    /// A field element is not below the BN254 modulus.
+    InvalidField,
+    InvalidKey,
+    /// Not sealed to this device, or modified in transit.
+    DecryptionFailed,
+    BadSignature,
+    /// The note does not hash to the commitment it came with.
+    CommitmentMismatch,
+}
+
+impl fmt::Display for PayloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PayloadError::Truncated => write!(f, "payload is truncated"),
+            PayloadError::TooLarge(len) => {
+                write!(f, "payload of {len} bytes exceeds {MAX_PAYLOAD_LEN}")
+            }
+            PayloadError::UnsupportedVersion(version) => {
+                write!(f, "unsupported payload version {version}")
+            }
+            PayloadError::MemoTooLong(len) => {
+                write!(f, "memo of {len} bytes exceeds {MAX_MEMO_LEN}")
+            }
+            PayloadError::InvalidUtf8 => write!(f, "memo is not utf-8"),
+            PayloadError::InvalidField => write!(f, "payload carries an out-of-range field"),
+            PayloadError::InvalidKey => write!(f, "payload carries an invalid public key"),
+            PayloadError::DecryptionFailed => {
+                write!(f, "payload is not for this device or was modified")
+            }
+            PayloadError::BadSignature => write!(f, "payload signature does not verify"),
+            PayloadError::CommitmentMismatch => {
+                write!(f, "note does not match its commitment")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PayloadError {}
+
+/// An opened note drop.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NoteDrop {
+    pub note: Note,
+    pub commitment: Fr,
+    pub memo: Option<String>,
+    pub sender: VerifyingKey,
+}
+
+impl NoteDrop {
+    pub fn sender_id(&self) -> PeerId {
+        PeerId::from_public_key(self.sender.as_bytes())
+    }
+
+    /// Seals `note` from `sender` to the holder of the agreement key
+    /// `recipient`.
+    pub fn seal(
+        note: &Note,
+        memo: Option<&str>,
+        sender: &DeviceIdentity,
+        recipient: &PublicKey,
+    ) -> Result<Vec<u8>, PayloadError> {
+        let memo = memo.unwrap_or_default().as_bytes();
+        if memo.len() > MAX_MEMO_LEN {
+            return Err(PayloadError::MemoTooLong(memo.len()));
+        }
+        let mut body = Zeroizing::new(Vec::with_capacity(
+            FIXED_BODY_LEN + memo.len() + SIGNATURE_LEN,
+        ));
+        for field in [note.owner, note.value, note.secret, note.commit()] {
+            body.extend_from_slice(&field_to_bytes(&field));
+        }
+        body.extend_from_slice(sender.verifying_key().as_bytes());
+        body.push(memo.len() as u8);
+        body.extend_from_slice(memo);
+        let signature = sender.sign(&signed_message(&body, recipient));
+        body.extend_from_slice(&signature.to_bytes());
+
+        let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+        let ephemeral_public = PublicKey::from(&ephemeral);
+        let shared = ephemeral.diffie_hellman(recipient);
+        if !shared.was_contributory() {
+            return Err(PayloadError::InvalidKey);
+        }
+        let mut out = Vec::with_capacity(HEADER_LEN + body.len() + TAG_LEN);
+        out.push(PAYLOAD_VERSION);
+        out.extend_from_slice(ephemeral_public.as_bytes());
+        let cipher = cipher(shared.as_bytes(), &ephemeral_public, recipient);
+        let ciphertext = cipher
+            .encrypt(
+                &Nonce::default(),
+                Payload {
+                    msg: &body,
+                    aad: &out,
+                },
+            )
+            .expect("payload is within aead limits");
+        out.extend_from_slice(&ciphertext);
+        Ok(out)
+    }
+
+    /// Opens a payload sealed to `recipient`, verifying the sender's
+    /// signature and the note's commitment.
+    pub fn open(bytes: &[u8], recipient: &DeviceIdentity) -> Result<Self, PayloadError> {
+        if bytes.len() > MAX_PAYLOAD_LEN {
+            return Err(PayloadError::TooLarge(bytes.len()));
+        }
+        let (header, ciphertext) = bytes
+            .split_at_checked(HEADER_LEN)
+            .ok_or(PayloadError::Truncated)?;
+        if header[0] != PAYLOAD_VERSION {
+            return Err(PayloadError::UnsupportedVersion(header[0]));
+        }
+        let ephemeral = PublicKey::from(<[u8; KEY_LEN]>::try_from(&header[1..]).unwrap());
+        let shared = Zeroizing::new(recipient.agree(&ephemeral));
+        if *shared == [0; 32] {
+            return Err(PayloadError::InvalidKey);
+        }
+        let own_key = recipient.agreement_public_key();
+        let body = cipher(&shared, &ephemeral, &own_key)
+            .decrypt(
+                &Nonce::default(),
+                Payload {
+                    msg: ciphertext,
+                    aad: header,
+                },
+            )
+            .map(Zeroizing::new)
+            .map_err(|_| PayloadError::DecryptionFailed)?;
+        Self::decode(&body, &own_key)
+    }
+
+    fn decode(body: &[u8], recipient: &PublicKey) -> Result<Self, PayloadError> {
+        let signed_len = body
+            .len()
+            .checked_sub(SIGNATURE_LEN)
+            .ok_or(PayloadError::Truncated)?;
+        let (signed, signature) = body.split_at(signed_len);
+        let (fixed, memo) = signed
+            .split_at_checked(FIXED_BODY_LEN)
+            .ok_or(PayloadError::Truncated)?;
+        if memo.len() != fixed[FIXED_BODY_LEN - 1] as usize {
+            return Err(PayloadError::Truncated);
+        }
+
+        let field = |index: usize| {
+            let bytes = fixed[index * FIELD_LEN..][..FIELD_LEN].try_into().unwrap();
+            field_from_bytes(bytes).ok_or(PayloadError::InvalidField)
+        };
+        let note = Note {
+            owner: field(0)?,
+            value: field(1)?,
+            secret: field(2)?,
+        };
+        let commitment = field(3)?;
+        let sender =
+            VerifyingKey::from_bytes(fixed[4 * FIELD_LEN..][..KEY_LEN].try_into().unwrap())
+                .map_err(|_| PayloadError::InvalidKey)?;
+        let signature = Signature::from_bytes(signature.try_into().unwrap());
+        sender
+            .verify(&signed_message(signed, recipient), &signature)
+            .map_err(|_| PayloadError::BadSignature)?;
+        if !note.verify_commitment(&commitment) {
+            return Err(PayloadError::CommitmentMismatch);
+        }
+        let memo = std::str::from_utf8(memo).map_err(|_| PayloadError::InvalidUtf8)?;
+        Ok(NoteDrop {
+            note,
+            commitment,
+            memo: (!memo.is_empty()).then(|| memo.to_string()),
+            sender,
+        })
+    }
+}
+
+fn signed_message(body: &[u8], recipient: &PublicKey) -> Vec<u8> {
+    [SIGNATURE_CONTEXT, recipient.as_bytes(), body].concat()
+}
+
+/// Each payload has its own ephemeral key, so a fixed nonce never repeats
+/// under the same key.
+fn cipher(shared: &[u8; 32], ephemeral: &PublicKey, recipient: &PublicKey) -> ChaCha20Poly1305 {
+    let key = Sha256::new()
+        .chain_update(KEY_CONTEXT)
+        .chain_update(shared)
+        .chain_update(ephemeral.as_bytes())
+        .chain_update(recipient.as_bytes())
+        .finalize();
+    ChaCha20Poly1305::new(&key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity(seed: u8) -> DeviceIdentity {
+        DeviceIdentity::from_secret_keys([seed; 32], [seed + 1; 32], [seed + 2; 32])
+    }
+
+    fn note() -> Note {
+        Note::new(1u64, 100u64, 1234u64)
+    }
+
+    #[test]
+    fn payload_round_trips_to_the_recipient() {
+        let (alice, bob) = (identity(1), identity(3));
+        let to = bob.agreement_public_key();
+        for memo in [Some("lunch"), None] {
+            let sealed = NoteDrop::seal(&note(), memo, &alice, &to).unwrap();
+            let drop = NoteDrop::open(&sealed, &bob).unwrap();
+            assert_eq!(drop.note, note());
+            assert_eq!(drop.commitment, note().commit());
+            assert_eq!(drop.memo.as_deref(), memo);
+            assert_eq!(drop.sender_id(), alice.peer_id());
+        }
+    }
+
+    #[test]
+    fn only_the_recipient_can_open_it() {
+        let (alice, bob, carol) = (identity(1), identity(3), identity(5));
+        let sealed = NoteDrop::seal(&note(), None, &alice, &bob.agreement_public_key()).unwrap();
+        assert_eq!(
+            NoteDrop::open(&sealed, &carol),
+            Err(PayloadError::DecryptionFailed)
+        );
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let (alice, bob) = (identity(1), identity(3));
+        let sealed = NoteDrop::seal(&note(), None, &alice, &bob.agreement_public_key()).unwrap();
+        for index in [0, 5, HEADER_LEN + 40, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[index] ^= 1;
+            assert!(NoteDrop::open(&tampered, &bob).is_err(), "byte {index}");
+        }
+        assert_eq!(
+            NoteDrop::open(&sealed[..HEADER_LEN - 1], &bob),
+            Err(PayloadError::Truncated)
+        );
+    }
+
+    #[test]
+    fn a_signed_note_that_misses_its_commitment_is_rejected() {
+        let (alice, bob) = (identity(1), identity(3));
+        let recipient = bob.agreement_public_key();
+        let mut body = Vec::new();
+        for field in [1u64, 100, 1234, 99].map(Fr::from) {
+            body.extend_from_slice(&field_to_bytes(&field));
+        }
+        body.extend_from_slice(alice.verifying_key().as_bytes());
+        body.push(0);
+        let signature = alice.sign(&signed_message(&body, &recipient));
+        body.extend_from_slice(&signature.to_bytes());
+        assert_eq!(
+            NoteDrop::decode(&body, &recipient),
+            Err(PayloadError::CommitmentMismatch)
+        );
+    }
+
+    #[test]
+    fn memos_and_payloads_are_bounded() {
+        let (alice, bob) = (identity(1), identity(3));
+        let to = bob.agreement_public_key();
+        let memo = "x".repeat(MAX_MEMO_LEN);
+        let sealed = NoteDrop::seal(&note(), Some(&memo), &alice, &to).unwrap();
+        assert_eq!(sealed.len(), MAX_PAYLOAD_LEN);
+        assert_eq!(
+            NoteDrop::seal(&note(), Some(&format!("{memo}x")), &alice, &to),
+            Err(PayloadError::MemoTooLong(MAX_MEMO_LEN + 1))
+        );
+        let mut oversized = sealed;
+        oversized.push(0);
+        assert_eq!(
+            NoteDrop::open(&oversized, &bob),
+            Err(PayloadError::TooLarge(MAX_PAYLOAD_LEN + 1))
+        );
+    }
+}
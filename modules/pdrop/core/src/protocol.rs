@@ -0,0 +1,338 @@
+//! The pdrop application protocol, spoken inside a Noise session once the
+//! handshake is done. A drop asks for consent before any note moves:
+//!
+//! 1. the sender offers: who it is, the amount, the memo and the size of the
+//!    sealed payload;
+//! 2. the receiver accepts or rejects, or lets the offer lapse;
+//! 3. only after an accept does the sender send the sealed `NoteDrop`;
+//! 4. the receiver answers with a signed `DeliveryReceipt` for the note's
+//!    commitment, which the sender keeps as proof of the drop, or rejects
+//!    the note if its cached nullifier set says it is already spent.
+//!
+//! The other way round, a device sends a signed `PaymentRequest`, which the
+//! receiver acknowledges with `Accept`. The payer's offer then names the
+//! request it settles.
+//!
+//! Every message is `version | kind | fields`, the version being the
+//! protocol version peers advertise. Field elements are 32 bytes
+//! big-endian, strings a length byte then utf-8, an empty one meaning none.
+
+use std::fmt;
+
+use crate::{
+    codec::{self, Reader},
+    identity::{DeviceIdentity, PROTOCOL_VERSION},
+    note::{Fr, Note, field_to_bytes},
+    payload::{MAX_MEMO_LEN, NoteDrop},
+    receipt::{DeliveryReceipt, ReceiptError},
+    request::{PaymentRequest, RequestError, RequestId},
+    types::PeerId,
+};
+
+const OFFER: u8 = 1;
+const ACCEPT: u8 = 2;
+const REJECT: u8 = 3;
+const PAYLOAD: u8 = 4;
+const RECEIPT: u8 = 5;
+const REQUEST: u8 = 6;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    Truncated,
+    UnsupportedVersion(u8),
+    UnknownMessage(u8),
+    UnknownReason(u8),
+    MemoTooLong(usize),
+    InvalidUtf8,
+    /// A field element is not below the BN254 modulus.
+    InvalidField,
+    InvalidRequest(RequestError),
+    InvalidReceipt(ReceiptError),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Truncated => write!(f, "message is truncated"),
+            ProtocolError::UnsupportedVersion(version) => {
+                write!(f, "unsupported protocol version {version}")
+            }
+            ProtocolError::UnknownMessage(kind) => write!(f, "unknown message kind {kind}"),
+            ProtocolError::UnknownReason(reason) => write!(f, "unknown reject reason {reason}"),
+            ProtocolError::MemoTooLong(len) => {
+                write!(f, "memo of {len} bytes exceeds {MAX_MEMO_LEN}")
+            }
+            ProtocolError::InvalidUtf8 => write!(f, "memo is not utf-8"),
+            ProtocolError::InvalidField => write!(f, "message carries an out-of-range field"),
+            ProtocolError::InvalidRequest(err) => write!(f, "invalid payment request: {err}"),
+            ProtocolError::InvalidReceipt(err) => write!(f, "invalid delivery receipt: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<codec::Truncated> for ProtocolError {
+    fn from(_: codec::Truncated) -> Self {
+        ProtocolError::Truncated
+    }
+}
+
+impl From<codec::InvalidField> for ProtocolError {
+    fn from(_: codec::InvalidField) -> Self {
+        ProtocolError::InvalidField
+    }
+}
+
+/// What a sender proposes to drop, shown to the receiver before it decides.
+/// Nothing in it is proven until the payload arrives, which must then match.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Offer {
+    pub sender: PeerId,
+    pub amount: Fr,
+    pub memo: Option<String>,
+    /// Size of the sealed payload to follow.
+    pub payload_len: u32,
+    /// The payment request the note settles, if it answers one.
+    pub request: Option<RequestId>,
+}
+
+impl Offer {
+    pub fn new(
+        sender: &DeviceIdentity,
+        note: &Note,
+        memo: Option<&str>,
+        payload_len: usize,
+    ) -> Self {
+        Offer {
+            sender: sender.peer_id(),
+            amount: note.value,
+            memo: memo.filter(|memo| !memo.is_empty()).map(str::to_string),
+            payload_len: payload_len as u32,
+            request: None,
+        }
+    }
+
+    /// Whether `drop` is what this offer announced.
+    pub fn describes(&self, drop: &NoteDrop) -> bool {
+        drop.sender_id() == self.sender && drop.note.value == self.amount && drop.memo == self.memo
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    /// The receiver said no.
+    Declined = 1,
+    /// The receiver did not decide in time.
+    Expired = 2,
+    /// The payload offered is larger than any note drop.
+    TooLarge = 3,
+    /// The note dropped was already spent.
+    Spent = 4,
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectReason::Declined => write!(f, "declined"),
+            RejectReason::Expired => write!(f, "not answered in time"),
+            RejectReason::TooLarge => write!(f, "payload too large"),
+            RejectReason::Spent => write!(f, "note already spent"),
+        }
+    }
+}
+
+impl TryFrom<u8> for RejectReason {
+    type Error = ProtocolError;
+
+    fn try_from(reason: u8) -> Result<Self, Self::Error> {
+        match reason {
+            1 => Ok(RejectReason::Declined),
+            2 => Ok(RejectReason::Expired),
+            3 => Ok(RejectReason::TooLarge),
+            4 => Ok(RejectReason::Spent),
+            reason => Err(ProtocolError::UnknownReason(reason)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// `sender (32) | amount (32) | memo_len | memo | payload_len (u32) |
+    /// has_request [| request id (32)]`
+    Offer(Offer),
+    Accept,
+    /// `reason`
+    Reject(RejectReason),
+    /// The sealed `NoteDrop`, to the end of the message.
+    Payload(Vec<u8>),
+    /// The encoded `DeliveryReceipt`, to the end of the message.
+    Receipt(Box<DeliveryReceipt>),
+    /// The encoded `PaymentRequest`, to the end of the message.
+    Request(Box<PaymentRequest>),
+}
+
+impl Message {
+    pub fn encode(&self) -> Result<Vec<u8>, ProtocolError> {
+        let mut out = vec![PROTOCOL_VERSION];
+        match self {
+            Message::Offer(offer) => {
+                let memo = offer.memo.as_deref().unwrap_or_default().as_bytes();
+                if memo.len() > MAX_MEMO_LEN {
+                    return Err(ProtocolError::MemoTooLong(memo.len()));
+                }
+                out.push(OFFER);
+                out.extend_from_slice(offer.sender.as_bytes());
+                out.extend_from_slice(&field_to_bytes(&offer.amount));
+                out.push(memo.len() as u8);
+                out.extend_from_slice(memo);
+                out.extend_from_slice(&offer.payload_len.to_be_bytes());
+                match &offer.request {
+                    Some(id) => {
+                        out.push(1);
+                        out.extend_from_slice(id.as_bytes());
+                    }
+                    None => out.push(0),
+                }
+            }
+            Message::Accept => out.push(ACCEPT),
+            Message::Reject(reason) => out.extend_from_slice(&[REJECT, *reason as u8]),
+            Message::Payload(payload) => {
+                out.push(PAYLOAD);
+                out.extend_from_slice(payload);
+            }
+            Message::Receipt(receipt) => {
+                out.push(RECEIPT);
+                out.extend_from_slice(&receipt.encode());
+            }
+            Message::Request(request) => {
+                out.push(REQUEST);
+                out.extend_from_slice(&request.encode());
+            }
+        }
+        Ok(out)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut reader = Reader::<ProtocolError>::new(bytes);
+        let version = reader.byte()?;
+        if version != PROTOCOL_VERSION {
+            return Err(ProtocolError::UnsupportedVersion(version));
+        }
+        match reader.byte()? {
+            OFFER => {
+                let sender = PeerId::from_bytes(reader.array()?);
+                let amount = reader.field()?;
+                let memo_len = reader.byte()? as usize;
+                if memo_len > MAX_MEMO_LEN {
+                    return Err(ProtocolError::MemoTooLong(memo_len));
+                }
+                let memo = std::str::from_utf8(reader.take(memo_len)?)
+                    .map_err(|_| ProtocolError::InvalidUtf8)?;
+                Ok(Message::Offer(Offer {
+                    sender,
+                    amount,
+                    memo: (!memo.is_empty()).then(|| memo.to_string()),
+                    payload_len: u32::from_be_bytes(reader.array()?),
+                    request: match reader.byte()? {
+                        0 => None,
+                        _ => Some(RequestId::from_bytes(reader.array()?)),
+                    },
+                }))
+            }
+            ACCEPT => Ok(Message::Accept),
+            REJECT => Ok(Message::Reject(reader.byte()?.try_into()?)),
+            PAYLOAD => Ok(Message::Payload(reader.rest().to_vec())),
+            RECEIPT => DeliveryReceipt::decode(reader.rest())
+                .map(|receipt| Message::Receipt(Box::new(receipt)))
+                .map_err(ProtocolError::InvalidReceipt),
+            REQUEST => PaymentRequest::decode(reader.rest())
+                .map(|request| Message::Request(Box::new(request)))
+                .map_err(ProtocolError::InvalidRequest),
+            kind => Err(ProtocolError::UnknownMessage(kind)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::note::FIELD_LEN;
+
+    fn offer(memo: Option<&str>) -> Offer {
+        let alice = DeviceIdentity::from_secret_keys([1; 32], [2; 32], [5; 32]);
+        Offer::new(&alice, &Note::new(1u64, 100u64, 1234u64), memo, 300)
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let merchant = DeviceIdentity::from_secret_keys([3; 32], [4; 32], [6; 32]);
+        let expiry = std::time::SystemTime::UNIX_EPOCH;
+        let request =
+            PaymentRequest::issue(&merchant, Fr::from(7u64), Fr::from(25u64), expiry, None)
+                .unwrap();
+        let answer = Offer {
+            request: Some(request.id()),
+            ..offer(None)
+        };
+        let messages = [
+            Message::Offer(offer(Some("lunch"))),
+            Message::Offer(offer(None)),
+            Message::Offer(answer),
+            Message::Request(Box::new(request)),
+            Message::Accept,
+            Message::Reject(RejectReason::Expired),
+            Message::Reject(RejectReason::Spent),
+            Message::Payload(vec![7; 40]),
+            Message::Receipt(Box::new(DeliveryReceipt::issue(
+                &merchant,
+                offer(None).sender,
+                Fr::from(99u64),
+                expiry,
+            ))),
+        ];
+        for message in messages {
+            let bytes = message.encode().unwrap();
+            assert_eq!(bytes[0], PROTOCOL_VERSION);
+            assert_eq!(Message::decode(&bytes), Ok(message));
+        }
+        assert_eq!(offer(Some("")).memo, None);
+    }
+
+    #[test]
+    fn malformed_messages_are_rejected() {
+        let encoded = Message::Offer(offer(Some("lunch"))).encode().unwrap();
+        for len in [0, 1, 2, 40, encoded.len() - 1] {
+            assert_eq!(
+                Message::decode(&encoded[..len]),
+                Err(ProtocolError::Truncated),
+                "{len} bytes"
+            );
+        }
+        let mut newer = encoded.clone();
+        newer[0] += 1;
+        assert_eq!(
+            Message::decode(&newer),
+            Err(ProtocolError::UnsupportedVersion(PROTOCOL_VERSION + 1))
+        );
+        assert_eq!(
+            Message::decode(&[PROTOCOL_VERSION, 9]),
+            Err(ProtocolError::UnknownMessage(9))
+        );
+        assert_eq!(
+            Message::decode(&[PROTOCOL_VERSION, REJECT, 9]),
+            Err(ProtocolError::UnknownReason(9))
+        );
+        let mut out_of_range = encoded;
+        out_of_range[2 + PeerId::LEN..][..FIELD_LEN].fill(0xff);
+        assert_eq!(
+            Message::decode(&out_of_range),
+            Err(ProtocolError::InvalidField)
+        );
+        let long = offer(Some(&"x".repeat(MAX_MEMO_LEN + 1)));
+        assert_eq!(
+            Message::Offer(long).encode(),
+            Err(ProtocolError::MemoTooLong(MAX_MEMO_LEN + 1))
+        );
+    }
+}
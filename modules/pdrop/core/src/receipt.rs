@@ -0,0 +1,284 @@
+//! Delivery receipts: the recipient of a note drop signing that it received
+//! the note with a given commitment from a given sender at a given time.
+//! The sender keeps the receipt, and anyone holding it can later check it
+//! offline against the recipient's key, to settle whether a note was handed
+//! over.
+//!
+//! Wire layout: `version | recipient ed25519 key | sender id (32) |
+//! commitment | timestamp (u64 unix seconds) | signature`, the signature
+//! covering everything before it.
+
+use std::{fmt, time::SystemTime};
+
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+
+use crate::{
+    codec::{self, Reader, unix_time, whole_seconds},
+    identity::DeviceIdentity,
+    note::{FIELD_LEN, Fr, field_to_bytes},
+    types::PeerId,
+};
+
+pub const RECEIPT_VERSION: u8 = 1;
+
+const SIGNATURE_CONTEXT: &[u8] = b"pdrop-delivery-receipt";
+const KEY_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+const BODY_LEN: usize = 1 + KEY_LEN + PeerId::LEN + FIELD_LEN + 8;
+
+/// Length of an encoded receipt.
+pub const RECEIPT_LEN: usize = BODY_LEN + SIGNATURE_LEN;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReceiptError {
+    Truncated,
+    UnsupportedVersion(u8),
+    /// A field element is not below the BN254 modulus.
+    InvalidField,
+    InvalidKey,
+    /// The timestamp lies beyond what this platform's clock can represent.
+    InvalidTimestamp,
+    BadSignature,
+    /// The receipt is genuine but for another transfer.
+    Mismatch,
+}
+
+impl fmt::Display for ReceiptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReceiptError::Truncated => write!(f, "receipt is truncated"),
+            ReceiptError::UnsupportedVersion(version) => {
+                write!(f, "unsupported receipt version {version}")
+            }
+            ReceiptError::InvalidField => write!(f, "receipt carries an out-of-range field"),
+            ReceiptError::InvalidKey => write!(f, "receipt carries an invalid key"),
+            ReceiptError::InvalidTimestamp => write!(f, "receipt timestamp is out of range"),
+            ReceiptError::BadSignature => write!(f, "receipt signature does not verify"),
+            ReceiptError::Mismatch => write!(f, "receipt is for another transfer"),
+        }
+    }
+}
+
+impl std::error::Error for ReceiptError {}
+
+impl From<codec::Truncated> for ReceiptError {
+    fn from(_: codec::Truncated) -> Self {
+        ReceiptError::Truncated
+    }
+}
+
+impl From<codec::InvalidField> for ReceiptError {
+    fn from(_: codec::InvalidField) -> Self {
+        ReceiptError::InvalidField
+    }
+}
+
+impl From<codec::InvalidTime> for ReceiptError {
+    fn from(_: codec::InvalidTime) -> Self {
+        ReceiptError::InvalidTimestamp
+    }
+}
+
+/// A signed delivery receipt. Only receipts whose signature verifies can be
+/// built or decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeliveryReceipt {
+    recipient: VerifyingKey,
+    sender: PeerId,
+    commitment: Fr,
+    timestamp: SystemTime,
+    signature: Signature,
+}
+
+impl DeliveryReceipt {
+    /// Signs, as `recipient`, the receipt of the note committed to by
+    /// `commitment` from `sender` at `at`, rounded down to the second.
+    pub fn issue(
+        recipient: &DeviceIdentity,
+        sender: PeerId,
+        commitment: Fr,
+        at: SystemTime,
+    ) -> Self {
+        let mut receipt = DeliveryReceipt {
+            recipient: recipient.verifying_key(),
+            sender,
+            commitment,
+            timestamp: whole_seconds(at),
+            signature: Signature::from_bytes(&[0; SIGNATURE_LEN]),
+        };
+        receipt.signature = recipient.sign(&signed_message(&receipt.body()));
+        receipt
+    }
+
+    pub fn recipient(&self) -> &VerifyingKey {
+        &self.recipient
+    }
+
+    pub fn recipient_id(&self) -> PeerId {
+        PeerId::from_public_key(self.recipient.as_bytes())
+    }
+
+    pub fn sender(&self) -> PeerId {
+        self.sender
+    }
+
+    pub fn commitment(&self) -> Fr {
+        self.commitment
+    }
+
+    pub fn timestamp(&self) -> SystemTime {
+        self.timestamp
+    }
+
+    /// Checks that this is `recipient`'s receipt for the note committed to
+    /// by `commitment` from `sender`. The signature was checked when the
+    /// receipt was built or decoded.
+    pub fn verify(
+        &self,
+        recipient: &PeerId,
+        sender: &PeerId,
+        commitment: &Fr,
+    ) -> Result<(), ReceiptError> {
+        if self.recipient_id() != *recipient
+            || self.sender != *sender
+            || self.commitment != *commitment
+        {
+            return Err(ReceiptError::Mismatch);
+        }
+        Ok(())
+    }
+
+    fn body(&self) -> Vec<u8> {
+        let mut body = Vec::with_capacity(BODY_LEN);
+        body.push(RECEIPT_VERSION);
+        body.extend_from_slice(self.recipient.as_bytes());
+        body.extend_from_slice(self.sender.as_bytes());
+        body.extend_from_slice(&field_to_bytes(&self.commitment));
+        body.extend_from_slice(&unix_time(self.timestamp).to_be_bytes());
+        body
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = self.body();
+        out.extend_from_slice(&self.signature.to_bytes());
+        out
+    }
+
+    /// Decodes a receipt, rejecting it unless its signature verifies.
+    /// Bytes past the receipt are ignored.
+    pub fn decode(bytes: &[u8]) -> Result<Self, ReceiptError> {
+        let mut reader = Reader::<ReceiptError>::new(bytes);
+        let body = reader.take(BODY_LEN)?;
+        let signature = Signature::from_bytes(&reader.array()?);
+        let mut reader = Reader::<ReceiptError>::new(body);
+        let version = reader.byte()?;
+        if version != RECEIPT_VERSION {
+            return Err(ReceiptError::UnsupportedVersion(version));
+        }
+        let recipient =
+            VerifyingKey::from_bytes(&reader.array()?).map_err(|_| ReceiptError::InvalidKey)?;
+        let sender = PeerId::from_bytes(reader.array()?);
+        let commitment = reader.field()?;
+        let timestamp = reader.timestamp()?;
+        recipient
+            .verify(&signed_message(body), &signature)
+            .map_err(|_| ReceiptError::BadSignature)?;
+        Ok(DeliveryReceipt {
+            recipient,
+            sender,
+            commitment,
+            timestamp,
+            signature,
+        })
+    }
+}
+
+fn signed_message(body: &[u8]) -> Vec<u8> {
+    [SIGNATURE_CONTEXT, body].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn identity(seed: u8) -> DeviceIdentity {
+        DeviceIdentity::from_secret_keys([seed; 32], [seed + 1; 32], [seed + 2; 32])
+    }
+
+    #[test]
+    fn a_signed_timestamp_beyond_the_clock_is_refused() {
+        let recipient = identity(3);
+        let mut body = receipt().body();
+        body[BODY_LEN - 8..].copy_from_slice(&u64::MAX.to_be_bytes());
+        let signature = recipient.sign(&signed_message(&body));
+        let bytes = [body, signature.to_bytes().to_vec()].concat();
+        assert_eq!(
+            DeliveryReceipt::decode(&bytes),
+            Err(ReceiptError::InvalidTimestamp)
+        );
+    }
+
+    fn receipt() -> DeliveryReceipt {
+        let at = SystemTime::UNIX_EPOCH + Duration::from_millis(1_000_500);
+        DeliveryReceipt::issue(&identity(3), identity(1).peer_id(), Fr::from(99u64), at)
+    }
+
+    #[test]
+    fn receipts_round_trip_and_verify_offline() {
+        let receipt = receipt();
+        let bytes = receipt.encode();
+        assert_eq!(bytes.len(), RECEIPT_LEN);
+        let decoded = DeliveryReceipt::decode(&bytes).unwrap();
+        assert_eq!(decoded, receipt);
+        assert_eq!(
+            decoded.timestamp(),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_000)
+        );
+
+        let (alice, bob) = (identity(1).peer_id(), identity(3).peer_id());
+        let commitment = Fr::from(99u64);
+        assert_eq!(decoded.verify(&bob, &alice, &commitment), Ok(()));
+        assert_eq!(
+            decoded.verify(&alice, &alice, &commitment),
+            Err(ReceiptError::Mismatch)
+        );
+        assert_eq!(
+            decoded.verify(&bob, &bob, &commitment),
+            Err(ReceiptError::Mismatch)
+        );
+        assert_eq!(
+            decoded.verify(&bob, &alice, &Fr::from(98u64)),
+            Err(ReceiptError::Mismatch)
+        );
+    }
+
+    #[test]
+    fn forged_receipts_are_rejected() {
+        let bytes = receipt().encode();
+        for index in [
+            1 + KEY_LEN,
+            1 + KEY_LEN + PeerId::LEN + 5,
+            BODY_LEN - 1,
+            RECEIPT_LEN - 1,
+        ] {
+            let mut tampered = bytes.clone();
+            tampered[index] ^= 1;
+            assert_eq!(
+                DeliveryReceipt::decode(&tampered),
+                Err(ReceiptError::BadSignature),
+                "byte {index}"
+            );
+        }
+        assert_eq!(
+            DeliveryReceipt::decode(&bytes[..RECEIPT_LEN - 1]),
+            Err(ReceiptError::Truncated)
+        );
+        let mut newer = bytes;
+        newer[0] += 1;
+        assert_eq!(
+            DeliveryReceipt::decode(&newer),
+            Err(ReceiptError::UnsupportedVersion(RECEIPT_VERSION + 1))
+        );
+    }
+}
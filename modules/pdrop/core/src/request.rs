@@ -0,0 +1,350 @@
+//! Payment requests: a recipient asking a nearby payer for a note, the
+//! reverse of a drop. A request is signed by the requesting device and names
+//! the owner key the note must be made out to, so a payer who verified it
+//! knows exactly which note settles it and whom to drop it to.
+//!
+//! Wire layout: `version | requester ed25519 key | owner | amount |
+//! expires_at (u64 unix seconds) | nonce (16) | memo_len | memo |
+//! signature`, field elements 32 bytes big-endian, the signature covering
+//! everything before it. The request id is a hash of the same bytes.
+
+use std::{fmt, time::SystemTime};
+
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    codec::{self, Reader, unix_time, whole_seconds},
+    identity::DeviceIdentity,
+    note::{FIELD_LEN, Fr, Note, field_to_bytes},
+    payload::MAX_MEMO_LEN,
+    types::PeerId,
+};
+
+pub const REQUEST_VERSION: u8 = 1;
+pub const NONCE_LEN: usize = 16;
+
+const SIGNATURE_CONTEXT: &[u8] = b"pdrop-payment-request";
+const ID_CONTEXT: &[u8] = b"pdrop-payment-request-id";
+const KEY_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RequestError {
+    Truncated,
+    UnsupportedVersion(u8),
+    MemoTooLong(usize),
+    InvalidUtf8,
+    /// A field element is not below the BN254 modulus.
+    InvalidField,
+    InvalidKey,
+    BadSignature,
+    Expired,
+    /// The expiry lies beyond what this platform's clock can represent.
+    InvalidExpiry,
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::Truncated => write!(f, "payment request is truncated"),
+            RequestError::UnsupportedVersion(version) => {
+                write!(f, "unsupported payment request version {version}")
+            }
+            RequestError::MemoTooLong(len) => {
+                write!(f, "memo of {len} bytes exceeds {MAX_MEMO_LEN}")
+            }
+            RequestError::InvalidUtf8 => write!(f, "memo is not utf-8"),
+            RequestError::InvalidField => {
+                write!(f, "payment request carries an out-of-range field")
+            }
+            RequestError::InvalidKey => write!(f, "payment request carries an invalid key"),
+            RequestError::BadSignature => write!(f, "payment request signature does not verify"),
+            RequestError::Expired => write!(f, "payment request has expired"),
+            RequestError::InvalidExpiry => write!(f, "payment request expiry is out of range"),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+impl From<codec::Truncated> for RequestError {
+    fn from(_: codec::Truncated) -> Self {
+        RequestError::Truncated
+    }
+}
+
+impl From<codec::InvalidField> for RequestError {
+    fn from(_: codec::InvalidField) -> Self {
+        RequestError::InvalidField
+    }
+}
+
+impl From<codec::InvalidTime> for RequestError {
+    fn from(_: codec::InvalidTime) -> Self {
+        RequestError::InvalidExpiry
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RequestId([u8; 32]);
+
+impl RequestId {
+    pub const fn from_bytes(bytes: [u8; 32]) -> Self {
+        RequestId(bytes)
+    }
+
+    pub const fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl fmt::Debug for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RequestId({}…)", hex::encode(&self.0[..4]))
+    }
+}
+
+/// A signed request for a note of `amount` made out to `owner`. Only
+/// requests whose signature verifies can be built or decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PaymentRequest {
+    requester: VerifyingKey,
+    owner: Fr,
+    amount: Fr,
+    expires_at: SystemTime,
+    memo: Option<String>,
+    nonce: [u8; NONCE_LEN],
+    signature: Signature,
+}
+
+impl PaymentRequest {
+    /// Signs a request from `requester`, valid until `expires_at`, which is
+    /// rounded down to the second.
+    pub fn issue(
+        requester: &DeviceIdentity,
+        owner: Fr,
+        amount: Fr,
+        expires_at: SystemTime,
+        memo: Option<&str>,
+    ) -> Result<Self, RequestError> {
+        let memo = memo.filter(|memo| !memo.is_empty());
+        let memo_len = memo.map_or(0, str::len);
+        if memo_len > MAX_MEMO_LEN {
+            return Err(RequestError::MemoTooLong(memo_len));
+        }
+        let mut nonce = [0; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let mut request = PaymentRequest {
+            requester: requester.verifying_key(),
+            owner,
+            amount,
+            expires_at: whole_seconds(expires_at),
+            memo: memo.map(str::to_string),
+            nonce,
+            signature: Signature::from_bytes(&[0; SIGNATURE_LEN]),
+        };
+        request.signature = requester.sign(&signed_message(&request.body()));
+        Ok(request)
+    }
+
+    pub fn id(&self) -> RequestId {
+        let id = Sha256::new()
+            .chain_update(ID_CONTEXT)
+            .chain_update(self.body())
+            .finalize();
+        RequestId(id.into())
+    }
+
+    pub fn requester(&self) -> &VerifyingKey {
+        &self.requester
+    }
+
+    /// The peer to drop the paying note to.
+    pub fn requester_id(&self) -> PeerId {
+        PeerId::from_public_key(self.requester.as_bytes())
+    }
+
+    pub fn owner(&self) -> Fr {
+        self.owner
+    }
+
+    pub fn amount(&self) -> Fr {
+        self.amount
+    }
+
+    pub fn expires_at(&self) -> SystemTime {
+        self.expires_at
+    }
+
+    pub fn memo(&self) -> Option<&str> {
+        self.memo.as_deref()
+    }
+
+    /// Checks that the request is still good at `now`. The signature was
+    /// checked when it was built or decoded.
+    pub fn check(&self, now: SystemTime) -> Result<(), RequestError> {
+        if now >= self.expires_at {
+            return Err(RequestError::Expired);
+        }
+        Ok(())
+    }
+
+    /// The note that settles this request, with the payer's `secret`.
+    pub fn note(&self, secret: Fr) -> Note {
+        Note {
+            owner: self.owner,
+            value: self.amount,
+            secret,
+        }
+    }
+
+    pub fn is_paid_by(&self, note: &Note) -> bool {
+        note.owner == self.owner && note.value == self.amount
+    }
+
+    fn body(&self) -> Vec<u8> {
+        let memo = self.memo.as_deref().unwrap_or_default().as_bytes();
+        let mut body = Vec::with_capacity(1 + KEY_LEN + 2 * FIELD_LEN + 8 + NONCE_LEN + 1);
+        body.push(REQUEST_VERSION);
+        body.extend_from_slice(self.requester.as_bytes());
+        body.extend_from_slice(&field_to_bytes(&self.owner));
+        body.extend_from_slice(&field_to_bytes(&self.amount));
+        body.extend_from_slice(&unix_time(self.expires_at).to_be_bytes());
+        body.extend_from_slice(&self.nonce);
+        body.push(memo.len() as u8);
+        body.extend_from_slice(memo);
+        body
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = self.body();
+        out.extend_from_slice(&self.signature.to_bytes());
+        out
+    }
+
+    /// Decodes a request, rejecting it unless its signature verifies.
+    pub fn decode(bytes: &[u8]) -> Result<Self, RequestError> {
+        let body_len = bytes
+            .len()
+            .checked_sub(SIGNATURE_LEN)
+            .ok_or(RequestError::Truncated)?;
+        let (body, signature) = bytes.split_at(body_len);
+        let mut reader = Reader::<RequestError>::new(body);
+        let version = reader.byte()?;
+        if version != REQUEST_VERSION {
+            return Err(RequestError::UnsupportedVersion(version));
+        }
+        let requester =
+            VerifyingKey::from_bytes(&reader.array()?).map_err(|_| RequestError::InvalidKey)?;
+        let owner = reader.field()?;
+        let amount = reader.field()?;
+        let expires_at = reader.timestamp()?;
+        let nonce = reader.array()?;
+        let memo_len = reader.byte()? as usize;
+        if memo_len > MAX_MEMO_LEN {
+            return Err(RequestError::MemoTooLong(memo_len));
+        }
+        let memo = reader.take(memo_len)?;
+        if !reader.is_empty() {
+            return Err(RequestError::Truncated);
+        }
+        let memo = std::str::from_utf8(memo).map_err(|_| RequestError::InvalidUtf8)?;
+        let signature = Signature::from_bytes(signature.try_into().unwrap());
+        requester
+            .verify(&signed_message(body), &signature)
+            .map_err(|_| RequestError::BadSignature)?;
+        Ok(PaymentRequest {
+            requester,
+            owner,
+            amount,
+            expires_at,
+            memo: (!memo.is_empty()).then(|| memo.to_string()),
+            nonce,
+            signature,
+        })
+    }
+}
+
+fn signed_message(body: &[u8]) -> Vec<u8> {
+    [SIGNATURE_CONTEXT, body].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn request(memo: Option<&str>) -> PaymentRequest {
+        let merchant = DeviceIdentity::from_secret_keys([1; 32], [2; 32], [3; 32]);
+        PaymentRequest::issue(&merchant, Fr::from(7u64), Fr::from(25u64), at(1_000), memo).unwrap()
+    }
+
+    #[test]
+    fn requests_round_trip_and_name_their_requester() {
+        for memo in [Some("coffee"), None] {
+            let request = request(memo);
+            let decoded = PaymentRequest::decode(&request.encode()).unwrap();
+            assert_eq!(decoded, request);
+            assert_eq!(decoded.id(), request.id());
+            assert_eq!(decoded.memo(), memo);
+        }
+        let merchant = DeviceIdentity::from_secret_keys([1; 32], [2; 32], [3; 32]);
+        assert_eq!(request(None).requester_id(), merchant.peer_id());
+        assert_ne!(request(None).id(), request(None).id(), "nonces differ");
+    }
+
+    #[test]
+    fn tampering_and_truncation_are_detected() {
+        let bytes = request(Some("coffee")).encode();
+        for index in [1, 40, 70, 100, bytes.len() - 70, bytes.len() - 1] {
+            let mut tampered = bytes.clone();
+            tampered[index] ^= 1;
+            assert!(PaymentRequest::decode(&tampered).is_err(), "byte {index}");
+        }
+        assert_eq!(
+            PaymentRequest::decode(&bytes[..SIGNATURE_LEN - 1]),
+            Err(RequestError::Truncated)
+        );
+        let mut newer = bytes;
+        newer[0] += 1;
+        assert_eq!(
+            PaymentRequest::decode(&newer),
+            Err(RequestError::UnsupportedVersion(REQUEST_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn an_expiry_beyond_the_clock_is_refused_before_the_signature() {
+        let mut bytes = request(None).encode();
+        let expiry = 1 + KEY_LEN + 2 * FIELD_LEN;
+        bytes[expiry..expiry + 8].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert_eq!(
+            PaymentRequest::decode(&bytes),
+            Err(RequestError::InvalidExpiry)
+        );
+    }
+
+    #[test]
+    fn requests_expire_and_are_paid_by_their_note() {
+        let request = request(None);
+        assert_eq!(request.check(at(999)), Ok(()));
+        assert_eq!(request.check(at(1_000)), Err(RequestError::Expired));
+
+        let note = request.note(Fr::from(1234u64));
+        assert!(request.is_paid_by(&note));
+        assert!(!request.is_paid_by(&Note::new(7u64, 24u64, 1234u64)));
+        assert!(!request.is_paid_by(&Note::new(8u64, 25u64, 1234u64)));
+    }
+}
@@ -0,0 +1,257 @@
+//! Rotating advertisement tokens. Instead of its peer id, a device
+//! advertises a short token keyed with a secret of its own and derived from
+//! the current time epoch, so that a passer-by sees an unrelated value every
+//! epoch while its contacts, who got the secret with its contact card, can
+//! recompute the token and recognise the device. The public key would not
+//! do: it goes out in every beacon and handshake.
+
+use std::{
+    fmt,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use hmac::{Hmac, Mac};
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+use zeroize::Zeroize;
+
+pub const TOKEN_LEN: usize = 8;
+pub const ROTATION_SECRET_LEN: usize = 32;
+
+const TOKEN_CONTEXT: &[u8] = b"pdrop-advertisement-token";
+
+/// Key of a device's advertisement tokens. It is handed out only to
+/// contacts: whoever holds it can follow the device from epoch to epoch.
+#[derive(Clone, PartialEq, Eq)]
+pub struct RotationSecret([u8; ROTATION_SECRET_LEN]);
+
+impl RotationSecret {
+    pub fn generate() -> Self {
+        let mut bytes = [0; ROTATION_SECRET_LEN];
+        OsRng.fill_bytes(&mut bytes);
+        RotationSecret(bytes)
+    }
+
+    pub const fn from_bytes(bytes: [u8; ROTATION_SECRET_LEN]) -> Self {
+        RotationSecret(bytes)
+    }
+
+    pub const fn as_bytes(&self) -> &[u8; ROTATION_SECRET_LEN] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for RotationSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RotationSecret(..)")
+    }
+}
+
+impl Drop for RotationSecret {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct AdvertisementToken([u8; TOKEN_LEN]);
+
+impl AdvertisementToken {
+    /// `hmac-sha256(secret, context | epoch)`, truncated.
+    pub fn derive(secret: &RotationSecret, epoch: u64) -> Self {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(TOKEN_CONTEXT);
+        mac.update(&epoch.to_be_bytes());
+        AdvertisementToken(mac.finalize().into_bytes()[..TOKEN_LEN].try_into().unwrap())
+    }
+
+    pub const fn from_bytes(bytes: [u8; TOKEN_LEN]) -> Self {
+        AdvertisementToken(bytes)
+    }
+
+    pub const fn as_bytes(&self) -> &[u8; TOKEN_LEN] {
+        &self.0
+    }
+}
+
+impl fmt::Display for AdvertisementToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl fmt::Debug for AdvertisementToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AdvertisementToken({self})")
+    }
+}
+
+impl FromStr for AdvertisementToken {
+    type Err = hex::FromHexError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bytes = [0; TOKEN_LEN];
+        hex::decode_to_slice(s, &mut bytes)?;
+        Ok(AdvertisementToken(bytes))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RotationConfig {
+    /// Length of an epoch; the token changes at every epoch boundary.
+    pub interval: Duration,
+    /// Epochs either side of the current one whose tokens still match, for
+    /// clocks that disagree and tokens heard just before a rotation.
+    pub skew_epochs: u64,
+}
+
+impl Default for RotationConfig {
+    fn default() -> Self {
+        RotationConfig {
+            interval: Duration::from_secs(15 * 60),
+            skew_epochs: 1,
+        }
+    }
+}
+
+impl RotationConfig {
+    /// Epoch `at` falls in, counted from the Unix epoch. Times before it all
+    /// fall in epoch 0.
+    pub fn epoch(&self, at: SystemTime) -> u64 {
+        let since = at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        (since.as_millis() / self.interval_millis()) as u64
+    }
+
+    /// When the epoch `at` falls in ends.
+    pub fn next_rotation(&self, at: SystemTime) -> SystemTime {
+        let end = (self.epoch(at) as u128 + 1) * self.interval_millis();
+        SystemTime::UNIX_EPOCH + Duration::from_millis(end as u64)
+    }
+
+    fn interval_millis(&self) -> u128 {
+        self.interval.as_millis().max(1)
+    }
+
+    /// Token the owner of `secret` advertises at `at`.
+    pub fn token(&self, secret: &RotationSecret, at: SystemTime) -> AdvertisementToken {
+        AdvertisementToken::derive(secret, self.epoch(at))
+    }
+
+    /// Whether `token`, heard at `at`, was advertised by the owner of
+    /// `secret` within the skew tolerance.
+    pub fn matches(
+        &self,
+        secret: &RotationSecret,
+        token: &AdvertisementToken,
+        at: SystemTime,
+    ) -> bool {
+        let epoch = self.epoch(at);
+        (epoch.saturating_sub(self.skew_epochs)..=epoch.saturating_add(self.skew_epochs))
+            .any(|epoch| AdvertisementToken::derive(secret, epoch) == *token)
+    }
+
+    /// The first of `secrets` whose owner advertised `token` at `at`.
+    pub fn resolve<'a>(
+        &self,
+        token: &AdvertisementToken,
+        at: SystemTime,
+        secrets: impl IntoIterator<Item = &'a RotationSecret>,
+    ) -> Option<&'a RotationSecret> {
+        secrets
+            .into_iter()
+            .find(|secret| self.matches(secret, token, at))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::DeviceIdentity;
+
+    fn secret(seed: u8) -> RotationSecret {
+        RotationSecret::from_bytes([seed; ROTATION_SECRET_LEN])
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn config(interval: u64, skew_epochs: u64) -> RotationConfig {
+        RotationConfig {
+            interval: Duration::from_secs(interval),
+            skew_epochs,
+        }
+    }
+
+    #[test]
+    fn tokens_rotate_at_epoch_boundaries() {
+        let config = config(60, 0);
+        let alice = secret(1);
+        assert_eq!(config.token(&alice, at(600)), config.token(&alice, at(659)));
+        assert_ne!(config.token(&alice, at(659)), config.token(&alice, at(660)));
+        assert_ne!(
+            config.token(&alice, at(600)),
+            config.token(&secret(2), at(600))
+        );
+        assert_eq!(config.next_rotation(at(600)), at(660));
+        assert_eq!(config.next_rotation(at(659)), at(660));
+
+        let slower = RotationConfig {
+            interval: Duration::from_secs(120),
+            ..config
+        };
+        assert_eq!(slower.token(&alice, at(600)), slower.token(&alice, at(660)));
+    }
+
+    #[test]
+    fn tokens_match_within_the_skew_tolerance() {
+        let alice = secret(1);
+        let token = config(60, 0).token(&alice, at(600));
+
+        let strict = config(60, 0);
+        assert!(strict.matches(&alice, &token, at(630)));
+        assert!(!strict.matches(&alice, &token, at(660)));
+        assert!(!strict.matches(&secret(2), &token, at(630)));
+
+        let lenient = config(60, 2);
+        assert!(lenient.matches(&alice, &token, at(600 - 120)));
+        assert!(lenient.matches(&alice, &token, at(600 + 179)));
+        assert!(!lenient.matches(&alice, &token, at(600 - 121)));
+        assert!(!lenient.matches(&alice, &token, at(600 + 180)));
+    }
+
+    #[test]
+    fn tokens_resolve_to_the_secret_that_derived_them() {
+        let config = config(60, 1);
+        let secrets = [secret(1), secret(2), secret(3)];
+        let token = config.token(&secrets[1], at(1_000));
+        assert_eq!(
+            config.resolve(&token, at(1_030), &secrets),
+            Some(&secrets[1])
+        );
+        assert_eq!(config.resolve(&token, at(1_300), &secrets), None);
+        assert_eq!(token.to_string().parse(), Ok(token));
+    }
+
+    #[test]
+    fn tokens_do_not_follow_from_the_keys() {
+        let mut identity = DeviceIdentity::from_secret_keys([1; 32], [2; 32], [3; 32]);
+        let public = RotationSecret::from_bytes(identity.verifying_key().to_bytes());
+        let config = config(60, 0);
+        let token = config.token(identity.rotation_secret(), at(600));
+        assert!(!config.matches(&public, &token, at(600)));
+
+        // a regenerated secret leaves those who held the old one behind
+        let old = identity.rotation_secret().clone();
+        identity.regenerate_rotation_secret();
+        let token = config.token(identity.rotation_secret(), at(600));
+        assert!(!config.matches(&old, &token, at(600)));
+        assert_eq!(
+            identity.peer_id(),
+            DeviceIdentity::from_secret_keys([1; 32], [2; 32], [3; 32]).peer_id()
+        );
+    }
+}
@@ -0,0 +1,597 @@
+//! Authenticated, encrypted sessions between two devices.
+//!
+//! The handshake is Noise XX over the devices' x25519 agreement keys. Each
+//! side's handshake payload is its ed25519 key and a signature over its
+//! x25519 static key, so the session is bound to both `DeviceIdentity`s and
+//! the remote `PeerId` is known once it completes.
+//!
+//! `Handshake` and `Session` never touch a transport: they turn messages
+//! into bytes and back, and `handshake` drives the exchange over any
+//! `Sink`/`Stream` of byte messages. After the handshake every frame is
+//! `nonce | ciphertext`, nonce a big-endian u64 counter. Explicit nonces let
+//! frames arrive out of order or not at all; a sliding window rejects
+//! replays. Keys move on to the next epoch every `rekey_interval` frames,
+//! following Noise's `REKEY`.
+
+use std::{error::Error, fmt};
+
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, aead::Aead};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use zeroize::Zeroizing;
+
+use crate::{identity::DeviceIdentity, types::PeerId};
+
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_SHA256";
+const IDENTITY_CONTEXT: &[u8] = b"pdrop-session-identity";
+const IDENTITY_PAYLOAD_LEN: usize = 32 + 64;
+const NONCE_LEN: usize = 8;
+const TAG_LEN: usize = 16;
+const MAX_NOISE_MESSAGE_LEN: usize = 65_535;
+
+/// Largest plaintext a single frame can carry.
+pub const MAX_PLAINTEXT_LEN: usize = MAX_NOISE_MESSAGE_LEN - NONCE_LEN - TAG_LEN;
+
+/// Frames this far behind the newest one received are rejected outright.
+pub const REPLAY_WINDOW: u64 = 64;
+
+/// How far ahead of the current epoch a frame may be and still be accepted.
+const MAX_EPOCH_SKIP: u64 = 2;
+
+#[derive(Debug)]
+pub enum SessionError {
+    NoiseError(snow::Error),
+    TransportError(Box<dyn Error + Send + Sync>),
+    /// The transport ended before the handshake completed.
+    TransportClosed,
+    /// The peer's identity payload is malformed or its signature is wrong.
+    InvalidIdentity,
+    MalformedFrame,
+    /// The frame was seen before, or is too old to tell.
+    Replayed(u64),
+    /// The frame does not decrypt under the session keys.
+    DecryptionFailed,
+    NonceExhausted,
+    MessageTooLong,
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::NoiseError(err) => write!(f, "noise handshake failed: {err}"),
+            SessionError::TransportError(err) => write!(f, "session transport failed: {err}"),
+            SessionError::TransportClosed => write!(f, "transport closed during handshake"),
+            SessionError::InvalidIdentity => write!(f, "peer presented an invalid identity"),
+            SessionError::MalformedFrame => write!(f, "malformed session frame"),
+            SessionError::Replayed(nonce) => write!(f, "replayed or stale frame {nonce}"),
+            SessionError::DecryptionFailed => write!(f, "session frame does not decrypt"),
+            SessionError::NonceExhausted => write!(f, "session nonces exhausted"),
+            SessionError::MessageTooLong => write!(f, "message exceeds the frame size"),
+        }
+    }
+}
+
+impl Error for SessionError {}
+
+impl From<snow::Error> for SessionError {
+    fn from(err: snow::Error) -> Self {
+        SessionError::NoiseError(err)
+    }
+}
+
+/// Parameters both ends of a session must agree on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionConfig {
+    /// Frames sent under one key before moving on to the next.
+    pub rekey_interval: u64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            rekey_interval: 1 << 16,
+        }
+    }
+}
+
+/// The remote end of a session, as proven by its handshake payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RemoteIdentity {
+    pub id: PeerId,
+    pub signing_key: VerifyingKey,
+    pub agreement_key: [u8; 32],
+}
+
+fn identity_payload(identity: &DeviceIdentity) -> Vec<u8> {
+    let statement = [IDENTITY_CONTEXT, identity.agreement_public_key().as_bytes()].concat();
+    [
+        identity.verifying_key().as_bytes().as_slice(),
+        &identity.sign(&statement).to_bytes(),
+    ]
+    .concat()
+}
+
+fn verify_identity_payload(
+    payload: &[u8],
+    remote_static: &[u8],
+) -> Result<RemoteIdentity, SessionError> {
+    if payload.len() != IDENTITY_PAYLOAD_LEN {
+        return Err(SessionError::InvalidIdentity);
+    }
+    let (key, signature) = payload.split_at(32);
+    let signing_key = VerifyingKey::from_bytes(key.try_into().unwrap())
+        .map_err(|_| SessionError::InvalidIdentity)?;
+    let signature = Signature::from_bytes(signature.try_into().unwrap());
+    let agreement_key: [u8; 32] = remote_static
+        .try_into()
+        .map_err(|_| SessionError::InvalidIdentity)?;
+    signing_key
+        .verify(&[IDENTITY_CONTEXT, &agreement_key].concat(), &signature)
+        .map_err(|_| SessionError::InvalidIdentity)?;
+    Ok(RemoteIdentity {
+        id: PeerId::from_public_key(signing_key.as_bytes()),
+        signing_key,
+        agreement_key,
+    })
+}
+
+/// One side of a Noise XX handshake in progress.
+pub struct Handshake {
+    noise: snow::HandshakeState,
+    payload: Vec<u8>,
+    remote: Option<RemoteIdentity>,
+    config: SessionConfig,
+}
+
+impl Handshake {
+    pub fn initiator(
+        identity: &DeviceIdentity,
+        config: SessionConfig,
+    ) -> Result<Self, SessionError> {
+        Self::new(identity, config, true)
+    }
+
+    pub fn responder(
+        identity: &DeviceIdentity,
+        config: SessionConfig,
+    ) -> Result<Self, SessionError> {
+        Self::new(identity, config, false)
+    }
+
+    fn new(
+        identity: &DeviceIdentity,
+        config: SessionConfig,
+        initiator: bool,
+    ) -> Result<Self, SessionError> {
+        let secret = Zeroizing::new(identity.agreement_key().to_bytes());
+        let builder = snow::Builder::new(NOISE_PARAMS.parse()?).local_private_key(&secret[..]);
+        let noise = if initiator {
+            builder.build_initiator()?
+        } else {
+            builder.build_responder()?
+        };
+        Ok(Handshake {
+            noise,
+            payload: identity_payload(identity),
+            remote: None,
+            config,
+        })
+    }
+
+    pub fn is_my_turn(&self) -> bool {
+        self.noise.is_my_turn()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.noise.is_handshake_finished()
+    }
+
+    /// Produces the next handshake message. Our identity goes into the
+    /// first message that is encrypted, never into the initiator's opening
+    /// one.
+    pub fn write_message(&mut self) -> Result<Vec<u8>, SessionError> {
+        let opening = self.noise.is_initiator() && self.remote.is_none();
+        let payload: &[u8] = if opening { &[] } else { &self.payload };
+        let mut message = vec![0; MAX_NOISE_MESSAGE_LEN];
+        let len = self.noise.write_message(payload, &mut message)?;
+        message.truncate(len);
+        Ok(message)
+    }
+
+    pub fn read_message(&mut self, message: &[u8]) -> Result<(), SessionError> {
+        let mut payload = vec![0; MAX_NOISE_MESSAGE_LEN];
+        let len = self.noise.read_message(message, &mut payload)?;
+        if let Some(remote_static) = self.noise.get_remote_static()
+            && self.remote.is_none()
+        {
+            self.remote = Some(verify_identity_payload(&payload[..len], remote_static)?);
+        }
+        Ok(())
+    }
+
+    /// The established session, once both sides have sent their messages.
+    pub fn into_session(mut self) -> Result<Session, SessionError> {
+        let remote = self.remote.ok_or(SessionError::InvalidIdentity)?;
+        let handshake_hash = self.noise.get_handshake_hash().to_vec();
+        let (initiator_key, responder_key) = self.noise.dangerously_get_raw_split();
+        let (send, receive) = if self.noise.is_initiator() {
+            (initiator_key, responder_key)
+        } else {
+            (responder_key, initiator_key)
+        };
+        Ok(Session {
+            remote,
+            handshake_hash,
+            rekey_interval: self.config.rekey_interval.max(1),
+            sender: Sender {
+                key: Zeroizing::new(send),
+                epoch: 0,
+                next_nonce: 0,
+            },
+            receiver: Receiver {
+                key: Zeroizing::new(receive),
+                epoch: 0,
+                previous: None,
+                window: ReplayWindow::default(),
+            },
+        })
+    }
+}
+
+/// Runs `handshake` to completion over `transport`, one handshake message
+/// per transport message.
+pub async fn handshake<T, E>(
+    mut handshake: Handshake,
+    transport: &mut T,
+) -> Result<Session, SessionError>
+where
+    T: Sink<Vec<u8>, Error = E> + Stream<Item = Vec<u8>> + Unpin,
+    E: Error + Send + Sync + 'static,
+{
+    while !handshake.is_finished() {
+        if handshake.is_my_turn() {
+            let message = handshake.write_message()?;
+            transport
+                .send(message)
+                .await
+                .map_err(|err| SessionError::TransportError(Box::new(err)))?;
+        } else {
+            let message = transport
+                .next()
+                .await
+                .ok_or(SessionError::TransportClosed)?;
+            handshake.read_message(&message)?;
+        }
+    }
+    handshake.into_session()
+}
+
+type Key = Zeroizing<[u8; 32]>;
+
+fn aead_nonce(nonce: u64) -> [u8; 12] {
+    let mut out = [0; 12];
+    out[4..].copy_from_slice(&nonce.to_le_bytes());
+    out
+}
+
+/// Noise `REKEY`: the first 32 bytes of encrypting zeros at the maximum
+/// nonce.
+fn rekey(key: &[u8; 32]) -> Key {
+    let cipher = ChaCha20Poly1305::new(key.into());
+    let next = cipher
+        .encrypt(&aead_nonce(u64::MAX).into(), &[0u8; 32][..])
+        .expect("encrypting a fixed block cannot fail");
+    Zeroizing::new(next[..32].try_into().unwrap())
+}
+
+struct Sender {
+    key: Key,
+    epoch: u64,
+    next_nonce: u64,
+}
+
+struct Receiver {
+    key: Key,
+    epoch: u64,
+    /// Key of the epoch before, for frames overtaken by the rekey.
+    previous: Option<Key>,
+    window: ReplayWindow,
+}
+
+/// Anti-replay window over the last `REPLAY_WINDOW` nonces.
+#[derive(Default)]
+struct ReplayWindow {
+    highest: Option<u64>,
+    /// Bit `i` is set when `highest - i` has been received.
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn is_fresh(&self, nonce: u64) -> bool {
+        let Some(highest) = self.highest else {
+            return true;
+        };
+        if nonce > highest {
+            return true;
+        }
+        let age = highest - nonce;
+        age < REPLAY_WINDOW && self.seen & (1 << age) == 0
+    }
+
+    fn mark(&mut self, nonce: u64) {
+        match self.highest {
+            Some(highest) if nonce <= highest => self.seen |= 1 << (highest - nonce),
+            Some(highest) => {
+                let shift = nonce - highest;
+                self.seen = if shift < REPLAY_WINDOW {
+                    (self.seen << shift) | 1
+                } else {
+                    1
+                };
+                self.highest = Some(nonce);
+            }
+            None => {
+                self.seen = 1;
+                self.highest = Some(nonce);
+            }
+        }
+    }
+}
+
+/// An established session. Frames from `encrypt` are meant for the peer's
+/// `decrypt`, which accepts each at most once.
+pub struct Session {
+    remote: RemoteIdentity,
+    handshake_hash: Vec<u8>,
+    rekey_interval: u64,
+    sender: Sender,
+    receiver: Receiver,
+}
+
+impl Session {
+    pub fn remote(&self) -> &RemoteIdentity {
+        &self.remote
+    }
+
+    pub fn remote_id(&self) -> PeerId {
+        self.remote.id
+    }
+
+    /// Unique per session and equal on both ends; lets higher layers bind
+    /// signatures to this session.
+    pub fn handshake_hash(&self) -> &[u8] {
+        &self.handshake_hash
+    }
+
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, SessionError> {
+        if plaintext.len() > MAX_PLAINTEXT_LEN {
+            return Err(SessionError::MessageTooLong);
+        }
+        let sender = &mut self.sender;
+        let nonce = sender.next_nonce;
+        if nonce == u64::MAX {
+            return Err(SessionError::NonceExhausted);
+        }
+        while sender.epoch < nonce / self.rekey_interval {
+            sender.key = rekey(&sender.key);
+            sender.epoch += 1;
+        }
+        let ciphertext = ChaCha20Poly1305::new((&*sender.key).into())
+            .encrypt(&aead_nonce(nonce).into(), plaintext)
+            .map_err(|_| SessionError::MessageTooLong)?;
+        sender.next_nonce += 1;
+        Ok([&nonce.to_be_bytes()[..], &ciphertext].concat())
+    }
+
+    pub fn decrypt(&mut self, frame: &[u8]) -> Result<Vec<u8>, SessionError> {
+        if frame.len() < NONCE_LEN + TAG_LEN {
+            return Err(SessionError::MalformedFrame);
+        }
+        let (nonce, ciphertext) = frame.split_at(NONCE_LEN);
+        let nonce = u64::from_be_bytes(nonce.try_into().unwrap());
+        let receiver = &mut self.receiver;
+        if !receiver.window.is_fresh(nonce) {
+            return Err(SessionError::Replayed(nonce));
+        }
+
+        // keys move forward only once a frame of the new epoch authenticates
+        let epoch = nonce / self.rekey_interval;
+        let key = match epoch {
+            e if e == receiver.epoch => receiver.key.clone(),
+            e if e + 1 == receiver.epoch => receiver
+                .previous
+                .clone()
+                .ok_or(SessionError::Replayed(nonce))?,
+            e if e > receiver.epoch && e - receiver.epoch <= MAX_EPOCH_SKIP => {
+                (receiver.epoch..e).fold(receiver.key.clone(), |key, _| rekey(&key))
+            }
+            _ => return Err(SessionError::Replayed(nonce)),
+        };
+        let plaintext = ChaCha20Poly1305::new((&*key).into())
+            .decrypt(&aead_nonce(nonce).into(), ciphertext)
+            .map_err(|_| SessionError::DecryptionFailed)?;
+
+        if epoch > receiver.epoch {
+            let superseded = std::mem::replace(&mut receiver.key, key);
+            receiver.previous = (epoch == receiver.epoch + 1).then_some(superseded);
+            receiver.epoch = epoch;
+        }
+        receiver.window.mark(nonce);
+        Ok(plaintext)
+    }
+}
+
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Session")
+            .field("remote", &self.remote.id)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use futures::{
+        channel::mpsc::{self, SendError, UnboundedReceiver, UnboundedSender},
+        executor::block_on,
+        future,
+    };
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    /// One end of an in-memory message pipe.
+    pub(crate) struct Duplex {
+        tx: UnboundedSender<Vec<u8>>,
+        rx: UnboundedReceiver<Vec<u8>>,
+    }
+
+    impl Duplex {
+        pub(crate) fn pair() -> (Duplex, Duplex) {
+            let (a_tx, b_rx) = mpsc::unbounded();
+            let (b_tx, a_rx) = mpsc::unbounded();
+            (Duplex { tx: a_tx, rx: a_rx }, Duplex { tx: b_tx, rx: b_rx })
+        }
+    }
+
+    impl Sink<Vec<u8>> for Duplex {
+        type Error = SendError;
+
+        fn poll_ready(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), SendError>> {
+            Pin::new(&mut self.tx).poll_ready(cx)
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), SendError> {
+            Pin::new(&mut self.tx).start_send(item)
+        }
+
+        fn poll_flush(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), SendError>> {
+            Pin::new(&mut self.tx).poll_flush(cx)
+        }
+
+        fn poll_close(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), SendError>> {
+            Pin::new(&mut self.tx).poll_close(cx)
+        }
+    }
+
+    impl Stream for Duplex {
+        type Item = Vec<u8>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Vec<u8>>> {
+            self.rx.poll_next_unpin(cx)
+        }
+    }
+
+    pub(crate) fn connect(
+        alice: &DeviceIdentity,
+        bob: &DeviceIdentity,
+        config: SessionConfig,
+    ) -> (Session, Session) {
+        let (mut a, mut b) = Duplex::pair();
+        let initiator = Handshake::initiator(alice, config).unwrap();
+        let responder = Handshake::responder(bob, config).unwrap();
+        let (alice, bob) = block_on(future::join(
+            handshake(initiator, &mut a),
+            handshake(responder, &mut b),
+        ));
+        (alice.unwrap(), bob.unwrap())
+    }
+
+    #[test]
+    fn handshake_authenticates_both_identities() {
+        let (alice, bob) = (DeviceIdentity::generate(), DeviceIdentity::generate());
+        let (mut a, mut b) = connect(&alice, &bob, SessionConfig::default());
+
+        assert_eq!(a.remote_id(), bob.peer_id());
+        assert_eq!(b.remote_id(), alice.peer_id());
+        assert_eq!(a.handshake_hash(), b.handshake_hash());
+
+        let frame = a.encrypt(b"hello bob").unwrap();
+        assert_eq!(b.decrypt(&frame).unwrap(), b"hello bob");
+        let frame = b.encrypt(b"hello alice").unwrap();
+        assert_eq!(a.decrypt(&frame).unwrap(), b"hello alice");
+    }
+
+    #[test]
+    fn identity_signed_for_another_agreement_key_is_rejected() {
+        let alice = DeviceIdentity::generate();
+        let bob = DeviceIdentity::generate();
+        let mut initiator = Handshake::initiator(&alice, SessionConfig::default()).unwrap();
+        let mut responder = Handshake::responder(&bob, SessionConfig::default()).unwrap();
+        // bob claims his ed25519 identity for a noise key that is not his
+        responder.payload = identity_payload(&DeviceIdentity::generate());
+
+        responder
+            .read_message(&initiator.write_message().unwrap())
+            .unwrap();
+        let second = responder.write_message().unwrap();
+        assert!(matches!(
+            initiator.read_message(&second),
+            Err(SessionError::InvalidIdentity)
+        ));
+    }
+
+    #[test]
+    fn replayed_and_tampered_frames_are_rejected() {
+        let (alice, bob) = (DeviceIdentity::generate(), DeviceIdentity::generate());
+        let (mut a, mut b) = connect(&alice, &bob, SessionConfig::default());
+
+        let first = a.encrypt(b"one").unwrap();
+        let second = a.encrypt(b"two").unwrap();
+        assert_eq!(b.decrypt(&second).unwrap(), b"two");
+        // out of order is fine, twice is not
+        assert_eq!(b.decrypt(&first).unwrap(), b"one");
+        assert!(matches!(b.decrypt(&first), Err(SessionError::Replayed(0))));
+
+        let mut tampered = a.encrypt(b"three").unwrap();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            b.decrypt(&tampered),
+            Err(SessionError::DecryptionFailed)
+        ));
+    }
+
+    #[test]
+    fn frames_older_than_the_window_are_rejected() {
+        let (alice, bob) = (DeviceIdentity::generate(), DeviceIdentity::generate());
+        let (mut a, mut b) = connect(&alice, &bob, SessionConfig::default());
+
+        let old = a.encrypt(b"old").unwrap();
+        for _ in 0..REPLAY_WINDOW {
+            let frame = a.encrypt(b"newer").unwrap();
+            b.decrypt(&frame).unwrap();
+        }
+        assert!(matches!(b.decrypt(&old), Err(SessionError::Replayed(0))));
+    }
+
+    #[test]
+    fn keys_roll_over_every_interval() {
+        let config = SessionConfig { rekey_interval: 4 };
+        let (alice, bob) = (DeviceIdentity::generate(), DeviceIdentity::generate());
+        let (mut a, mut b) = connect(&alice, &bob, config);
+
+        let frames: Vec<_> = (0..12u8).map(|i| a.encrypt(&[i]).unwrap()).collect();
+        // frame 3 is overtaken by the first frame of the next epoch
+        for i in [0, 1, 2, 4, 3, 5, 6, 7, 8, 9, 10, 11] {
+            assert_eq!(b.decrypt(&frames[i]).unwrap(), [i as u8]);
+        }
+        assert_eq!(b.receiver.epoch, 2);
+
+        // a forged frame from a later epoch does not move the keys
+        let mut forged = frames[11].clone();
+        forged[..NONCE_LEN].copy_from_slice(&13u64.to_be_bytes());
+        assert!(b.decrypt(&forged).is_err());
+        assert_eq!(b.receiver.epoch, 2);
+    }
+}
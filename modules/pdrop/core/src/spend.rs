@@ -0,0 +1,468 @@
+//! Guarding offline note drops against double spends. A sender still knows
+//! the secret of a note it dropped and could spend it on chain before the
+//! recipient does; nothing offline can prevent that, but both sides can
+//! narrow it down:
+//!
+//! - the recipient checks the note's nullifier against a `NullifierSet`
+//!   cached from the chain or a relayer, refusing notes already spent;
+//! - the sender marks every note it handed off as locked in its
+//!   `SpendLocks`, so its own wallet does not spend or drop it again;
+//! - the recipient keeps the notes it received pending until it spends them
+//!   itself, and a later sync reports any whose nullifier turned up first
+//!   as a `Conflict`.
+//!
+//! Nullifier set layout: `magic | version | synced_at (u64 unix seconds, 0
+//! for never) | count (u32) | nullifiers`. Spend lock layout: `magic |
+//! version | count (u32) | locks | count (u32) | pending`, each lock
+//! `commitment | peer (32) | at (u64)`, each pending note `nullifier |
+//! commitment | peer (32) | at (u64)`. Integers are big-endian.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, btree_map::Entry},
+    fmt, fs, io,
+    path::Path,
+    time::SystemTime,
+};
+
+use crate::{
+    codec::{self, Reader, unix_time, whole_seconds},
+    note::{FIELD_LEN, Fr, Note, field_to_bytes},
+    persist::atomic_write,
+    types::PeerId,
+};
+
+pub const SPEND_VERSION: u8 = 1;
+
+const NULLIFIERS_MAGIC: &[u8; 6] = b"PDROPN";
+const LOCKS_MAGIC: &[u8; 6] = b"PDROPL";
+
+#[derive(Debug)]
+pub enum SpendError {
+    IoError(io::Error),
+    NotASpendStore,
+    UnsupportedVersion(u8),
+    Truncated,
+    /// A field element is not below the BN254 modulus.
+    InvalidField,
+    /// A timestamp lies beyond what this platform's clock can represent.
+    InvalidTimestamp,
+}
+
+impl fmt::Display for SpendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpendError::IoError(err) => write!(f, "spend store i/o failed: {err}"),
+            SpendError::NotASpendStore => write!(f, "not a pdrop spend store"),
+            SpendError::UnsupportedVersion(version) => {
+                write!(f, "unsupported spend store version {version}")
+            }
+            SpendError::Truncated => write!(f, "spend store is truncated"),
+            SpendError::InvalidField => write!(f, "spend store carries an out-of-range field"),
+            SpendError::InvalidTimestamp => write!(f, "spend store timestamp is out of range"),
+        }
+    }
+}
+
+impl std::error::Error for SpendError {}
+
+impl From<codec::Truncated> for SpendError {
+    fn from(_: codec::Truncated) -> Self {
+        SpendError::Truncated
+    }
+}
+
+impl From<codec::InvalidField> for SpendError {
+    fn from(_: codec::InvalidField) -> Self {
+        SpendError::InvalidField
+    }
+}
+
+impl From<codec::InvalidTime> for SpendError {
+    fn from(_: codec::InvalidTime) -> Self {
+        SpendError::InvalidTimestamp
+    }
+}
+
+impl From<io::Error> for SpendError {
+    fn from(err: io::Error) -> Self {
+        SpendError::IoError(err)
+    }
+}
+
+/// Nullifiers known to be spent, as of the last sync.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NullifierSet {
+    nullifiers: BTreeSet<Fr>,
+    synced_at: Option<SystemTime>,
+}
+
+impl NullifierSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the nullifiers seen on chain or at a relayer, noting the sync
+    /// time to the second.
+    pub fn sync(&mut self, nullifiers: impl IntoIterator<Item = Fr>, at: SystemTime) {
+        self.nullifiers.extend(nullifiers);
+        self.synced_at = Some(whole_seconds(at));
+    }
+
+    pub fn contains(&self, nullifier: &Fr) -> bool {
+        self.nullifiers.contains(nullifier)
+    }
+
+    /// Whether `note` was already spent, as far as the last sync knows.
+    pub fn is_spent(&self, note: &Note) -> bool {
+        self.contains(&note.nullifier())
+    }
+
+    /// When the set was last synced, if ever. A stale set lets through
+    /// notes spent since.
+    pub fn synced_at(&self) -> Option<SystemTime> {
+        self.synced_at
+    }
+
+    pub fn len(&self) -> usize {
+        self.nullifiers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nullifiers.is_empty()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(15 + self.nullifiers.len() * FIELD_LEN);
+        out.extend_from_slice(NULLIFIERS_MAGIC);
+        out.push(SPEND_VERSION);
+        out.extend_from_slice(&self.synced_at.map_or(0, unix_time).to_be_bytes());
+        out.extend_from_slice(&(self.nullifiers.len() as u32).to_be_bytes());
+        for nullifier in &self.nullifiers {
+            out.extend_from_slice(&field_to_bytes(nullifier));
+        }
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, SpendError> {
+        let mut reader = open(bytes, NULLIFIERS_MAGIC)?;
+        let synced_at = reader.timestamp()?;
+        let mut set = NullifierSet {
+            nullifiers: BTreeSet::new(),
+            synced_at: (synced_at != SystemTime::UNIX_EPOCH).then_some(synced_at),
+        };
+        for _ in 0..u32::from_be_bytes(reader.array()?) {
+            set.nullifiers.insert(reader.field()?);
+        }
+        Ok(set)
+    }
+
+    /// Writes the set to `path` through a temporary file renamed over it.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), SpendError> {
+        Ok(atomic_write(path.as_ref(), &self.encode())?)
+    }
+
+    /// Loads the set at `path`, or an empty, never synced one.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, SpendError> {
+        match fs::read(path) {
+            Ok(bytes) => Self::decode(&bytes),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// A note this device handed off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lock {
+    /// The peer it was dropped to.
+    pub peer: PeerId,
+    pub at: SystemTime,
+}
+
+/// A note handed to this device that it has not spent yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pending {
+    pub commitment: Fr,
+    /// The peer that dropped it, who can still spend it.
+    pub peer: PeerId,
+    pub at: SystemTime,
+}
+
+/// A received note whose nullifier showed up on chain before this device
+/// spent it: most likely its sender spent it again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Conflict {
+    pub nullifier: Fr,
+    pub note: Pending,
+}
+
+/// Notes locked after being handed off, and received notes pending until
+/// spent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SpendLocks {
+    /// By commitment.
+    locked: BTreeMap<Fr, Lock>,
+    /// By nullifier.
+    pending: BTreeMap<Fr, Pending>,
+}
+
+impl SpendLocks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Locks `note` as handed off to `peer`. Returns `false`, changing
+    /// nothing, if it was already locked.
+    pub fn lock(&mut self, note: &Note, peer: PeerId, at: SystemTime) -> bool {
+        let lock = Lock {
+            peer,
+            at: whole_seconds(at),
+        };
+        match self.locked.entry(note.commit()) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(lock);
+                true
+            }
+        }
+    }
+
+    /// Whether the note committed to by `commitment` was handed off, and so
+    /// must not be spent or dropped again.
+    pub fn is_locked(&self, commitment: &Fr) -> bool {
+        self.locked.contains_key(commitment)
+    }
+
+    pub fn lock_of(&self, commitment: &Fr) -> Option<&Lock> {
+        self.locked.get(commitment)
+    }
+
+    /// Releases the note committed to by `commitment`, for when the user
+    /// knows the drop never reached the peer.
+    pub fn unlock(&mut self, commitment: &Fr) -> Option<Lock> {
+        self.locked.remove(commitment)
+    }
+
+    /// Keeps `note`, received from `peer`, pending until it is spent.
+    pub fn receive(&mut self, note: &Note, peer: PeerId, at: SystemTime) {
+        let pending = Pending {
+            commitment: note.commit(),
+            peer,
+            at: whole_seconds(at),
+        };
+        self.pending.insert(note.nullifier(), pending);
+    }
+
+    /// Received notes not spent yet, as far as this device knows.
+    pub fn pending(&self) -> impl Iterator<Item = &Pending> {
+        self.pending.values()
+    }
+
+    /// Marks the received note committed to by `commitment` as spent by
+    /// this device, so a later sync does not report it.
+    pub fn settle(&mut self, commitment: &Fr) -> Option<Pending> {
+        let nullifier = self
+            .pending
+            .iter()
+            .find(|(_, pending)| pending.commitment == *commitment)
+            .map(|(nullifier, _)| *nullifier)?;
+        self.pending.remove(&nullifier)
+    }
+
+    /// Checks the pending notes against `nullifiers`, returning those spent
+    /// by someone else. They are no longer pending.
+    pub fn sync(&mut self, nullifiers: &NullifierSet) -> Vec<Conflict> {
+        let spent: Vec<Fr> = self
+            .pending
+            .keys()
+            .filter(|nullifier| nullifiers.contains(nullifier))
+            .copied()
+            .collect();
+        spent
+            .into_iter()
+            .map(|nullifier| Conflict {
+                nullifier,
+                note: self.pending.remove(&nullifier).unwrap(),
+            })
+            .collect()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(LOCKS_MAGIC);
+        out.push(SPEND_VERSION);
+        out.extend_from_slice(&(self.locked.len() as u32).to_be_bytes());
+        for (commitment, lock) in &self.locked {
+            out.extend_from_slice(&field_to_bytes(commitment));
+            out.extend_from_slice(lock.peer.as_bytes());
+            out.extend_from_slice(&unix_time(lock.at).to_be_bytes());
+        }
+        out.extend_from_slice(&(self.pending.len() as u32).to_be_bytes());
+        for (nullifier, pending) in &self.pending {
+            out.extend_from_slice(&field_to_bytes(nullifier));
+            out.extend_from_slice(&field_to_bytes(&pending.commitment));
+            out.extend_from_slice(pending.peer.as_bytes());
+            out.extend_from_slice(&unix_time(pending.at).to_be_bytes());
+        }
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, SpendError> {
+        let mut reader = open(bytes, LOCKS_MAGIC)?;
+        let mut locks = SpendLocks::new();
+        for _ in 0..u32::from_be_bytes(reader.array()?) {
+            let commitment = reader.field()?;
+            let lock = Lock {
+                peer: PeerId::from_bytes(reader.array()?),
+                at: reader.timestamp()?,
+            };
+            locks.locked.insert(commitment, lock);
+        }
+        for _ in 0..u32::from_be_bytes(reader.array()?) {
+            let nullifier = reader.field()?;
+            let pending = Pending {
+                commitment: reader.field()?,
+                peer: PeerId::from_bytes(reader.array()?),
+                at: reader.timestamp()?,
+            };
+            locks.pending.insert(nullifier, pending);
+        }
+        Ok(locks)
+    }
+
+    /// Writes the locks to `path` through a temporary file renamed over it.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), SpendError> {
+        Ok(atomic_write(path.as_ref(), &self.encode())?)
+    }
+
+    /// Loads the locks at `path`, or empty ones if there are none yet.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, SpendError> {
+        match fs::read(path) {
+            Ok(bytes) => Self::decode(&bytes),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Starts reading a store after its magic and version.
+fn open<'a>(bytes: &'a [u8], magic: &[u8; 6]) -> Result<Reader<'a, SpendError>, SpendError> {
+    let mut reader = Reader::new(bytes);
+    if reader.take(magic.len()).ok() != Some(magic) {
+        return Err(SpendError::NotASpendStore);
+    }
+    let version = reader.byte()?;
+    if version != SPEND_VERSION {
+        return Err(SpendError::UnsupportedVersion(version));
+    }
+    Ok(reader)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn peer(seed: u8) -> PeerId {
+        PeerId::from_bytes([seed; 32])
+    }
+
+    #[test]
+    fn handed_off_notes_stay_locked() {
+        let (note, other) = (
+            Note::new(1u64, 100u64, 1234u64),
+            Note::new(1u64, 5u64, 9u64),
+        );
+        let mut locks = SpendLocks::new();
+        assert!(locks.lock(&note, peer(2), at(1_000)));
+        assert!(!locks.lock(&note, peer(3), at(2_000)), "locked once");
+        assert!(locks.is_locked(&note.commit()));
+        assert!(!locks.is_locked(&other.commit()));
+        assert_eq!(locks.lock_of(&note.commit()).unwrap().peer, peer(2));
+
+        assert!(locks.unlock(&note.commit()).is_some());
+        assert!(!locks.is_locked(&note.commit()));
+    }
+
+    #[test]
+    fn a_sync_reports_received_notes_spent_elsewhere() {
+        let (kept, spent, doubled) = (
+            Note::new(7u64, 10u64, 1u64),
+            Note::new(7u64, 20u64, 2u64),
+            Note::new(7u64, 30u64, 3u64),
+        );
+        let mut nullifiers = NullifierSet::new();
+        assert_eq!(nullifiers.synced_at(), None);
+        nullifiers.sync([Fr::from(42u64)], at(500));
+        assert!(!nullifiers.is_spent(&doubled));
+
+        let mut locks = SpendLocks::new();
+        for note in [kept, spent, doubled] {
+            locks.receive(&note, peer(1), at(1_000));
+        }
+        // this device spends one note itself, the sender spends another
+        assert!(locks.settle(&spent.commit()).is_some());
+        nullifiers.sync([spent.nullifier(), doubled.nullifier()], at(2_000));
+        assert!(nullifiers.is_spent(&doubled));
+        assert_eq!(nullifiers.synced_at(), Some(at(2_000)));
+
+        let conflicts = locks.sync(&nullifiers);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].nullifier, doubled.nullifier());
+        assert_eq!(conflicts[0].note.commitment, doubled.commit());
+        assert_eq!(conflicts[0].note.peer, peer(1));
+        let pending: Vec<_> = locks.pending().map(|pending| pending.commitment).collect();
+        assert_eq!(pending, [kept.commit()]);
+        assert!(locks.sync(&nullifiers).is_empty(), "reported once");
+    }
+
+    #[test]
+    fn spend_stores_are_saved_and_loaded() {
+        let dir = std::env::temp_dir().join(format!("pdrop-spend-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (nullifiers_path, locks_path) = (dir.join("nullifiers"), dir.join("locks"));
+        assert_eq!(
+            NullifierSet::load(&nullifiers_path).unwrap(),
+            NullifierSet::new()
+        );
+        assert_eq!(SpendLocks::load(&locks_path).unwrap(), SpendLocks::new());
+
+        let mut nullifiers = NullifierSet::new();
+        nullifiers.sync([Fr::from(1u64), Fr::from(2u64)], at(1_500));
+        let mut locks = SpendLocks::new();
+        locks.lock(&Note::new(1u64, 2u64, 3u64), peer(4), at(1_000));
+        locks.receive(&Note::new(5u64, 6u64, 7u64), peer(8), at(1_100));
+        nullifiers.save(&nullifiers_path).unwrap();
+        locks.save(&locks_path).unwrap();
+        assert_eq!(NullifierSet::load(&nullifiers_path).unwrap(), nullifiers);
+        assert_eq!(SpendLocks::load(&locks_path).unwrap(), locks);
+
+        let bytes = locks.encode();
+        assert!(matches!(
+            SpendLocks::decode(&bytes[..bytes.len() - 1]),
+            Err(SpendError::Truncated)
+        ));
+        assert!(matches!(
+            SpendLocks::decode(&nullifiers.encode()),
+            Err(SpendError::NotASpendStore)
+        ));
+        let mut bytes = nullifiers.encode();
+        bytes[NULLIFIERS_MAGIC.len() + 1..][..8].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert!(matches!(
+            NullifierSet::decode(&bytes),
+            Err(SpendError::InvalidTimestamp)
+        ));
+        let mut bytes = locks.encode();
+        let at = LOCKS_MAGIC.len() + 1 + 4 + FIELD_LEN + PeerId::LEN;
+        bytes[at..at + 8].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert!(matches!(
+            SpendLocks::decode(&bytes),
+            Err(SpendError::InvalidTimestamp)
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,821 @@
+//! Chunked, resumable transfer of a payload over an unreliable link.
+//!
+//! The sender opens with `Start`, naming the payload by its sha256 hash, and
+//! the receiver answers with an `Ack` of the chunks it already holds, which
+//! is how a transfer resumes after a reconnect. Chunks then flow within a
+//! sliding window; each is acknowledged selectively (everything below
+//! `next`, plus a bitmap of the 64 chunks after it) and resent if no ack
+//! arrives in time. Once it holds every chunk the receiver checks the
+//! whole-payload hash and answers `Done`.
+//!
+//! Nothing here does I/O or reads a clock: callers feed in messages and the
+//! time, as a `Duration` since any fixed origin, and send what comes out.
+//!
+//! Frames: `version | kind | fields`, integers big-endian.
+//! - `Start`: `hash (32) | len (u32) | chunk_size (u16)`
+//! - `Chunk`: `index (u32) | data`
+//! - `Ack`: `next (u32) | bitmap (u64)`, bit `i` standing for chunk
+//!   `next + 1 + i`
+//! - `Done`: `ok (u8)`
+
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    fmt,
+    time::Duration,
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    codec::{self, Reader},
+    types::PeerId,
+};
+
+pub const TRANSFER_VERSION: u8 = 1;
+pub const HASH_LEN: usize = 32;
+
+const START: u8 = 1;
+const CHUNK: u8 = 2;
+const ACK: u8 = 3;
+const DONE: u8 = 4;
+/// Chunks past `next` an ack reports on.
+const ACK_BITMAP_LEN: u32 = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferError {
+    Truncated,
+    UnsupportedVersion(u8),
+    UnknownMessage(u8),
+    TooLarge(usize),
+    InvalidChunk(u32),
+    InvalidAck(u32),
+    /// A message that makes no sense at this point of the transfer.
+    Unexpected,
+    HashMismatch,
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferError::Truncated => write!(f, "transfer message is truncated"),
+            TransferError::UnsupportedVersion(version) => {
+                write!(f, "unsupported transfer version {version}")
+            }
+            TransferError::UnknownMessage(kind) => write!(f, "unknown transfer message {kind}"),
+            TransferError::TooLarge(len) => write!(f, "payload of {len} bytes is too large"),
+            TransferError::InvalidChunk(index) => write!(f, "chunk {index} does not fit"),
+            TransferError::InvalidAck(next) => write!(f, "ack up to chunk {next} does not fit"),
+            TransferError::Unexpected => write!(f, "unexpected transfer message"),
+            TransferError::HashMismatch => write!(f, "payload does not match its hash"),
+        }
+    }
+}
+
+impl std::error::Error for TransferError {}
+
+impl From<codec::Truncated> for TransferError {
+    fn from(_: codec::Truncated) -> Self {
+        TransferError::Truncated
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferConfig {
+    /// Payload bytes per chunk. The default fits a chunk frame into a BLE
+    /// write at the common 185-byte MTU.
+    pub chunk_size: u16,
+    /// Chunks sent but not yet acknowledged, at most.
+    pub window: usize,
+    /// How long a chunk, or a `Start`, waits for an ack before it is sent
+    /// again.
+    pub retransmit_timeout: Duration,
+    /// Largest payload a receiver accepts.
+    pub max_payload_len: usize,
+}
+
+impl Default for TransferConfig {
+    fn default() -> Self {
+        TransferConfig {
+            chunk_size: 176,
+            window: 8,
+            retransmit_timeout: Duration::from_secs(1),
+            max_payload_len: 1 << 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Start {
+        hash: [u8; HASH_LEN],
+        len: u32,
+        chunk_size: u16,
+    },
+    Chunk {
+        index: u32,
+        data: Vec<u8>,
+    },
+    Ack {
+        next: u32,
+        bitmap: u64,
+    },
+    Done {
+        ok: bool,
+    },
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![TRANSFER_VERSION];
+        match self {
+            Message::Start {
+                hash,
+                len,
+                chunk_size,
+            } => {
+                out.push(START);
+                out.extend_from_slice(hash);
+                out.extend_from_slice(&len.to_be_bytes());
+                out.extend_from_slice(&chunk_size.to_be_bytes());
+            }
+            Message::Chunk { index, data } => {
+                out.push(CHUNK);
+                out.extend_from_slice(&index.to_be_bytes());
+                out.extend_from_slice(data);
+            }
+            Message::Ack { next, bitmap } => {
+                out.push(ACK);
+                out.extend_from_slice(&next.to_be_bytes());
+                out.extend_from_slice(&bitmap.to_be_bytes());
+            }
+            Message::Done { ok } => {
+                out.push(DONE);
+                out.push(*ok as u8);
+            }
+        }
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, TransferError> {
+        let mut reader = Reader::<TransferError>::new(bytes);
+        let version = reader.byte()?;
+        if version != TRANSFER_VERSION {
+            return Err(TransferError::UnsupportedVersion(version));
+        }
+        match reader.byte()? {
+            START => Ok(Message::Start {
+                hash: reader.array()?,
+                len: u32::from_be_bytes(reader.array()?),
+                chunk_size: u16::from_be_bytes(reader.array()?),
+            }),
+            CHUNK => Ok(Message::Chunk {
+                index: u32::from_be_bytes(reader.array()?),
+                data: reader.rest().to_vec(),
+            }),
+            ACK => Ok(Message::Ack {
+                next: u32::from_be_bytes(reader.array()?),
+                bitmap: u64::from_be_bytes(reader.array()?),
+            }),
+            DONE => Ok(Message::Done {
+                ok: reader.byte()? != 0,
+            }),
+            kind => Err(TransferError::UnknownMessage(kind)),
+        }
+    }
+}
+
+pub fn payload_hash(payload: &[u8]) -> [u8; HASH_LEN] {
+    Sha256::digest(payload).into()
+}
+
+/// Sending side of one transfer.
+#[derive(Debug, Clone)]
+pub struct Sender {
+    payload: Vec<u8>,
+    hash: [u8; HASH_LEN],
+    config: TransferConfig,
+    acked: Vec<bool>,
+    /// When each chunk was last sent, while it awaits its ack.
+    in_flight: Vec<Option<Duration>>,
+    /// Whether the receiver has said which chunks it holds.
+    started: bool,
+    /// When the receiver was last asked, or last heard from.
+    probed_at: Option<Duration>,
+    outcome: Option<Result<(), TransferError>>,
+}
+
+impl Sender {
+    /// Fails if `Start` cannot describe the payload, at 4 GiB or more, or
+    /// if chunks of `config` would carry nothing.
+    pub fn new(payload: Vec<u8>, config: &TransferConfig) -> Result<Self, TransferError> {
+        if u32::try_from(payload.len()).is_err() {
+            return Err(TransferError::TooLarge(payload.len()));
+        }
+        if config.chunk_size == 0 {
+            return Err(TransferError::InvalidChunk(0));
+        }
+        let chunks = payload.len().div_ceil(config.chunk_size as usize);
+        Ok(Sender {
+            hash: payload_hash(&payload),
+            payload,
+            config: config.clone(),
+            acked: vec![false; chunks],
+            in_flight: vec![None; chunks],
+            started: false,
+            probed_at: None,
+            outcome: None,
+        })
+    }
+
+    pub fn hash(&self) -> &[u8; HASH_LEN] {
+        &self.hash
+    }
+
+    pub fn chunks(&self) -> usize {
+        self.acked.len()
+    }
+
+    /// Chunks the receiver is known to hold.
+    pub fn acked(&self) -> usize {
+        self.acked.iter().filter(|acked| **acked).count()
+    }
+
+    /// `None` while the transfer runs; then whether the receiver got the
+    /// payload intact.
+    pub fn outcome(&self) -> Option<&Result<(), TransferError>> {
+        self.outcome.as_ref()
+    }
+
+    /// The link was replaced: chunks in flight are forgotten, and the
+    /// receiver is asked again what it holds before any more are sent.
+    pub fn reconnect(&mut self) {
+        self.in_flight.fill(None);
+        self.started = false;
+        self.probed_at = None;
+    }
+
+    fn all_acked(&self) -> bool {
+        self.acked.iter().all(|acked| *acked)
+    }
+
+    fn chunk(&self, index: usize) -> Message {
+        let size = self.config.chunk_size as usize;
+        let end = (index * size + size).min(self.payload.len());
+        Message::Chunk {
+            index: index as u32,
+            data: self.payload[index * size..end].to_vec(),
+        }
+    }
+
+    fn expired(&self, sent_at: Duration, now: Duration) -> bool {
+        sent_at + self.config.retransmit_timeout <= now
+    }
+
+    /// The next message to send at `now`, if any. Call until `None`, then
+    /// again after a message arrives or at `poll_timeout`.
+    pub fn poll_transmit(&mut self, now: Duration) -> Option<Message> {
+        if self.outcome.is_some() {
+            return None;
+        }
+        // until the receiver answers, and once it holds everything but has
+        // not confirmed the hash, ask where it stands
+        if !self.started || self.all_acked() {
+            if self.probed_at.is_some_and(|at| !self.expired(at, now)) {
+                return None;
+            }
+            self.probed_at = Some(now);
+            return Some(Message::Start {
+                hash: self.hash,
+                len: self.payload.len() as u32,
+                chunk_size: self.config.chunk_size,
+            });
+        }
+        let unacked = || (0..self.chunks()).filter(|&index| !self.acked[index]);
+        let resend = unacked()
+            .find(|&index| self.in_flight[index].is_some_and(|sent_at| self.expired(sent_at, now)));
+        let in_flight = unacked()
+            .filter(|&index| self.in_flight[index].is_some())
+            .count();
+        let fresh = || unacked().find(|&index| self.in_flight[index].is_none());
+        let index = resend.or_else(|| (in_flight < self.config.window).then(fresh)?)?;
+        self.in_flight[index] = Some(now);
+        Some(self.chunk(index))
+    }
+
+    /// When `poll_transmit` next has something to resend, if ever without
+    /// further messages.
+    pub fn poll_timeout(&self) -> Option<Duration> {
+        if self.outcome.is_some() {
+            return None;
+        }
+        let timeout = self.config.retransmit_timeout;
+        if !self.started || self.all_acked() {
+            return Some(self.probed_at.map_or(Duration::ZERO, |at| at + timeout));
+        }
+        (0..self.chunks())
+            .filter(|&index| !self.acked[index])
+            .filter_map(|index| Some(self.in_flight[index]? + timeout))
+            .min()
+    }
+
+    pub fn handle(&mut self, message: Message, now: Duration) -> Result<(), TransferError> {
+        match message {
+            Message::Ack { next, bitmap } => {
+                let chunks = self.chunks() as u32;
+                if next > chunks {
+                    return Err(TransferError::InvalidAck(next));
+                }
+                let selected = (0..ACK_BITMAP_LEN)
+                    .filter(|bit| bitmap >> bit & 1 == 1)
+                    .map(|bit| next + 1 + bit)
+                    .filter(|&index| index < chunks);
+                for index in (0..next).chain(selected) {
+                    self.acked[index as usize] = true;
+                    self.in_flight[index as usize] = None;
+                }
+                self.started = true;
+                self.probed_at = Some(now);
+                Ok(())
+            }
+            Message::Done { ok } => {
+                self.outcome = Some(if ok {
+                    Ok(())
+                } else {
+                    Err(TransferError::HashMismatch)
+                });
+                Ok(())
+            }
+            Message::Start { .. } | Message::Chunk { .. } => Err(TransferError::Unexpected),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Incoming {
+    hash: [u8; HASH_LEN],
+    len: usize,
+    chunk_size: usize,
+    chunks: Vec<Option<Vec<u8>>>,
+    /// Set once every chunk arrived and the hash matched.
+    verified: bool,
+}
+
+impl Incoming {
+    fn ack(&self) -> Message {
+        let next = self
+            .chunks
+            .iter()
+            .position(Option::is_none)
+            .unwrap_or(self.chunks.len());
+        let bitmap = (0..ACK_BITMAP_LEN as usize)
+            .filter(|bit| self.chunks.get(next + 1 + bit).is_some_and(Option::is_some))
+            .fold(0, |bitmap, bit| bitmap | 1 << bit);
+        Message::Ack {
+            next: next as u32,
+            bitmap,
+        }
+    }
+}
+
+/// Receiving side of the transfers from one peer. A partial transfer is
+/// kept until another payload is started, so the sender can resume it.
+#[derive(Debug, Clone)]
+pub struct Receiver {
+    config: TransferConfig,
+    incoming: Option<Incoming>,
+    payload: Option<Vec<u8>>,
+}
+
+impl Receiver {
+    pub fn new(config: &TransferConfig) -> Self {
+        Receiver {
+            config: config.clone(),
+            incoming: None,
+            payload: None,
+        }
+    }
+
+    /// The verified payload, once.
+    pub fn take_payload(&mut self) -> Option<Vec<u8>> {
+        self.payload.take()
+    }
+
+    /// Chunks held of the current transfer.
+    pub fn received(&self) -> usize {
+        self.incoming.as_ref().map_or(0, |incoming| {
+            incoming
+                .chunks
+                .iter()
+                .filter(|chunk| chunk.is_some())
+                .count()
+        })
+    }
+
+    /// Handles a message from the sender, returning the replies.
+    pub fn handle(&mut self, message: Message) -> Result<Vec<Message>, TransferError> {
+        match message {
+            Message::Start {
+                hash,
+                len,
+                chunk_size,
+            } => {
+                let len = len as usize;
+                if len > self.config.max_payload_len {
+                    return Err(TransferError::TooLarge(len));
+                }
+                if chunk_size == 0 && len > 0 {
+                    return Err(TransferError::InvalidChunk(0));
+                }
+                let chunk_size = chunk_size as usize;
+                let resumed = self.incoming.as_ref().is_some_and(|incoming| {
+                    (incoming.hash, incoming.len, incoming.chunk_size) == (hash, len, chunk_size)
+                });
+                if !resumed {
+                    self.incoming = Some(Incoming {
+                        hash,
+                        len,
+                        chunk_size,
+                        chunks: vec![None; len.div_ceil(chunk_size.max(1))],
+                        verified: false,
+                    });
+                }
+                self.replies()
+            }
+            Message::Chunk { index, data } => {
+                let incoming = self.incoming.as_mut().ok_or(TransferError::Unexpected)?;
+                let at = index as usize * incoming.chunk_size;
+                let expected = incoming.len.saturating_sub(at).min(incoming.chunk_size);
+                if index as usize >= incoming.chunks.len() || data.len() != expected {
+                    return Err(TransferError::InvalidChunk(index));
+                }
+                incoming.chunks[index as usize].get_or_insert(data);
+                self.replies()
+            }
+            Message::Ack { .. } | Message::Done { .. } => Err(TransferError::Unexpected),
+        }
+    }
+
+    /// An ack of what is held, and the verdict once everything is.
+    fn replies(&mut self) -> Result<Vec<Message>, TransferError> {
+        let incoming = self.incoming.as_mut().ok_or(TransferError::Unexpected)?;
+        let mut replies = vec![incoming.ack()];
+        if incoming.chunks.iter().any(Option::is_none) {
+            return Ok(replies);
+        }
+        if !incoming.verified {
+            let payload: Vec<u8> = incoming
+                .chunks
+                .iter()
+                .flatten()
+                .flatten()
+                .copied()
+                .collect();
+            if payload_hash(&payload) != incoming.hash {
+                // start over should the sender try again
+                self.incoming = None;
+                replies.push(Message::Done { ok: false });
+                return Ok(replies);
+            }
+            incoming.verified = true;
+            self.payload = Some(payload);
+        }
+        replies.push(Message::Done { ok: true });
+        Ok(replies)
+    }
+}
+
+/// Transfers by peer, kept across reconnects so that an interrupted
+/// transfer to or from the same `PeerId` picks up where it stopped.
+#[derive(Debug, Clone, Default)]
+pub struct Transfers {
+    config: TransferConfig,
+    outgoing: HashMap<PeerId, Sender>,
+    incoming: HashMap<PeerId, Receiver>,
+}
+
+impl Transfers {
+    pub fn new(config: TransferConfig) -> Self {
+        Transfers {
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// The sender of `payload` to `peer`: the interrupted one, resumed, if it
+    /// carries the same payload, else a fresh one.
+    pub fn outgoing(
+        &mut self,
+        peer: PeerId,
+        payload: Vec<u8>,
+    ) -> Result<&mut Sender, TransferError> {
+        let hash = payload_hash(&payload);
+        match self.outgoing.entry(peer) {
+            Entry::Occupied(entry) => {
+                let sender = entry.into_mut();
+                if sender.hash == hash && sender.outcome.is_none() {
+                    sender.reconnect();
+                } else {
+                    *sender = Sender::new(payload, &self.config)?;
+                }
+                Ok(sender)
+            }
+            Entry::Vacant(entry) => Ok(entry.insert(Sender::new(payload, &self.config)?)),
+        }
+    }
+
+    pub fn incoming(&mut self, peer: PeerId) -> &mut Receiver {
+        let config = &self.config;
+        self.incoming
+            .entry(peer)
+            .or_insert_with(|| Receiver::new(config))
+    }
+
+    /// Forgets the transfers with `peer`, e.g. once they completed.
+    pub fn remove(&mut self, peer: &PeerId) {
+        self.outgoing.remove(peer);
+        self.incoming.remove(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> TransferConfig {
+        TransferConfig {
+            chunk_size: 16,
+            window: 4,
+            retransmit_timeout: Duration::from_millis(500),
+            max_payload_len: 4096,
+        }
+    }
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    /// A link dropping frames at random, reproducibly, and counting the
+    /// chunks it carries. It goes dead after `frames` frames.
+    struct Link {
+        loss: u64,
+        rng: u64,
+        frames: usize,
+        chunks_sent: usize,
+    }
+
+    impl Link {
+        fn new(loss_percent: u64, seed: u64) -> Self {
+            Link {
+                loss: loss_percent,
+                rng: seed | 1,
+                frames: usize::MAX,
+                chunks_sent: 0,
+            }
+        }
+
+        /// Whether the next frame gets through.
+        fn delivers(&mut self) -> bool {
+            if self.frames == 0 {
+                return false;
+            }
+            self.frames -= 1;
+            self.rng ^= self.rng << 13;
+            self.rng ^= self.rng >> 7;
+            self.rng ^= self.rng << 17;
+            self.rng % 100 >= self.loss
+        }
+
+        /// Runs the exchange for `steps` ticks of 100 ms from `from`, frames
+        /// crossing the wire encoded, until the sender has an outcome.
+        fn run(
+            &mut self,
+            sender: &mut Sender,
+            receiver: &mut Receiver,
+            from: u64,
+            steps: u64,
+        ) -> u64 {
+            for step in from..from + steps {
+                let now = Duration::from_millis(100 * step);
+                while let Some(message) = sender.poll_transmit(now) {
+                    self.chunks_sent += matches!(message, Message::Chunk { .. }) as usize;
+                    if !self.delivers() {
+                        continue;
+                    }
+                    let replies = receiver
+                        .handle(Message::decode(&message.encode()).unwrap())
+                        .unwrap();
+                    for reply in replies {
+                        if self.delivers() {
+                            let reply = Message::decode(&reply.encode()).unwrap();
+                            sender.handle(reply, now).unwrap();
+                        }
+                    }
+                }
+                if sender.outcome().is_some() {
+                    return step;
+                }
+            }
+            from + steps
+        }
+    }
+
+    #[test]
+    fn messages_round_trip_and_reject_garbage() {
+        let messages = [
+            Message::Start {
+                hash: [9; HASH_LEN],
+                len: 1000,
+                chunk_size: 16,
+            },
+            Message::Chunk {
+                index: 3,
+                data: vec![1, 2, 3],
+            },
+            Message::Ack {
+                next: 5,
+                bitmap: 0b1010,
+            },
+            Message::Done { ok: false },
+        ];
+        for message in messages {
+            assert_eq!(Message::decode(&message.encode()), Ok(message));
+        }
+        assert_eq!(
+            Message::decode(&[TRANSFER_VERSION, 9]),
+            Err(TransferError::UnknownMessage(9))
+        );
+        assert_eq!(
+            Message::decode(&[TRANSFER_VERSION + 1, ACK]),
+            Err(TransferError::UnsupportedVersion(TRANSFER_VERSION + 1))
+        );
+        assert_eq!(
+            Message::decode(&[TRANSFER_VERSION, ACK, 0]),
+            Err(TransferError::Truncated)
+        );
+    }
+
+    #[test]
+    fn payload_crosses_a_clean_link_in_one_pass() {
+        let data = payload(1000);
+        let mut sender = Sender::new(data.clone(), &config()).unwrap();
+        let mut receiver = Receiver::new(&config());
+        let mut link = Link::new(0, 1);
+        link.run(&mut sender, &mut receiver, 0, 100);
+
+        assert_eq!(sender.outcome(), Some(&Ok(())));
+        assert_eq!(sender.chunks(), 63);
+        assert_eq!(link.chunks_sent, 63);
+        assert_eq!(receiver.take_payload(), Some(data));
+        assert_eq!(receiver.take_payload(), None);
+    }
+
+    #[test]
+    fn payload_survives_a_lossy_link() {
+        for seed in 1..=5 {
+            let data = payload(2000);
+            let mut sender = Sender::new(data.clone(), &config()).unwrap();
+            let mut receiver = Receiver::new(&config());
+            let mut link = Link::new(30, seed);
+            link.run(&mut sender, &mut receiver, 0, 10_000);
+
+            assert_eq!(sender.outcome(), Some(&Ok(())), "seed {seed}");
+            assert_eq!(receiver.take_payload(), Some(data));
+            assert!(link.chunks_sent > sender.chunks());
+        }
+    }
+
+    #[test]
+    fn window_limits_chunks_in_flight() {
+        let mut sender = Sender::new(payload(1000), &config()).unwrap();
+        let mut receiver = Receiver::new(&config());
+        let start = sender.poll_transmit(Duration::ZERO).unwrap();
+        assert_eq!(sender.poll_transmit(Duration::ZERO), None);
+        for reply in receiver.handle(start).unwrap() {
+            sender.handle(reply, Duration::ZERO).unwrap();
+        }
+        let sent: Vec<_> = std::iter::from_fn(|| sender.poll_transmit(Duration::ZERO)).collect();
+        assert_eq!(sent.len(), config().window);
+        assert_eq!(sender.poll_timeout(), Some(config().retransmit_timeout));
+
+        // chunk 1 is acked selectively; chunk 0 is resent once it times out
+        for message in &sent[1..] {
+            let replies = receiver.handle(message.clone()).unwrap();
+            assert!(matches!(replies[0], Message::Ack { next: 0, .. }));
+            sender.handle(replies[0].clone(), Duration::ZERO).unwrap();
+        }
+        assert_eq!(sender.acked(), 3);
+        let later = config().retransmit_timeout;
+        assert!(matches!(
+            sender.poll_transmit(later),
+            Some(Message::Chunk { index: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn transfer_resumes_after_reconnecting_to_the_same_peer() {
+        let bob = PeerId::from_public_key(b"bob");
+        let data = payload(1000);
+        let mut alice = Transfers::new(config());
+        let mut bob_side = Transfers::new(config());
+
+        // the link dies after a while
+        let mut link = Link::new(0, 1);
+        link.frames = 30;
+        let sender = alice.outgoing(bob, data.clone()).unwrap();
+        link.run(sender, bob_side.incoming(bob), 0, 1);
+        let sender = alice.outgoing(bob, data.clone()).unwrap();
+        let acked = sender.acked();
+        assert!(acked > 0 && acked < sender.chunks());
+        assert!(
+            matches!(
+                sender.poll_transmit(Duration::ZERO),
+                Some(Message::Start { .. })
+            ),
+            "the receiver is asked what it holds first"
+        );
+
+        // reconnected: the receiver's ack skips what it already holds
+        let mut link = Link::new(0, 1);
+        let sender = alice.outgoing(bob, data.clone()).unwrap();
+        link.run(sender, bob_side.incoming(bob), 10, 100);
+        assert_eq!(sender.outcome(), Some(&Ok(())));
+        assert_eq!(link.chunks_sent, sender.chunks() - acked);
+        assert_eq!(bob_side.incoming(bob).take_payload(), Some(data));
+
+        // another payload starts afresh
+        let other = alice.outgoing(bob, payload(10)).unwrap();
+        assert_eq!(other.acked(), 0);
+    }
+
+    #[test]
+    fn corrupted_payload_fails_the_hash_check() {
+        let data = payload(100);
+        let mut sender = Sender::new(data.clone(), &config()).unwrap();
+        let mut receiver = Receiver::new(&config());
+        let mut now = Duration::ZERO;
+        while sender.outcome().is_none() {
+            while let Some(mut message) = sender.poll_transmit(now) {
+                if let Message::Chunk { index: 2, data } = &mut message {
+                    data[0] ^= 1;
+                }
+                for reply in receiver.handle(message).unwrap() {
+                    sender.handle(reply, now).unwrap();
+                }
+            }
+            now += Duration::from_millis(100);
+        }
+        assert_eq!(sender.outcome(), Some(&Err(TransferError::HashMismatch)));
+        assert_eq!(receiver.take_payload(), None);
+        assert_eq!(receiver.received(), 0);
+    }
+
+    #[test]
+    fn receiver_rejects_what_does_not_fit() {
+        let mut receiver = Receiver::new(&config());
+        assert_eq!(
+            receiver.handle(Message::Chunk {
+                index: 0,
+                data: vec![0; 16]
+            }),
+            Err(TransferError::Unexpected)
+        );
+        let start = |len| Message::Start {
+            hash: [0; HASH_LEN],
+            len,
+            chunk_size: 16,
+        };
+        assert_eq!(
+            receiver.handle(start(5000)),
+            Err(TransferError::TooLarge(5000))
+        );
+        receiver.handle(start(20)).unwrap();
+        for (index, len) in [(1, 16), (2, 4), (0, 15)] {
+            assert_eq!(
+                receiver.handle(Message::Chunk {
+                    index,
+                    data: vec![0; len]
+                }),
+                Err(TransferError::InvalidChunk(index))
+            );
+        }
+
+        // an empty payload needs no chunks at all
+        let mut sender = Sender::new(Vec::new(), &config()).unwrap();
+        let start = sender.poll_transmit(Duration::ZERO).unwrap();
+        for reply in receiver.handle(start).unwrap() {
+            sender.handle(reply, Duration::ZERO).unwrap();
+        }
+        assert_eq!(sender.outcome(), Some(&Ok(())));
+        assert_eq!(receiver.take_payload(), Some(Vec::new()));
+
+        // chunks that carry nothing would never get anywhere
+        let empty_chunks = TransferConfig {
+            chunk_size: 0,
+            ..config()
+        };
+        assert_eq!(
+            Sender::new(payload(10), &empty_chunks).unwrap_err(),
+            TransferError::InvalidChunk(0)
+        );
+    }
+}
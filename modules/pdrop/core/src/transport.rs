@@ -0,0 +1,291 @@
+//! Connections to discovered peers. A `Transport` dials a `PeerInfo` or
+//! accepts incoming connections; either way the result is a `Connection`,
+//! a framed, bidirectional byte pipe that looks the same over BLE, sockets
+//! or memory.
+
+use std::{
+    fmt, io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use futures::{Sink, SinkExt, Stream, StreamExt, channel::mpsc};
+
+use crate::{
+    identity::{PeerInfo, TransportAddress},
+    types::{BoxFutureResponse, BoxStreamResponse},
+};
+
+/// One message as handed to and received from a connection. Frames never
+/// exceed the connection's MTU; fragmentation, where the medium needs it, is
+/// the transport's business.
+pub type Frame = Vec<u8>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloseReason {
+    /// This end closed the connection.
+    Local,
+    /// The other end closed it.
+    Remote,
+    /// The link went away, e.g. the peer moved out of range.
+    LinkLost,
+    /// Nothing was heard from the peer for too long.
+    Timeout,
+    Failed(String),
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseReason::Local => write!(f, "closed locally"),
+            CloseReason::Remote => write!(f, "closed by peer"),
+            CloseReason::LinkLost => write!(f, "link lost"),
+            CloseReason::Timeout => write!(f, "timed out"),
+            CloseReason::Failed(reason) => write!(f, "failed: {reason}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TransportError {
+    /// None of the peer's addresses is usable by this transport.
+    NoAddress,
+    Unreachable,
+    FrameTooLarge {
+        len: usize,
+        mtu: usize,
+    },
+    Closed(CloseReason),
+    IoError(io::Error),
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::NoAddress => write!(f, "peer has no address for this transport"),
+            TransportError::Unreachable => write!(f, "peer is unreachable"),
+            TransportError::FrameTooLarge { len, mtu } => {
+                write!(f, "frame of {len} bytes exceeds the mtu of {mtu}")
+            }
+            TransportError::Closed(reason) => write!(f, "connection {reason}"),
+            TransportError::IoError(err) => write!(f, "transport i/o failed: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {}
+
+impl From<io::Error> for TransportError {
+    fn from(err: io::Error) -> Self {
+        TransportError::IoError(err)
+    }
+}
+
+/// Why a connection ended, shared between the connection and the backend
+/// driving it. The first reason recorded sticks.
+#[derive(Debug, Clone, Default)]
+pub struct CloseHandle(Arc<Mutex<Option<CloseReason>>>);
+
+impl CloseHandle {
+    pub fn close(&self, reason: CloseReason) {
+        self.0.lock().unwrap().get_or_insert(reason);
+    }
+
+    pub fn reason(&self) -> Option<CloseReason> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+pub type BoxFrameSink = Pin<Box<dyn Sink<Frame, Error = TransportError> + Send>>;
+
+/// A framed, bidirectional connection to a peer.
+///
+/// Sending goes through `Sink`: `poll_ready` stays pending while the peer
+/// or the medium cannot take more, which is how backpressure reaches the
+/// sender. Receiving goes through `Stream`, which ends when the connection
+/// does; `close_reason` then says why.
+pub struct Connection {
+    remote: Option<TransportAddress>,
+    mtu: usize,
+    sink: BoxFrameSink,
+    stream: BoxStreamResponse<Frame>,
+    closed: CloseHandle,
+}
+
+impl Connection {
+    /// Wraps a backend's halves. The backend records close reasons on
+    /// `closed`; a stream that ends without one was closed by the peer.
+    pub fn new(
+        remote: Option<TransportAddress>,
+        mtu: usize,
+        sink: BoxFrameSink,
+        stream: BoxStreamResponse<Frame>,
+        closed: CloseHandle,
+    ) -> Self {
+        Connection {
+            remote,
+            mtu,
+            sink,
+            stream,
+            closed,
+        }
+    }
+
+    /// Two connected ends in memory, each side buffering up to `capacity`
+    /// frames before pushing back.
+    pub fn pair(mtu: usize, capacity: usize) -> (Connection, Connection) {
+        let (a_tx, b_rx) = mpsc::channel(capacity);
+        let (b_tx, a_rx) = mpsc::channel(capacity);
+        let end = |tx: mpsc::Sender<Frame>, rx: mpsc::Receiver<Frame>| {
+            let closed = CloseHandle::default();
+            let peer_closed = closed.clone();
+            let sink = tx.sink_map_err(move |_| {
+                TransportError::Closed(peer_closed.reason().unwrap_or(CloseReason::Remote))
+            });
+            Connection::new(None, mtu, Box::pin(sink), Box::pin(rx), closed)
+        };
+        (end(a_tx, a_rx), end(b_tx, b_rx))
+    }
+
+    pub fn remote_address(&self) -> Option<TransportAddress> {
+        self.remote
+    }
+
+    /// Largest frame this connection carries.
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// Why the connection ended, once it has.
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.closed.reason()
+    }
+
+    /// Closes the connection, telling the peer where the medium allows.
+    pub async fn close(&mut self) -> Result<(), TransportError> {
+        self.closed.close(CloseReason::Local);
+        self.sink.close().await
+    }
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Connection")
+            .field("remote", &self.remote)
+            .field("mtu", &self.mtu)
+            .field("closed", &self.closed.reason())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Sink<Frame> for Connection {
+    type Error = TransportError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if let Some(reason) = self.closed.reason() {
+            return Poll::Ready(Err(TransportError::Closed(reason)));
+        }
+        self.sink.as_mut().poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, frame: Frame) -> Result<(), Self::Error> {
+        if frame.len() > self.mtu {
+            return Err(TransportError::FrameTooLarge {
+                len: frame.len(),
+                mtu: self.mtu,
+            });
+        }
+        self.sink.as_mut().start_send(frame)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.sink.as_mut().poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.closed.close(CloseReason::Local);
+        self.sink.as_mut().poll_close(cx)
+    }
+}
+
+impl Stream for Connection {
+    type Item = Frame;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Frame>> {
+        let next = self.stream.poll_next_unpin(cx);
+        if let Poll::Ready(None) = next {
+            self.closed.close(CloseReason::Remote);
+        }
+        next
+    }
+}
+
+/// Opens connections to peers found through `Discovery`.
+pub trait Transport {
+    type Error;
+
+    /// Connects to `peer` using whichever of its addresses this transport
+    /// understands.
+    fn dial(&self, peer: &PeerInfo) -> BoxFutureResponse<Connection, Self::Error>;
+
+    /// Starts accepting connections. Each call takes over from the previous
+    /// stream.
+    fn listen(&self) -> BoxFutureResponse<BoxStreamResponse<Connection>, Self::Error>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn paired_ends_exchange_frames_and_see_the_close() {
+        block_on(async {
+            let (mut a, mut b) = Connection::pair(16, 4);
+            a.send(b"ping".to_vec()).await.unwrap();
+            assert_eq!(b.next().await.unwrap(), b"ping");
+            b.send(b"pong".to_vec()).await.unwrap();
+            assert_eq!(a.next().await.unwrap(), b"pong");
+
+            a.close().await.unwrap();
+            assert_eq!(b.next().await, None);
+            assert_eq!(b.close_reason(), Some(CloseReason::Remote));
+            assert_eq!(a.close_reason(), Some(CloseReason::Local));
+            assert!(matches!(
+                a.send(vec![1]).await,
+                Err(TransportError::Closed(CloseReason::Local))
+            ));
+        });
+    }
+
+    #[test]
+    fn frames_over_the_mtu_are_refused() {
+        block_on(async {
+            let (mut a, _b) = Connection::pair(4, 4);
+            assert!(matches!(
+                a.send(vec![0; 5]).await,
+                Err(TransportError::FrameTooLarge { len: 5, mtu: 4 })
+            ));
+            a.send(vec![0; 4]).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn a_full_peer_pushes_back() {
+        block_on(async {
+            let (mut a, mut b) = Connection::pair(16, 1);
+            let mut accepted = 0u8;
+            while futures::poll!(futures::future::poll_fn(|cx| a.poll_ready_unpin(cx))).is_ready() {
+                a.start_send_unpin(vec![accepted]).unwrap();
+                accepted += 1;
+            }
+            assert!((1..=3).contains(&accepted), "accepted {accepted}");
+
+            assert_eq!(b.next().await.unwrap(), [0]);
+            futures::future::poll_fn(|cx| a.poll_ready_unpin(cx))
+                .await
+                .unwrap();
+        });
+    }
+}
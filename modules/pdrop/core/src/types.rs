@@ -1,8 +1,74 @@
-use std::{future, pin::Pin, process::Output};
+use std::{fmt, pin::Pin, str::FromStr};
 
 use futures::Stream;
+use sha2::{Digest, Sha256};
+
+/// Fingerprint of a peer's long-term public key.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PeerId([u8; 32]);
+
+impl PeerId {
+    pub const LEN: usize = 32;
+
+    pub fn from_public_key(public_key: &[u8]) -> Self {
+        PeerId(Sha256::digest(public_key).into())
+    }
+
+    /// Stand-in id for a peer seen on a medium that does not carry its key,
+    /// derived from whatever stable handle the medium offers. It is replaced
+    /// by the real id once the peer presents its key.
+    pub fn provisional(handle: &[u8]) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(b"pdrop-provisional-id");
+        hasher.update(handle);
+        PeerId(hasher.finalize().into())
+    }
+
+    pub const fn from_bytes(bytes: [u8; 32]) -> Self {
+        PeerId(bytes)
+    }
+
+    pub const fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl fmt::Debug for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PeerId({}…)", hex::encode(&self.0[..4]))
+    }
+}
+
+impl FromStr for PeerId {
+    type Err = hex::FromHexError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bytes = [0; 32];
+        hex::decode_to_slice(s, &mut bytes)?;
+        Ok(PeerId(bytes))
+    }
+}
 
-pub type PeerId = &'static str;
 pub type BoxFutureResponse<T, E> = Pin<Box<dyn Future<Output = Result<T, E>> + Send + 'static>>;
 
-pub type BoxStreamResponse<T> = Pin<Box<dyn Stream<Item = T>>>;
+pub type BoxStreamResponse<T> = Pin<Box<dyn Stream<Item = T> + Send>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peer_id_is_a_key_fingerprint_and_parses_back() {
+        let id = PeerId::from_public_key(&[1; 32]);
+        assert_eq!(id, PeerId::from_public_key(&[1; 32]));
+        assert_ne!(id, PeerId::from_public_key(&[2; 32]));
+        assert_ne!(id, PeerId::provisional(&[1; 32]));
+        assert_eq!(id.to_string().parse::<PeerId>(), Ok(id));
+    }
+}
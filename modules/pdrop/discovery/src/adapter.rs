@@ -0,0 +1,251 @@
+//! Local Bluetooth adapters: picking one among several, and noticing when
+//! they are plugged in, unplugged, or switched on and off.
+
+#[cfg(target_os = "linux")]
+use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
+
+use btleplug::{
+    api::{Central, CentralState, Manager},
+    platform,
+};
+
+/// Which adapter to use when the machine has several.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdapterSelector {
+    /// Position in the platform's adapter list.
+    Index(usize),
+    /// Platform name, e.g. `hci1` on Linux.
+    Name(String),
+    /// Controller address. Only known on Linux.
+    Address([u8; 6]),
+}
+
+impl Default for AdapterSelector {
+    fn default() -> Self {
+        AdapterSelector::Index(0)
+    }
+}
+
+impl AdapterSelector {
+    pub fn matches(&self, adapter: &AdapterDescription) -> bool {
+        match self {
+            AdapterSelector::Index(index) => adapter.index == *index,
+            AdapterSelector::Name(name) => adapter.name == *name,
+            AdapterSelector::Address(address) => adapter.address == Some(*address),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdapterDescription {
+    pub index: usize,
+    pub name: String,
+    pub address: Option<[u8; 6]>,
+    pub powered: bool,
+}
+
+impl fmt::Display for AdapterDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let Some([a, b, c, d, e, g]) = self.address {
+            write!(f, " ({a:02X}:{b:02X}:{c:02X}:{d:02X}:{e:02X}:{g:02X})")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdapterEvent {
+    Added(AdapterDescription),
+    Removed(AdapterDescription),
+    PoweredOn(AdapterDescription),
+    PoweredOff(AdapterDescription),
+}
+
+impl AdapterEvent {
+    /// Whether this event makes the adapter `selector` picks usable again,
+    /// so scanning and advertising should resume on it.
+    pub fn brings_back(&self, selector: &AdapterSelector) -> bool {
+        match self {
+            AdapterEvent::Added(adapter) => adapter.powered && selector.matches(adapter),
+            AdapterEvent::PoweredOn(adapter) => selector.matches(adapter),
+            AdapterEvent::Removed(_) | AdapterEvent::PoweredOff(_) => false,
+        }
+    }
+}
+
+/// Turns successive snapshots of the adapter list into events. Adapters are
+/// told apart by name, which survives replugging.
+#[derive(Debug, Default)]
+pub struct AdapterWatch {
+    known: BTreeMap<String, AdapterDescription>,
+}
+
+impl AdapterWatch {
+    pub fn update(&mut self, adapters: Vec<AdapterDescription>) -> Vec<AdapterEvent> {
+        let mut events = Vec::new();
+        let mut previous = std::mem::take(&mut self.known);
+        for adapter in adapters {
+            match previous.remove(&adapter.name) {
+                None => events.push(AdapterEvent::Added(adapter.clone())),
+                Some(known) if known.powered != adapter.powered => {
+                    events.push(if adapter.powered {
+                        AdapterEvent::PoweredOn(adapter.clone())
+                    } else {
+                        AdapterEvent::PoweredOff(adapter.clone())
+                    })
+                }
+                Some(_) => {}
+            }
+            self.known.insert(adapter.name.clone(), adapter);
+        }
+        events.extend(previous.into_values().map(AdapterEvent::Removed));
+        events
+    }
+}
+
+/// The platform's adapters, described well enough to select among them.
+#[derive(Clone)]
+pub(crate) struct Adapters {
+    manager: platform::Manager,
+    #[cfg(target_os = "linux")]
+    bluez: Arc<tokio::sync::OnceCell<Option<bluez_async::BluetoothSession>>>,
+}
+
+impl Adapters {
+    pub(crate) fn new(manager: platform::Manager) -> Self {
+        Adapters {
+            manager,
+            #[cfg(target_os = "linux")]
+            bluez: Arc::default(),
+        }
+    }
+
+    pub(crate) async fn describe(
+        &self,
+    ) -> Result<Vec<(platform::Adapter, AdapterDescription)>, btleplug::Error> {
+        let addresses = self.addresses().await;
+        let mut described = Vec::new();
+        for (index, adapter) in self.manager.adapters().await?.into_iter().enumerate() {
+            // btleplug describes an adapter as `name (details)`
+            let info = adapter.adapter_info().await?;
+            let name = info.split(" (").next().unwrap_or_default().to_string();
+            let powered = matches!(adapter.adapter_state().await, Ok(CentralState::PoweredOn));
+            let description = AdapterDescription {
+                index,
+                address: addresses.get(&name).copied(),
+                name,
+                powered,
+            };
+            described.push((adapter, description));
+        }
+        Ok(described)
+    }
+
+    pub(crate) async fn select(
+        &self,
+        selector: &AdapterSelector,
+    ) -> Result<Option<(platform::Adapter, AdapterDescription)>, btleplug::Error> {
+        Ok(self
+            .describe()
+            .await?
+            .into_iter()
+            .find(|(_, description)| selector.matches(description)))
+    }
+
+    /// Controller addresses by adapter name, from BlueZ. btleplug does not
+    /// expose them.
+    #[cfg(target_os = "linux")]
+    async fn addresses(&self) -> HashMap<String, [u8; 6]> {
+        let session = self
+            .bluez
+            .get_or_init(|| async {
+                // the session's D-Bus task is already spawned; its handle is
+                // only needed to learn that the bus went away
+                let (_handle, session) = bluez_async::BluetoothSession::new().await.ok()?;
+                Some(session)
+            })
+            .await;
+        let Some(session) = session else {
+            return HashMap::new();
+        };
+        let adapters = session.get_adapters().await.unwrap_or_default();
+        adapters
+            .into_iter()
+            .map(|adapter| (adapter.id.to_string(), adapter.mac_address.into()))
+            .collect()
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    async fn addresses(&self) -> HashMap<String, [u8; 6]> {
+        HashMap::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn adapter(index: usize, name: &str, powered: bool) -> AdapterDescription {
+        AdapterDescription {
+            index,
+            name: name.to_string(),
+            address: Some([0, 0, 0, 0, 0, index as u8]),
+            powered,
+        }
+    }
+
+    #[test]
+    fn selectors_match_by_index_name_or_address() {
+        let hci1 = adapter(1, "hci1", true);
+        assert!(AdapterSelector::Index(1).matches(&hci1));
+        assert!(!AdapterSelector::default().matches(&hci1));
+        assert!(AdapterSelector::Name("hci1".to_string()).matches(&hci1));
+        assert!(AdapterSelector::Address([0, 0, 0, 0, 0, 1]).matches(&hci1));
+        assert!(!AdapterSelector::Address([0, 0, 0, 0, 0, 2]).matches(&hci1));
+        let unknown = AdapterDescription {
+            address: None,
+            ..hci1
+        };
+        assert!(!AdapterSelector::Address([0, 0, 0, 0, 0, 1]).matches(&unknown));
+    }
+
+    #[test]
+    fn snapshots_become_plug_and_power_events() {
+        let mut watch = AdapterWatch::default();
+        assert_eq!(
+            watch.update(vec![adapter(0, "hci0", true)]),
+            [AdapterEvent::Added(adapter(0, "hci0", true))]
+        );
+        assert_eq!(watch.update(vec![adapter(0, "hci0", true)]), []);
+        assert_eq!(
+            watch.update(vec![adapter(0, "hci0", false), adapter(1, "hci1", true)]),
+            [
+                AdapterEvent::PoweredOff(adapter(0, "hci0", false)),
+                AdapterEvent::Added(adapter(1, "hci1", true)),
+            ]
+        );
+        assert_eq!(
+            watch.update(vec![adapter(0, "hci1", true)]),
+            [AdapterEvent::Removed(adapter(0, "hci0", false))]
+        );
+        assert_eq!(
+            watch.update(vec![adapter(0, "hci0", true), adapter(1, "hci1", true)]),
+            [AdapterEvent::Added(adapter(0, "hci0", true))]
+        );
+    }
+
+    #[test]
+    fn only_the_selected_adapter_coming_back_resumes() {
+        let selector = AdapterSelector::Name("hci1".to_string());
+        assert!(AdapterEvent::PoweredOn(adapter(1, "hci1", true)).brings_back(&selector));
+        assert!(AdapterEvent::Added(adapter(1, "hci1", true)).brings_back(&selector));
+        assert!(!AdapterEvent::Added(adapter(1, "hci1", false)).brings_back(&selector));
+        assert!(!AdapterEvent::PoweredOn(adapter(0, "hci0", true)).brings_back(&selector));
+        assert!(!AdapterEvent::PoweredOff(adapter(1, "hci1", false)).brings_back(&selector));
+    }
+}
@@ -1,93 +1,1133 @@
-use core::types::BoxFutureResponse;
+use core::{
+    advertisement::{Advertisement, CompactAdvertisement},
+    discovery::DiscoveryEvent,
+    identity::{Capabilities, DeviceIdentity, PROTOCOL_VERSION, PeerInfo, TransportAddress},
+    rotation::{RotationConfig, RotationSecret},
+    types::{BoxFutureResponse, BoxStreamResponse, PeerId},
+};
+use std::{
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
 
 use bluster::Peripheral;
 use btleplug::{
-    api::{Central, Manager, ScanFilter},
+    api::{Central, CentralEvent, Peripheral as _, PeripheralProperties, ScanFilter},
     platform::{self},
 };
+use futures::{
+    StreamExt,
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    future, stream,
+};
+use tokio::{runtime::Handle, task::JoinHandle, time::Instant};
 use uuid::Uuid;
 
+use crate::{
+    adapter::{AdapterEvent, AdapterSelector, AdapterWatch, Adapters},
+    gatt::GattConfig,
+    tracker::PeerTracker,
+};
+
+/// The pdrop GATT service, advertised by `broadcast`. Scans only surface
+/// peripherals listing it.
+pub const PDROP_SERVICE_UUID: Uuid = Uuid::from_u128(0x7064726f_7000_4b6e_a3c5_8f0d2c9e1a00);
+
 pub struct BleDiscovery {
-    manager: btleplug::platform::Manager,
-    peripheral: Option<Peripheral>,
+    pub(crate) adapters: Adapters,
+    pub(crate) peripheral: Option<Arc<Peripheral>>,
+    pub(crate) config: BleDiscoveryConfig,
+    radio: Radio,
+    adapter_events_tx: Arc<Mutex<UnboundedSender<AdapterEvent>>>,
+    adapter_events: Option<UnboundedReceiver<AdapterEvent>>,
+    watcher: JoinHandle<()>,
+    rotator: JoinHandle<()>,
+}
+
+/// One kind of radio activity: whether the user wants it, and the handle
+/// it runs under while it does.
+#[derive(Debug)]
+struct Session<H> {
+    wanted: bool,
+    running: Option<H>,
+}
+
+impl<H> Default for Session<H> {
+    fn default() -> Self {
+        Session {
+            wanted: false,
+            running: None,
+        }
+    }
+}
+
+impl<H: Guard> Session<H> {
+    /// Marks the session wanted; `true` if it still has to be started.
+    fn want(&mut self) -> bool {
+        self.wanted = true;
+        self.running.is_none()
+    }
+
+    /// Marks the session unwanted, handing back what has to be stopped.
+    fn unwant(&mut self) -> Option<H> {
+        self.wanted = false;
+        self.running.take()
+    }
+
+    fn started(&mut self, handle: H) {
+        self.running = Some(handle);
+    }
+
+    /// Forgets a session the radio ended on its own, e.g. with its adapter;
+    /// `true` if the user still wants it.
+    fn lost(&mut self) -> bool {
+        if let Some(guard) = self.running.take() {
+            guard.disarm();
+        }
+        self.wanted
+    }
+}
+
+/// Handle of a running session, which stops the session when dropped.
+trait Guard {
+    /// Lets go of a session that already ended, without stopping it.
+    fn disarm(self);
 }
 
-// error types
+/// A scan, stopped on drop on the runtime it was started on.
+struct ScanGuard {
+    /// Runs on the adapter it was started on; `None` once stopped.
+    adapter: Option<platform::Adapter>,
+    runtime: Handle,
+}
+
+impl ScanGuard {
+    fn new(adapter: platform::Adapter) -> Self {
+        ScanGuard {
+            adapter: Some(adapter),
+            runtime: Handle::current(),
+        }
+    }
+
+    async fn stop(mut self) -> Result<(), BleDiscoveryError> {
+        let Some(adapter) = self.adapter.take() else {
+            return Ok(());
+        };
+        adapter
+            .stop_scan()
+            .await
+            .map_err(|err| BleDiscoveryError::classify(err, BleDiscoveryError::DiscoveryError))
+    }
+}
+
+impl Guard for ScanGuard {
+    fn disarm(mut self) {
+        self.adapter = None;
+    }
+}
+
+impl Drop for ScanGuard {
+    fn drop(&mut self) {
+        if let Some(adapter) = self.adapter.take() {
+            stop_on(
+                &self.runtime,
+                "scan",
+                async move { adapter.stop_scan().await },
+            );
+        }
+    }
+}
+
+/// An advertisement, stopped on drop on the runtime it was started on.
+struct AdvertisingGuard {
+    /// `None` once stopped.
+    peripheral: Option<Arc<Peripheral>>,
+    runtime: Handle,
+}
+
+impl AdvertisingGuard {
+    fn new(peripheral: Arc<Peripheral>) -> Self {
+        AdvertisingGuard {
+            peripheral: Some(peripheral),
+            runtime: Handle::current(),
+        }
+    }
+
+    async fn stop(mut self) -> Result<(), BleAdvertiserError> {
+        let Some(peripheral) = self.peripheral.take() else {
+            return Ok(());
+        };
+        Ok(peripheral.stop_advertising().await?)
+    }
+}
+
+impl Guard for AdvertisingGuard {
+    fn disarm(mut self) {
+        self.peripheral = None;
+    }
+}
+
+impl Drop for AdvertisingGuard {
+    fn drop(&mut self) {
+        if let Some(peripheral) = self.peripheral.take() {
+            stop_on(&self.runtime, "advertisement", async move {
+                peripheral.stop_advertising().await
+            });
+        }
+    }
+}
+
+/// Stops a session whose guard was dropped, from a task on `runtime`: drop
+/// cannot wait, and may run on a thread outside any runtime. A failed stop
+/// is logged, and so is a runtime that shut down before the stop ran, which
+/// leaves the session to the OS.
+fn stop_on<E: fmt::Display>(
+    runtime: &Handle,
+    session: &'static str,
+    stop: impl Future<Output = Result<(), E>> + Send + 'static,
+) {
+    let mut unstopped = Unstopped(Some(session));
+    runtime.spawn(async move {
+        if let Err(err) = stop.await {
+            tracing::warn!("failed to stop the dropped ble {session}: {err}");
+        }
+        unstopped.stopped();
+    });
+}
+
+/// Logs, when dropped before the stop it travels with ran, that a session
+/// was left running.
+struct Unstopped(Option<&'static str>);
+
+impl Unstopped {
+    fn stopped(&mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for Unstopped {
+    fn drop(&mut self) {
+        if let Some(session) = self.0 {
+            tracing::warn!("ble {session} left running: its runtime shut down before stopping it");
+        }
+    }
+}
+
+#[derive(Default)]
+struct Sessions {
+    scan: Session<ScanGuard>,
+    advertising: Session<AdvertisingGuard>,
+}
+
+/// The scan and advertising sessions of a `BleDiscovery`, shared with its
+/// background tasks. They start and stop one at a time, under the lock, and
+/// a session still running when the last of them goes away is stopped by
+/// its guard.
+#[derive(Clone)]
+struct Radio {
+    adapters: Adapters,
+    peripheral: Option<Arc<Peripheral>>,
+    /// Key of the advertised tokens.
+    secret: RotationSecret,
+    config: BleDiscoveryConfig,
+    sessions: Arc<tokio::sync::Mutex<Sessions>>,
+}
+
+impl Radio {
+    async fn start_scan(&self) -> Result<(), BleDiscoveryError> {
+        let mut sessions = self.sessions.lock().await;
+        if sessions.scan.want() {
+            let adapter = scan_on(&self.adapters, &self.config.adapter).await?;
+            sessions.scan.started(ScanGuard::new(adapter));
+        }
+        Ok(())
+    }
+
+    async fn stop_scan(&self) -> Result<(), BleDiscoveryError> {
+        let mut sessions = self.sessions.lock().await;
+        match sessions.scan.unwant() {
+            Some(scan) => scan.stop().await,
+            None => Ok(()),
+        }
+    }
+
+    async fn start_advertising(&self) -> Result<(), BleAdvertiserError> {
+        let mut sessions = self.sessions.lock().await;
+        if sessions.advertising.want() {
+            let peripheral = advertise(self.peripheral.clone(), &self.secret, &self.config).await?;
+            sessions
+                .advertising
+                .started(AdvertisingGuard::new(peripheral));
+        }
+        Ok(())
+    }
+
+    async fn stop_advertising(&self) -> Result<(), BleAdvertiserError> {
+        let mut sessions = self.sessions.lock().await;
+        match sessions.advertising.unwant() {
+            Some(advertising) => advertising.stop().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Forgets the scan when its adapter goes away, so that a later
+    /// `start_scan` really starts one.
+    async fn adapter_gone(&self) {
+        self.sessions.lock().await.scan.lost();
+    }
+
+    /// Restarts whatever the user wants once the adapter is back. Failures
+    /// are retried when it next comes back.
+    async fn resume(&self) {
+        let mut sessions = self.sessions.lock().await;
+        if sessions.scan.lost()
+            && let Ok(adapter) = scan_on(&self.adapters, &self.config.adapter).await
+        {
+            sessions.scan.started(ScanGuard::new(adapter));
+        }
+        if sessions.advertising.lost()
+            && let Ok(peripheral) =
+                advertise(self.peripheral.clone(), &self.secret, &self.config).await
+        {
+            sessions
+                .advertising
+                .started(AdvertisingGuard::new(peripheral));
+        }
+    }
+
+    /// Re-advertises under the current token, if advertising.
+    async fn rotate(&self) {
+        let sessions = self.sessions.lock().await;
+        if sessions.advertising.running.is_some() {
+            // a failed rotation is retried at the next boundary
+            let _ = advertise(self.peripheral.clone(), &self.secret, &self.config).await;
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BleDiscoveryConfig {
+    /// A peer not sighted for this long is reported as lost.
+    pub inactivity_timeout: Duration,
+    /// How often the peer table is checked for inactive peers.
+    pub sweep_interval: Duration,
+    pub gatt: GattConfig,
+    /// Adapter to scan and connect with. Advertising always uses the
+    /// platform's default adapter.
+    pub adapter: AdapterSelector,
+    /// How often adapters are checked for being plugged in or out and
+    /// switched on or off.
+    pub adapter_poll_interval: Duration,
+    /// How often the advertised token changes, and how far off a peer's
+    /// clock may be.
+    pub rotation: RotationConfig,
+    /// What the compact advertisement says this node does.
+    pub capabilities: Capabilities,
+}
+
+impl Default for BleDiscoveryConfig {
+    fn default() -> Self {
+        BleDiscoveryConfig {
+            inactivity_timeout: Duration::from_secs(10),
+            sweep_interval: Duration::from_secs(1),
+            gatt: GattConfig::default(),
+            adapter: AdapterSelector::default(),
+            adapter_poll_interval: Duration::from_secs(2),
+            rotation: RotationConfig::default(),
+            capabilities: Capabilities::RECEIVE | Capabilities::SEND,
+        }
+    }
+}
+
+/// bluster is still on uuid 0.8.
+pub(crate) fn bluster_uuid(uuid: Uuid) -> bluster_uuid::Uuid {
+    bluster_uuid::Uuid::from_bytes(*uuid.as_bytes())
+}
+
+#[derive(Debug)]
 pub enum BleDiscoveryError {
+    /// The machine has no Bluetooth adapter.
+    NoAdapter,
+    /// The OS refused access to Bluetooth.
+    PermissionDenied,
+    AdapterPoweredOff,
+    UnsupportedPlatform(String),
     InitializationError(btleplug::Error),
     DiscoveryError(btleplug::Error),
 }
 
-pub enum BleAdvertiserError {
-    InitializationError(btleplug::Error),
-    AdvertiserError(btleplug::Error),
+impl BleDiscoveryError {
+    /// Sorts a btleplug error into the variants callers act on, falling back
+    /// to `other` for the rest.
+    fn classify(err: btleplug::Error, other: fn(btleplug::Error) -> Self) -> Self {
+        match err {
+            btleplug::Error::PermissionDenied => BleDiscoveryError::PermissionDenied,
+            btleplug::Error::NotSupported(what) => BleDiscoveryError::UnsupportedPlatform(what),
+            err => other(err),
+        }
+    }
 }
 
+impl fmt::Display for BleDiscoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BleDiscoveryError::NoAdapter => write!(f, "no bluetooth adapter found"),
+            BleDiscoveryError::PermissionDenied => write!(f, "bluetooth permission denied"),
+            BleDiscoveryError::AdapterPoweredOff => write!(f, "bluetooth adapter is powered off"),
+            BleDiscoveryError::UnsupportedPlatform(what) => {
+                write!(f, "bluetooth is not supported here: {what}")
+            }
+            BleDiscoveryError::InitializationError(err) => {
+                write!(f, "bluetooth initialization failed: {err}")
+            }
+            BleDiscoveryError::DiscoveryError(err) => write!(f, "ble scan failed: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for BleDiscoveryError {}
+
 impl From<btleplug::Error> for BleDiscoveryError {
     fn from(err: btleplug::Error) -> Self {
-        BleDiscoveryError::InitializationError(err)
+        BleDiscoveryError::classify(err, BleDiscoveryError::InitializationError)
+    }
+}
+
+#[derive(Debug)]
+pub enum BleAdvertiserError {
+    /// No adapter could act as a peripheral.
+    NoAdapter,
+    PermissionDenied,
+    AdapterPoweredOff,
+    UnsupportedPlatform(String),
+    AdvertiserError(bluster::Error),
+}
+
+impl fmt::Display for BleAdvertiserError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BleAdvertiserError::NoAdapter => write!(f, "no bluetooth adapter can advertise"),
+            BleAdvertiserError::PermissionDenied => write!(f, "bluetooth permission denied"),
+            BleAdvertiserError::AdapterPoweredOff => write!(f, "bluetooth adapter is powered off"),
+            BleAdvertiserError::UnsupportedPlatform(what) => {
+                write!(f, "ble advertising is not supported here: {what}")
+            }
+            BleAdvertiserError::AdvertiserError(err) => write!(f, "ble advertising failed: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for BleAdvertiserError {}
+
+impl From<bluster::Error> for BleAdvertiserError {
+    fn from(err: bluster::Error) -> Self {
+        match &err.error_type {
+            bluster::ErrorType::PermissionDenied => BleAdvertiserError::PermissionDenied,
+            // BlueZ answers NotReady while the adapter is off
+            bluster::ErrorType::Bluez if err.name.ends_with("NotReady") => {
+                BleAdvertiserError::AdapterPoweredOff
+            }
+            // CoreBluetooth reports its manager state by name
+            bluster::ErrorType::CoreBluetooth(state) => match state.to_lowercase().as_str() {
+                "unsupported" => BleAdvertiserError::UnsupportedPlatform(state.clone()),
+                "unauthorized" => BleAdvertiserError::PermissionDenied,
+                "poweredoff" => BleAdvertiserError::AdapterPoweredOff,
+                _ => BleAdvertiserError::AdvertiserError(err),
+            },
+            _ => BleAdvertiserError::AdvertiserError(err),
+        }
     }
 }
 
 impl BleDiscovery {
     pub async fn new() -> Result<Self, BleDiscoveryError> {
-        let manager = platform::Manager::new().await?;
+        Self::with_config(BleDiscoveryConfig::default()).await
+    }
+
+    /// Advertises tokens keyed with a throwaway secret, which nobody can
+    /// link to a peer.
+    pub async fn with_config(config: BleDiscoveryConfig) -> Result<Self, BleDiscoveryError> {
+        Self::with_identity(&DeviceIdentity::generate(), config).await
+    }
+
+    /// Advertises tokens keyed with the rotation secret of `identity`, which
+    /// its contacts resolve to its peer id.
+    pub async fn with_identity(
+        identity: &DeviceIdentity,
+        config: BleDiscoveryConfig,
+    ) -> Result<Self, BleDiscoveryError> {
+        let adapters = Adapters::new(platform::Manager::new().await?);
+        let peripheral = Peripheral::new().await.ok().map(Arc::new);
+        let radio = Radio {
+            adapters: adapters.clone(),
+            peripheral: peripheral.clone(),
+            secret: identity.rotation_secret().clone(),
+            config: config.clone(),
+            sessions: Arc::default(),
+        };
+        let (adapter_events_tx, adapter_events) = mpsc::unbounded();
+        let adapter_events_tx = Arc::new(Mutex::new(adapter_events_tx));
+        let watcher = tokio::spawn(watch_adapters(
+            radio.clone(),
+            Arc::clone(&adapter_events_tx),
+        ));
+        let rotator = tokio::spawn(rotate_tokens(radio.clone()));
         Ok(BleDiscovery {
-            manager,
-            peripheral: Peripheral::new().await.ok(),
+            adapters,
+            peripheral,
+            config,
+            radio,
+            adapter_events_tx,
+            adapter_events: Some(adapter_events),
+            watcher,
+            rotator,
+        })
+    }
+
+    /// Adapters being plugged in or out and switched on or off. Each call
+    /// takes over from the previous stream.
+    pub fn adapter_events(&mut self) -> BoxStreamResponse<AdapterEvent> {
+        let events = self.adapter_events.take().unwrap_or_else(|| {
+            let (events_tx, events) = mpsc::unbounded();
+            *self.adapter_events_tx.lock().unwrap() = events_tx;
+            events
+        });
+        Box::pin(events)
+    }
+}
+
+/// Ends the background tasks, and with them their share of the sessions;
+/// the session guards stop whatever is still running.
+impl Drop for BleDiscovery {
+    fn drop(&mut self) {
+        self.watcher.abort();
+        self.rotator.abort();
+    }
+}
+
+async fn scan_on(
+    adapters: &Adapters,
+    selector: &AdapterSelector,
+) -> Result<platform::Adapter, BleDiscoveryError> {
+    let (central, description) = adapters
+        .select(selector)
+        .await?
+        .ok_or(BleDiscoveryError::NoAdapter)?;
+    if !description.powered {
+        return Err(BleDiscoveryError::AdapterPoweredOff);
+    }
+    // the filter only lets through devices listing the pdrop service, which
+    // every pdrop advertiser does
+    let filter = ScanFilter {
+        services: vec![PDROP_SERVICE_UUID],
+    };
+    central
+        .start_scan(filter)
+        .await
+        .map_err(|err| BleDiscoveryError::classify(err, BleDiscoveryError::DiscoveryError))?;
+    Ok(central)
+}
+
+/// The compact advertisement under the current token of `secret`, in the
+/// text form advertisers put into the local name.
+fn advertised_name(
+    secret: &RotationSecret,
+    config: &BleDiscoveryConfig,
+    now: SystemTime,
+) -> String {
+    let compact = CompactAdvertisement {
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: config.capabilities,
+        token: config.rotation.token(secret, now),
+    };
+    compact.to_string()
+}
+
+/// Advertises the pdrop service with the compact advertisement as local
+/// name, replacing any earlier advertisement. Hands back the peripheral
+/// advertising.
+async fn advertise(
+    peripheral: Option<Arc<Peripheral>>,
+    secret: &RotationSecret,
+    config: &BleDiscoveryConfig,
+) -> Result<Arc<Peripheral>, BleAdvertiserError> {
+    let peripheral = peripheral.ok_or(BleAdvertiserError::NoAdapter)?;
+    if !peripheral.is_powered().await? {
+        return Err(BleAdvertiserError::AdapterPoweredOff);
+    }
+    if peripheral.is_advertising().await? {
+        peripheral.stop_advertising().await?;
+    }
+    let service = bluster_uuid(PDROP_SERVICE_UUID);
+    // bluster can set neither manufacturer nor service data, so the
+    // compact advertisement travels hex-encoded as the local name, which
+    // BlueZ and CoreBluetooth put into the scan response
+    let name = advertised_name(secret, config, SystemTime::now());
+    // the returned stream only reports advertising events
+    let _events = peripheral.start_advertising(&name, &[service]).await?;
+    Ok(peripheral)
+}
+
+/// Re-advertises at every epoch boundary, so the token on air follows the
+/// clock.
+async fn rotate_tokens(radio: Radio) {
+    loop {
+        let now = SystemTime::now();
+        let left = radio
+            .config
+            .rotation
+            .next_rotation(now)
+            .duration_since(now)
+            .unwrap_or_default();
+        tokio::time::sleep(left).await;
+        radio.rotate().await;
+    }
+}
+
+/// Polls the adapter list, reporting changes and resuming whatever was
+/// running when the selected adapter comes back.
+async fn watch_adapters(radio: Radio, events: Arc<Mutex<UnboundedSender<AdapterEvent>>>) {
+    let selector = &radio.config.adapter;
+    let mut watch = AdapterWatch::default();
+    let mut interval = tokio::time::interval(radio.config.adapter_poll_interval);
+    loop {
+        interval.tick().await;
+        let Ok(described) = radio.adapters.describe().await else {
+            continue;
+        };
+        let changes = watch.update(described.into_iter().map(|(_, d)| d).collect());
+        let (mut gone, mut back) = (false, false);
+        for event in changes {
+            gone |= matches!(
+                &event,
+                AdapterEvent::Removed(adapter) | AdapterEvent::PoweredOff(adapter)
+                    if selector.matches(adapter)
+            );
+            back |= event.brings_back(selector);
+            // nobody may be listening
+            let _ = events.lock().unwrap().unbounded_send(event);
+        }
+        if gone {
+            radio.adapter_gone().await;
+        }
+        if back {
+            radio.resume().await;
+        }
+    }
+}
+
+/// A single advertisement report, decoupled from btleplug's `CentralEvent`
+/// so the translation into `DiscoveryEvent`s can be driven by a scripted
+/// source in tests.
+#[derive(Debug, Clone)]
+pub struct Sighting {
+    pub id: String,
+    pub properties: PeripheralProperties,
+}
+
+pub trait SightingSource {
+    fn sightings(&self) -> BoxFutureResponse<BoxStreamResponse<Sighting>, BleDiscoveryError>;
+}
+
+impl SightingSource for platform::Adapter {
+    fn sightings(&self) -> BoxFutureResponse<BoxStreamResponse<Sighting>, BleDiscoveryError> {
+        let adapter = self.clone();
+        Box::pin(async move {
+            let events = adapter.events().await.map_err(|err| {
+                BleDiscoveryError::classify(err, BleDiscoveryError::DiscoveryError)
+            })?;
+            let sightings = events.filter_map(move |event| {
+                let adapter = adapter.clone();
+                async move {
+                    let id = match event {
+                        CentralEvent::DeviceDiscovered(id)
+                        | CentralEvent::DeviceUpdated(id)
+                        | CentralEvent::ManufacturerDataAdvertisement { id, .. }
+                        | CentralEvent::ServiceDataAdvertisement { id, .. }
+                        | CentralEvent::ServicesAdvertisement { id, .. } => id,
+                        _ => return None,
+                    };
+                    let properties = adapter
+                        .peripheral(&id)
+                        .await
+                        .ok()?
+                        .properties()
+                        .await
+                        .ok()??;
+                    Some(Sighting {
+                        id: id.to_string(),
+                        properties,
+                    })
+                }
+            });
+            Ok(Box::pin(sightings) as BoxStreamResponse<Sighting>)
         })
     }
+}
 
-    pub fn publish_service(&self) -> BoxFutureResponse<(), BleDiscoveryError> {
-        Box::pin(async move { Ok(()) })
+/// The compact advertisement a pdrop peer puts into its local name. Any
+/// other name is a display name.
+fn compact_advertisement(properties: &PeripheralProperties) -> Option<CompactAdvertisement> {
+    properties.local_name.as_deref()?.parse().ok()
+}
+
+/// A device is a pdrop peer if it lists the pdrop service or carries its
+/// service data.
+fn is_pdrop_peer(properties: &PeripheralProperties) -> bool {
+    properties.services.contains(&PDROP_SERVICE_UUID)
+        || properties.service_data.contains_key(&PDROP_SERVICE_UUID)
+}
+
+/// Builds the peer a pdrop sighting describes. The peer's own advertisement,
+/// when it fits into the service data, wins over what the radio reports.
+/// Without one the peer goes by a provisional id derived from its rotating
+/// token, which survives address rotation for the length of an epoch, or
+/// failing that from the platform's peripheral id. Signal, address and time
+/// always come from the reception.
+fn sighted_peer(sighting: &Sighting) -> Option<PeerInfo> {
+    if !is_pdrop_peer(&sighting.properties) {
+        return None;
+    }
+    let properties = &sighting.properties;
+    let compact = compact_advertisement(properties);
+    let advertised = properties
+        .service_data
+        .get(&PDROP_SERVICE_UUID)
+        .and_then(|data| Advertisement::decode(data).ok());
+    let mut info = match (advertised, compact) {
+        (Some(advertisement), _) => advertisement.info,
+        (None, Some(compact)) => {
+            // the name is taken up by the compact advertisement
+            let mut info = PeerInfo::new(PeerId::provisional(compact.token.as_bytes()));
+            info.provisional = true;
+            info.capabilities = compact.capabilities;
+            info.protocol_versions = vec![compact.protocol_version];
+            info.token = Some(compact.token);
+            info
+        }
+        (None, None) => {
+            let mut info = PeerInfo::new(PeerId::provisional(sighting.id.as_bytes()));
+            info.provisional = true;
+            info.display_name = properties.local_name.clone();
+            info
+        }
+    };
+    info.rssi = properties.rssi;
+    info.tx_power = properties.tx_power_level;
+    info.last_seen = Some(SystemTime::now());
+    let address = TransportAddress::Ble(properties.address.into_inner());
+    if !info.addresses.contains(&address) {
+        info.addresses.insert(0, address);
     }
+    Some(info)
+}
+
+enum TrackerInput {
+    Sighting(Sighting),
+    Sweep,
+    End,
+}
+
+/// Turns the sightings of `source` into discovery events. The stream ends
+/// when the source does.
+pub fn peer_events<S: SightingSource>(
+    source: &S,
+    config: BleDiscoveryConfig,
+) -> BoxStreamResponse<DiscoveryEvent> {
+    let sightings = stream::once(source.sightings())
+        .flat_map(|sightings| match sightings {
+            Ok(sightings) => sightings,
+            Err(_) => Box::pin(stream::empty()),
+        })
+        .map(TrackerInput::Sighting)
+        .chain(stream::once(future::ready(TrackerInput::End)));
+    let sweeps = stream::unfold(
+        tokio::time::interval(config.sweep_interval),
+        |mut interval| async move {
+            interval.tick().await;
+            Some((TrackerInput::Sweep, interval))
+        },
+    );
+
+    let events = stream::select(sightings, sweeps)
+        .take_while(|input| future::ready(!matches!(input, TrackerInput::End)))
+        .scan(
+            PeerTracker::new(config.inactivity_timeout),
+            |tracker, input| {
+                let now = Instant::now();
+                let events = match input {
+                    // service data has no room for a payment request
+                    TrackerInput::Sighting(sighting) => sighted_peer(&sighting)
+                        .map(|info| tracker.observe(sighting.id, info, None, now))
+                        .unwrap_or_default(),
+                    TrackerInput::Sweep => tracker.sweep(now),
+                    TrackerInput::End => Vec::new(),
+                };
+                future::ready(Some(events))
+            },
+        )
+        .flat_map(stream::iter);
+    Box::pin(events)
 }
 
 impl core::discovery::Discovery for BleDiscovery {
     type Error = BleDiscoveryError;
     type DiscoveryEvent = core::discovery::DiscoveryEvent;
 
+    /// Starts scanning on the selected adapter, unless already scanning.
+    /// Scanning resumes by itself whenever that adapter comes back, even if
+    /// starting it failed here.
     fn start_scan(&self) -> BoxFutureResponse<(), Self::Error> {
-        let manager = self.manager.clone();
-        Box::pin(async move {
-            let central = manager
-                .adapters()
-                .await
-                .unwrap()
-                .into_iter()
-                .nth(0)
-                .unwrap();
-            central.start_scan(ScanFilter::default()).await.unwrap();
-            Ok(())
-        })
+        let radio = self.radio.clone();
+        Box::pin(async move { radio.start_scan().await })
     }
+
+    /// Stops the running scan, if any.
     fn stop_scan(&self) -> BoxFutureResponse<(), Self::Error> {
-        Box::pin(async move { Ok(()) })
+        let radio = self.radio.clone();
+        Box::pin(async move { radio.stop_scan().await })
     }
 
+    /// Sightings on the selected adapter, waiting for it to appear if it is
+    /// not there yet.
     fn poll_events(&mut self) -> core::types::BoxStreamResponse<Self::DiscoveryEvent> {
-        Box::pin(futures::stream::empty())
+        let (adapters, config) = (self.adapters.clone(), self.config.clone());
+        let selector = config.adapter.clone();
+        let interval = config.adapter_poll_interval;
+        let adapter = async move {
+            loop {
+                if let Ok(Some((adapter, _))) = adapters.select(&selector).await {
+                    return adapter;
+                }
+                tokio::time::sleep(interval).await;
+            }
+        };
+        Box::pin(
+            stream::once(adapter).flat_map(move |adapter| peer_events(&adapter, config.clone())),
+        )
     }
 }
 
 impl core::discovery::Advertiser for BleDiscovery {
     type Error = BleAdvertiserError;
 
+    /// Starts advertising the current rotating token, unless already
+    /// advertising. Like scanning, advertising resumes by itself when the
+    /// adapter comes back.
     fn broadcast(&self) -> BoxFutureResponse<(), Self::Error> {
-        if let Some(peripheral) = &self.peripheral {
-            let _ = peripheral.start_advertising("pdrop-01", &[Uuid::from_u128(0x180D)]);
-        } else {
-            // some log
-        }
-        Box::pin(async move { Ok(()) })
+        let radio = self.radio.clone();
+        Box::pin(async move { radio.start_advertising().await })
     }
 
+    /// Stops the running advertisement, if any.
     fn stop_broadcast(&self) -> BoxFutureResponse<(), Self::Error> {
-        Box::pin(async move { Ok(()) })
+        let radio = self.radio.clone();
+        Box::pin(async move { radio.stop_advertising().await })
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing::run;
+    use core::identity::{Capabilities, PROTOCOL_VERSION};
+
+    /// Replays sightings at fixed offsets from the start of the scan, then
+    /// stays silent.
+    struct ScriptedSource(Vec<(Duration, Sighting)>);
+
+    impl SightingSource for ScriptedSource {
+        fn sightings(&self) -> BoxFutureResponse<BoxStreamResponse<Sighting>, BleDiscoveryError> {
+            let script = self.0.clone();
+            Box::pin(async move {
+                let start = Instant::now();
+                let replay = stream::iter(script).then(move |(at, sighting)| async move {
+                    tokio::time::sleep_until(start + at).await;
+                    sighting
+                });
+                Ok(Box::pin(replay.chain(stream::pending())) as BoxStreamResponse<Sighting>)
+            })
+        }
+    }
+
+    fn sighting(id: &str, services: Vec<Uuid>) -> Sighting {
+        Sighting {
+            id: id.to_string(),
+            properties: PeripheralProperties {
+                local_name: Some(format!("{id}-name")),
+                rssi: Some(-60),
+                tx_power_level: Some(4),
+                services,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn config() -> BleDiscoveryConfig {
+        BleDiscoveryConfig {
+            inactivity_timeout: Duration::from_secs(10),
+            sweep_interval: Duration::from_secs(1),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn reports_pdrop_peers_once_and_ignores_others() {
+        run(async {
+            let source = ScriptedSource(vec![
+                (Duration::ZERO, sighting("speaker", vec![])),
+                (
+                    Duration::from_secs(1),
+                    sighting("alice", vec![PDROP_SERVICE_UUID]),
+                ),
+                (
+                    Duration::from_secs(2),
+                    sighting("alice", vec![PDROP_SERVICE_UUID]),
+                ),
+                (
+                    Duration::from_secs(3),
+                    sighting("bob", vec![PDROP_SERVICE_UUID]),
+                ),
+            ]);
+            let events: Vec<_> = peer_events(&source, config()).take(2).collect().await;
+
+            let ids: Vec<_> = events
+                .iter()
+                .map(|event| match event {
+                    DiscoveryEvent::PeerDiscovered(info) => info.id,
+                    _ => panic!("unexpected event: {event:?}"),
+                })
+                .collect();
+            assert_eq!(
+                ids,
+                [PeerId::provisional(b"alice"), PeerId::provisional(b"bob")]
+            );
+            let DiscoveryEvent::PeerDiscovered(alice) = &events[0] else {
+                unreachable!()
+            };
+            assert_eq!(alice.display_name.as_deref(), Some("alice-name"));
+            assert_eq!(alice.rssi, Some(-60));
+            assert_eq!(alice.tx_power, Some(4));
+            assert!(alice.last_seen.is_some());
+        });
+    }
+
+    #[test]
+    fn prefers_the_advertisement_in_service_data() {
+        run(async {
+            let mut advertised = PeerInfo::new(PeerId::from_public_key(b"alice-key"));
+            advertised.display_name = Some("Alice".to_string());
+            let advertisement = Advertisement::new(advertised.clone());
+            let mut alice = sighting("AA:BB:CC:DD:EE:FF", vec![]);
+            alice.properties.address = [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff].into();
+            alice
+                .properties
+                .service_data
+                .insert(PDROP_SERVICE_UUID, advertisement.encode().unwrap());
+            let source = ScriptedSource(vec![(Duration::ZERO, alice)]);
+
+            let Some(DiscoveryEvent::PeerDiscovered(peer)) =
+                peer_events(&source, config()).next().await
+            else {
+                panic!("alice was not discovered");
+            };
+            assert_eq!(peer.id, advertised.id);
+            assert_eq!(peer.display_name, advertised.display_name);
+            assert_eq!(peer.rssi, Some(-60));
+            assert_eq!(
+                peer.addresses,
+                [TransportAddress::Ble([0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff])]
+            );
+        });
+    }
+
+    fn secret(seed: u8) -> RotationSecret {
+        RotationSecret::from_bytes([seed; 32])
+    }
+
+    #[test]
+    fn identifies_peers_by_the_advertised_name() {
+        run(async {
+            let mut advertiser = config();
+            advertiser.capabilities = Capabilities::RECEIVE;
+            let now = SystemTime::now();
+            let alice_token = advertiser.rotation.token(&secret(1), now);
+            let mut alice = sighting("rotated-address", vec![PDROP_SERVICE_UUID]);
+            alice.properties.local_name = Some(advertised_name(&secret(1), &advertiser, now));
+            let source = ScriptedSource(vec![(Duration::ZERO, alice)]);
+
+            let Some(DiscoveryEvent::PeerDiscovered(peer)) =
+                peer_events(&source, config()).next().await
+            else {
+                panic!("alice was not discovered");
+            };
+            assert_eq!(peer.id, PeerId::provisional(alice_token.as_bytes()));
+            assert!(peer.provisional);
+            assert_eq!(peer.token, Some(alice_token));
+            assert_eq!(peer.capabilities, Capabilities::RECEIVE);
+            assert_eq!(peer.protocol_versions, [PROTOCOL_VERSION]);
+            assert_eq!(peer.display_name, None);
+        });
+    }
+
+    #[test]
+    fn needs_the_pdrop_service_besides_the_name() {
+        let mut impostor = sighting("impostor", vec![]);
+        impostor.properties.local_name =
+            Some(advertised_name(&secret(1), &config(), SystemTime::now()));
+        assert_eq!(sighted_peer(&impostor), None);
+    }
+
+    #[test]
+    fn the_provisional_id_follows_the_token() {
+        let now = SystemTime::now();
+        let mut alice = sighting("alice", vec![PDROP_SERVICE_UUID]);
+        alice.properties.local_name = Some(advertised_name(&secret(1), &config(), now));
+        let first = sighted_peer(&alice).unwrap();
+
+        // tokens change with the epoch, and so does the provisional id
+        let later = now + config().rotation.interval;
+        let rotated = config().rotation.token(&secret(1), later);
+        alice.properties.local_name = Some(advertised_name(&secret(1), &config(), later));
+        let peer = sighted_peer(&alice).unwrap();
+        assert_ne!(peer.id, first.id);
+        assert_eq!(peer.id, PeerId::provisional(rotated.as_bytes()));
+
+        alice.properties.local_name = Some("Alice's phone".to_string());
+        let peer = sighted_peer(&alice).unwrap();
+        assert_eq!(peer.token, None);
+        assert_eq!(peer.display_name.as_deref(), Some("Alice's phone"));
+    }
+
+    impl Guard for u8 {
+        fn disarm(self) {}
+    }
+
+    #[test]
+    fn dropped_sessions_are_stopped_while_their_runtime_lives() {
+        let stopped = Arc::new(Mutex::new(Vec::new()));
+        let stop = |session: &'static str| {
+            let stopped = Arc::clone(&stopped);
+            async move {
+                stopped.lock().unwrap().push(session);
+                Ok::<_, BleDiscoveryError>(())
+            }
+        };
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let handle = runtime.handle().clone();
+        // dropped outside the runtime, stopped on it
+        stop_on(&handle, "scan", stop("scan"));
+        runtime.block_on(tokio::task::yield_now());
+        assert_eq!(*stopped.lock().unwrap(), ["scan"]);
+
+        drop(runtime);
+        stop_on(&handle, "advertisement", stop("advertisement"));
+        assert_eq!(
+            *stopped.lock().unwrap(),
+            ["scan"],
+            "no runtime left to stop it"
+        );
+    }
+
+    #[test]
+    fn sessions_start_and_stop_once() {
+        let mut session = Session::<u8>::default();
+        assert!(session.unwant().is_none());
+        assert!(session.want());
+        session.started(1);
+        assert!(!session.want(), "a running session is not started again");
+        assert_eq!(session.unwant(), Some(1));
+        assert_eq!(
+            session.unwant(),
+            None,
+            "a stopped session is not stopped again"
+        );
+
+        // an unwanted session that ended is not resumed, a wanted one is
+        assert!(!session.lost());
+        assert!(session.want());
+        session.started(2);
+        assert!(session.lost());
+        assert!(session.want(), "a lost session is started again");
+    }
+
+    #[test]
+    fn reports_loss_after_inactivity_timeout() {
+        run(async {
+            let source = ScriptedSource(vec![
+                (Duration::ZERO, sighting("alice", vec![PDROP_SERVICE_UUID])),
+                (
+                    Duration::from_secs(5),
+                    sighting("alice", vec![PDROP_SERVICE_UUID]),
+                ),
+            ]);
+            let start = Instant::now();
+            let mut events = peer_events(&source, config());
+
+            assert!(matches!(
+                events.next().await,
+                Some(DiscoveryEvent::PeerDiscovered(_))
+            ));
+            let lost = events.next().await;
+            assert!(
+                matches!(lost, Some(DiscoveryEvent::PeerLost(ref info)) if info.id == PeerId::provisional(b"alice"))
+            );
+            let elapsed = start.elapsed();
+            assert!(
+                elapsed >= Duration::from_secs(15),
+                "lost too early: {elapsed:?}"
+            );
+            assert!(
+                elapsed <= Duration::from_secs(16),
+                "lost too late: {elapsed:?}"
+            );
+        });
+    }
+
+    #[test]
+    fn rediscovers_a_peer_after_loss() {
+        run(async {
+            let source = ScriptedSource(vec![
+                (Duration::ZERO, sighting("alice", vec![PDROP_SERVICE_UUID])),
+                (
+                    Duration::from_secs(30),
+                    sighting("alice", vec![PDROP_SERVICE_UUID]),
+                ),
+            ]);
+            let events: Vec<_> = peer_events(&source, config()).take(3).collect().await;
+
+            assert!(matches!(events[0], DiscoveryEvent::PeerDiscovered(_)));
+            assert!(matches!(events[1], DiscoveryEvent::PeerLost(_)));
+            assert!(matches!(events[2], DiscoveryEvent::PeerDiscovered(_)));
+        });
+    }
+
+    #[test]
+    fn btleplug_errors_are_sorted_into_actionable_variants() {
+        let scan = |err| BleDiscoveryError::classify(err, BleDiscoveryError::DiscoveryError);
+        assert!(matches!(
+            scan(btleplug::Error::PermissionDenied),
+            BleDiscoveryError::PermissionDenied
+        ));
+        assert!(matches!(
+            scan(btleplug::Error::NotSupported("le scan".to_string())),
+            BleDiscoveryError::UnsupportedPlatform(what) if what == "le scan"
+        ));
+        assert!(matches!(
+            scan(btleplug::Error::DeviceNotFound),
+            BleDiscoveryError::DiscoveryError(btleplug::Error::DeviceNotFound)
+        ));
+        assert!(matches!(
+            BleDiscoveryError::from(btleplug::Error::RuntimeError("dbus".to_string())),
+            BleDiscoveryError::InitializationError(_)
+        ));
+        assert_eq!(
+            BleDiscoveryError::NoAdapter.to_string(),
+            "no bluetooth adapter found"
+        );
+    }
 }
@@ -0,0 +1,722 @@
+//! Frame transport over the pdrop GATT service.
+//!
+//! The advertising side hosts the service: centrals write chunks to the RX
+//! characteristic and receive chunks as notifications of the TX
+//! characteristic. A central subscribing to TX opens a connection. Frames
+//! are cut into chunks that fit one ATT write or notification:
+//!
+//! `header | [frame length, first chunk only] | data`
+//!
+//! The header byte flags the first and last chunk of a frame and carries a
+//! 5-bit sequence number; the frame length is a big-endian u32. Notifications
+//! are not acknowledged, so both directions use credits: a side sends only
+//! as many chunks as its peer has granted, and the peer grants more, with a
+//! chunk flagged `CREDIT` carrying the count, as it consumes them.
+
+use core::{
+    identity::{PeerInfo, TransportAddress},
+    transport::{CloseHandle, CloseReason, Connection, Frame, Transport, TransportError},
+    types::{BoxFutureResponse, BoxStreamResponse},
+};
+use std::{collections::HashSet, collections::VecDeque, fmt};
+
+use bluster::gatt::{
+    characteristic::{Characteristic, Properties, Secure, Write},
+    event::{Event, Response},
+    service::Service,
+};
+use btleplug::api::{BDAddr, Central, Peripheral as _, WriteType};
+use futures::{
+    Sink, SinkExt, Stream, StreamExt,
+    channel::mpsc::{self, Receiver, Sender},
+    sink,
+};
+use uuid::Uuid;
+
+use crate::{
+    adapter::{AdapterSelector, Adapters},
+    ble::{BleDiscovery, PDROP_SERVICE_UUID, bluster_uuid},
+};
+
+/// Written by centrals, received by the advertising side.
+pub const RX_CHARACTERISTIC_UUID: Uuid = Uuid::from_u128(0x7064726f_7000_4b6e_a3c5_8f0d2c9e1a01);
+/// Notified by the advertising side, received by centrals.
+pub const TX_CHARACTERISTIC_UUID: Uuid = Uuid::from_u128(0x7064726f_7000_4b6e_a3c5_8f0d2c9e1a02);
+
+const FIRST: u8 = 0x80;
+const LAST: u8 = 0x40;
+const CREDIT: u8 = 0x20;
+const SEQ_MASK: u8 = 0x1f;
+const LENGTH_LEN: usize = 4;
+/// ATT write and notification headers take 3 bytes of the MTU.
+const ATT_OVERHEAD: usize = 3;
+
+/// Frames buffered per direction between a connection and its link.
+const FRAME_CAPACITY: usize = 4;
+
+#[derive(Debug, Clone)]
+pub struct GattConfig {
+    /// ATT MTU assumed for the link. Neither bluster nor btleplug report the
+    /// negotiated value, so it is configured; 185 is what current phones
+    /// settle on.
+    pub att_mtu: usize,
+    /// Largest frame a connection carries.
+    pub max_frame_len: usize,
+    /// Chunks either side may send before it needs a grant from its peer.
+    pub initial_credits: u8,
+}
+
+impl Default for GattConfig {
+    fn default() -> Self {
+        GattConfig {
+            att_mtu: 185,
+            max_frame_len: 8 * 1024,
+            initial_credits: 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChunkError {
+    FrameTooLarge {
+        len: usize,
+        max: usize,
+    },
+    OutOfSequence {
+        expected: u8,
+        got: u8,
+    },
+    /// A continuation arrived with no frame in progress, or a first chunk
+    /// arrived in the middle of one.
+    UnexpectedChunk,
+    LengthMismatch,
+    Malformed,
+}
+
+impl fmt::Display for ChunkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkError::FrameTooLarge { len, max } => {
+                write!(f, "frame of {len} bytes exceeds {max}")
+            }
+            ChunkError::OutOfSequence { expected, got } => {
+                write!(f, "expected chunk {expected}, got {got}")
+            }
+            ChunkError::UnexpectedChunk => write!(f, "chunk does not fit the frame in progress"),
+            ChunkError::LengthMismatch => write!(f, "frame length does not match its chunks"),
+            ChunkError::Malformed => write!(f, "malformed chunk"),
+        }
+    }
+}
+
+impl std::error::Error for ChunkError {}
+
+struct Partial {
+    len: usize,
+    data: Vec<u8>,
+}
+
+/// Chunking, reassembly and credit accounting for one connection, without
+/// any I/O: frames go in through `send_frame` and chunks come out of
+/// `poll_transmit`; chunks go in through `receive` and frames come out.
+pub struct ChunkLink {
+    chunk_len: usize,
+    max_frame_len: usize,
+    grant_batch: u8,
+    outgoing: VecDeque<Vec<u8>>,
+    credits: u32,
+    /// Most credits the peer can rightly have granted at once.
+    window: u32,
+    next_seq: u8,
+    owed_grant: u8,
+    consumed: u8,
+    expected_seq: u8,
+    partial: Option<Partial>,
+}
+
+impl ChunkLink {
+    /// Fails if `config.att_mtu` leaves no room for data in a first chunk.
+    pub fn new(config: &GattConfig) -> Result<Self, GattError> {
+        let chunk_len = config.att_mtu.saturating_sub(ATT_OVERHEAD);
+        if chunk_len <= 1 + LENGTH_LEN {
+            return Err(GattError::MtuTooSmall(config.att_mtu));
+        }
+        let initial_credits = config.initial_credits.max(2);
+        Ok(ChunkLink {
+            chunk_len,
+            max_frame_len: config.max_frame_len,
+            grant_batch: initial_credits / 2,
+            outgoing: VecDeque::new(),
+            credits: initial_credits.into(),
+            window: initial_credits.into(),
+            next_seq: 0,
+            owed_grant: 0,
+            consumed: 0,
+            expected_seq: 0,
+            partial: None,
+        })
+    }
+
+    /// Data chunks waiting for credit.
+    pub fn queued_chunks(&self) -> usize {
+        self.outgoing.len()
+    }
+
+    pub fn send_frame(&mut self, frame: &[u8]) -> Result<(), ChunkError> {
+        if frame.len() > self.max_frame_len {
+            return Err(ChunkError::FrameTooLarge {
+                len: frame.len(),
+                max: self.max_frame_len,
+            });
+        }
+        let mut rest = frame;
+        let mut first = true;
+        loop {
+            let room = self.chunk_len - 1 - if first { LENGTH_LEN } else { 0 };
+            let (data, tail) = rest.split_at(room.min(rest.len()));
+            rest = tail;
+            let mut header = self.next_seq;
+            self.next_seq = (self.next_seq + 1) & SEQ_MASK;
+            if first {
+                header |= FIRST;
+            }
+            if rest.is_empty() {
+                header |= LAST;
+            }
+            let mut chunk = Vec::with_capacity(self.chunk_len);
+            chunk.push(header);
+            if first {
+                chunk.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+            }
+            chunk.extend_from_slice(data);
+            self.outgoing.push_back(chunk);
+            first = false;
+            if rest.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Next chunk to put on the air, if any may be sent now. Credit grants
+    /// go first and need no credit themselves.
+    pub fn poll_transmit(&mut self) -> Option<Vec<u8>> {
+        if self.owed_grant > 0 {
+            return Some(vec![CREDIT, std::mem::take(&mut self.owed_grant)]);
+        }
+        if self.credits == 0 {
+            return None;
+        }
+        let chunk = self.outgoing.pop_front()?;
+        self.credits -= 1;
+        Some(chunk)
+    }
+
+    /// Takes in one chunk from the peer, returning the frame it completes.
+    pub fn receive(&mut self, chunk: &[u8]) -> Result<Option<Frame>, ChunkError> {
+        let (&header, body) = chunk.split_first().ok_or(ChunkError::Malformed)?;
+        if header & CREDIT != 0 {
+            let &[granted] = body else {
+                return Err(ChunkError::Malformed);
+            };
+            // a peer granting more than it was sent is ignored beyond the
+            // window rather than trusted
+            self.credits = self
+                .credits
+                .saturating_add(u32::from(granted))
+                .min(self.window);
+            return Ok(None);
+        }
+
+        let seq = header & SEQ_MASK;
+        if seq != self.expected_seq {
+            return Err(ChunkError::OutOfSequence {
+                expected: self.expected_seq,
+                got: seq,
+            });
+        }
+        self.expected_seq = (seq + 1) & SEQ_MASK;
+        self.consumed += 1;
+        if self.consumed == self.grant_batch {
+            self.owed_grant += std::mem::take(&mut self.consumed);
+        }
+
+        let data = if header & FIRST != 0 {
+            if self.partial.is_some() {
+                return Err(ChunkError::UnexpectedChunk);
+            }
+            let (len, data) = body
+                .split_at_checked(LENGTH_LEN)
+                .ok_or(ChunkError::Malformed)?;
+            let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+            if len > self.max_frame_len {
+                return Err(ChunkError::FrameTooLarge {
+                    len,
+                    max: self.max_frame_len,
+                });
+            }
+            self.partial = Some(Partial {
+                len,
+                data: Vec::with_capacity(len),
+            });
+            data
+        } else {
+            body
+        };
+        let partial = self.partial.as_mut().ok_or(ChunkError::UnexpectedChunk)?;
+        if partial.data.len() + data.len() > partial.len {
+            return Err(ChunkError::LengthMismatch);
+        }
+        partial.data.extend_from_slice(data);
+        if header & LAST == 0 {
+            return Ok(None);
+        }
+        let partial = self.partial.take().unwrap();
+        if partial.data.len() != partial.len {
+            return Err(ChunkError::LengthMismatch);
+        }
+        Ok(Some(partial.data))
+    }
+}
+
+/// Runs `link` between a connection's frame channels and the chunks of a
+/// GATT link, until either side goes away. New frames are taken only once
+/// the previous ones are on the air, so a peer withholding credit pushes
+/// back on the connection.
+async fn pump<I, W>(
+    mut link: ChunkLink,
+    mut chunks_in: I,
+    mut chunks_out: W,
+    mut frames_out: Receiver<Frame>,
+    mut frames_in: Sender<Frame>,
+    closed: CloseHandle,
+) where
+    I: Stream<Item = Vec<u8>> + Unpin,
+    W: Sink<Vec<u8>> + Unpin,
+{
+    loop {
+        while let Some(chunk) = link.poll_transmit() {
+            if chunks_out.send(chunk).await.is_err() {
+                closed.close(CloseReason::LinkLost);
+                return;
+            }
+        }
+        tokio::select! {
+            chunk = chunks_in.next() => {
+                let Some(chunk) = chunk else {
+                    closed.close(CloseReason::Remote);
+                    return;
+                };
+                match link.receive(&chunk) {
+                    Ok(Some(frame)) => {
+                        // nobody reading frames is not a reason to stop sending
+                        let _ = frames_in.send(frame).await;
+                    }
+                    Ok(None) => {}
+                    Err(err) => {
+                        closed.close(CloseReason::Failed(err.to_string()));
+                        return;
+                    }
+                }
+            }
+            frame = frames_out.next(), if link.queued_chunks() == 0 => {
+                let Some(frame) = frame else {
+                    closed.close(CloseReason::Local);
+                    return;
+                };
+                if let Err(err) = link.send_frame(&frame) {
+                    closed.close(CloseReason::Failed(err.to_string()));
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Starts pumping a GATT link and returns the connection it carries.
+fn open_connection<I, W>(
+    config: &GattConfig,
+    remote: Option<TransportAddress>,
+    chunks_in: I,
+    chunks_out: W,
+) -> Result<(Connection, impl Future<Output = ()> + Send + 'static), GattError>
+where
+    I: Stream<Item = Vec<u8>> + Unpin + Send + 'static,
+    W: Sink<Vec<u8>> + Unpin + Send + 'static,
+{
+    let link = ChunkLink::new(config)?;
+    let (frames_out_tx, frames_out) = mpsc::channel(FRAME_CAPACITY);
+    let (frames_in, frames_in_rx) = mpsc::channel(FRAME_CAPACITY);
+    let closed = CloseHandle::default();
+    let sink_closed = closed.clone();
+    let sink = frames_out_tx.sink_map_err(move |_| {
+        TransportError::Closed(sink_closed.reason().unwrap_or(CloseReason::LinkLost))
+    });
+    let pumped = pump(
+        link,
+        chunks_in,
+        chunks_out,
+        frames_out,
+        frames_in,
+        closed.clone(),
+    );
+    let connection = Connection::new(
+        remote,
+        config.max_frame_len,
+        Box::pin(sink),
+        Box::pin(frames_in_rx),
+        closed,
+    );
+    Ok((connection, pumped))
+}
+
+#[derive(Debug)]
+pub enum GattError {
+    /// This device cannot act as a peripheral, so it cannot accept
+    /// connections.
+    NoPeripheral,
+    NoAdapter,
+    /// The peer has no BLE address, or is not among the adapter's
+    /// peripherals.
+    NoAddress,
+    ServiceNotFound,
+    /// The configured ATT MTU leaves no room for data.
+    MtuTooSmall(usize),
+    PeripheralError(bluster::Error),
+    CentralError(btleplug::Error),
+}
+
+impl fmt::Display for GattError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GattError::NoPeripheral => write!(f, "no ble peripheral available"),
+            GattError::NoAdapter => write!(f, "no ble adapter available"),
+            GattError::NoAddress => write!(f, "peer has no known ble address"),
+            GattError::ServiceNotFound => write!(f, "peer does not expose the pdrop service"),
+            GattError::MtuTooSmall(mtu) => write!(f, "att mtu {mtu} leaves no room for data"),
+            GattError::PeripheralError(err) => write!(f, "gatt server failed: {err}"),
+            GattError::CentralError(err) => write!(f, "gatt client failed: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for GattError {}
+
+impl From<bluster::Error> for GattError {
+    fn from(err: bluster::Error) -> Self {
+        GattError::PeripheralError(err)
+    }
+}
+
+impl From<btleplug::Error> for GattError {
+    fn from(err: btleplug::Error) -> Self {
+        GattError::CentralError(err)
+    }
+}
+
+/// The pdrop service, reporting writes and subscriptions on `events`.
+fn pdrop_service(events: Sender<Event>) -> Service {
+    let rx = Characteristic::new(
+        bluster_uuid(RX_CHARACTERISTIC_UUID),
+        Properties::new(
+            None,
+            Some(Write::WithResponse(Secure::Insecure(events.clone()))),
+            None,
+            None,
+        ),
+        None,
+        HashSet::new(),
+    );
+    let tx = Characteristic::new(
+        bluster_uuid(TX_CHARACTERISTIC_UUID),
+        Properties::new(None, None, Some(events), None),
+        None,
+        HashSet::new(),
+    );
+    Service::new(
+        bluster_uuid(PDROP_SERVICE_UUID),
+        true,
+        [rx, tx].into_iter().collect(),
+    )
+}
+
+/// Turns GATT server events into connections. bluster does not tell
+/// centrals apart, so there is one connection at a time: a subscription to
+/// TX opens it, and unsubscribing ends it.
+async fn serve(
+    mut events: Receiver<Event>,
+    accepted: mpsc::UnboundedSender<Connection>,
+    config: GattConfig,
+) {
+    let mut current: Option<Sender<Vec<u8>>> = None;
+    while let Some(event) = events.next().await {
+        match event {
+            Event::NotifySubscribe(subscribe) => {
+                let (chunks_tx, chunks_in) = mpsc::channel(usize::from(config.initial_credits));
+                // the config was checked when listening started
+                let Ok((connection, pumped)) =
+                    open_connection(&config, None, chunks_in, subscribe.notification)
+                else {
+                    return;
+                };
+                if accepted.unbounded_send(connection).is_err() {
+                    return;
+                }
+                tokio::spawn(pumped);
+                current = Some(chunks_tx);
+            }
+            Event::NotifyUnsubscribe => current = None,
+            Event::WriteRequest(write) => {
+                let _ = write.response.send(Response::Success(Vec::new()));
+                if let Some(chunks) = current.as_mut()
+                    && chunks.send(write.data).await.is_err()
+                {
+                    current = None;
+                }
+            }
+            Event::ReadRequest(read) => {
+                let _ = read.response.send(Response::UnlikelyError);
+            }
+        }
+    }
+}
+
+async fn connect(
+    adapters: Adapters,
+    selector: AdapterSelector,
+    address: BDAddr,
+    config: GattConfig,
+) -> Result<Connection, GattError> {
+    ChunkLink::new(&config)?;
+    let (adapter, _) = adapters
+        .select(&selector)
+        .await?
+        .ok_or(GattError::NoAdapter)?;
+    let device = adapter
+        .peripherals()
+        .await?
+        .into_iter()
+        .find(|device| device.address() == address)
+        .ok_or(GattError::NoAddress)?;
+    device.connect().await?;
+    device.discover_services().await?;
+    let characteristic = |uuid| {
+        device
+            .characteristics()
+            .into_iter()
+            .find(|c| c.service_uuid == PDROP_SERVICE_UUID && c.uuid == uuid)
+            .ok_or(GattError::ServiceNotFound)
+    };
+    let (rx, tx) = (
+        characteristic(RX_CHARACTERISTIC_UUID)?,
+        characteristic(TX_CHARACTERISTIC_UUID)?,
+    );
+    device.subscribe(&tx).await?;
+
+    let chunks_in = device
+        .notifications()
+        .await?
+        .filter(|n| futures::future::ready(n.uuid == TX_CHARACTERISTIC_UUID))
+        .map(|n| n.value);
+    let chunks_out = Box::pin(sink::unfold(
+        device.clone(),
+        move |device, chunk: Vec<u8>| {
+            let rx = rx.clone();
+            async move {
+                device.write(&rx, &chunk, WriteType::WithResponse).await?;
+                Ok::<_, btleplug::Error>(device)
+            }
+        },
+    ));
+    let remote = Some(TransportAddress::Ble(address.into_inner()));
+    let (connection, pumped) = open_connection(&config, remote, chunks_in, chunks_out)?;
+    tokio::spawn(async move {
+        pumped.await;
+        let _ = device.disconnect().await;
+    });
+    Ok(connection)
+}
+
+impl Transport for BleDiscovery {
+    type Error = GattError;
+
+    /// Connects as a central to the first BLE address of `peer`.
+    fn dial(&self, peer: &PeerInfo) -> BoxFutureResponse<Connection, Self::Error> {
+        let address = peer.addresses.iter().find_map(|address| match address {
+            TransportAddress::Ble(address) => Some(BDAddr::from(*address)),
+            TransportAddress::Socket(_) => None,
+        });
+        let adapters = self.adapters.clone();
+        let (selector, config) = (self.config.adapter.clone(), self.config.gatt.clone());
+        Box::pin(async move {
+            let address = address.ok_or(GattError::NoAddress)?;
+            connect(adapters, selector, address, config).await
+        })
+    }
+
+    /// Publishes the pdrop GATT service and accepts centrals subscribing to
+    /// it.
+    fn listen(&self) -> BoxFutureResponse<BoxStreamResponse<Connection>, Self::Error> {
+        let (peripheral, config) = (self.peripheral.clone(), self.config.gatt.clone());
+        Box::pin(async move {
+            let peripheral = peripheral.ok_or(GattError::NoPeripheral)?;
+            ChunkLink::new(&config)?;
+            let (events_tx, events) = mpsc::channel(usize::from(config.initial_credits));
+            peripheral.add_service(&pdrop_service(events_tx))?;
+            let (accepted, incoming) = mpsc::unbounded();
+            tokio::spawn(serve(events, accepted, config));
+            Ok(Box::pin(incoming) as BoxStreamResponse<Connection>)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::run;
+
+    fn config(att_mtu: usize) -> GattConfig {
+        GattConfig {
+            att_mtu,
+            max_frame_len: 1024,
+            initial_credits: 4,
+        }
+    }
+
+    fn link(att_mtu: usize) -> ChunkLink {
+        ChunkLink::new(&config(att_mtu)).unwrap()
+    }
+
+    /// Moves every chunk `from` may send into `to`, collecting the frames.
+    fn deliver(from: &mut ChunkLink, to: &mut ChunkLink) -> (usize, Vec<Frame>) {
+        let (mut chunks, mut frames) = (0, Vec::new());
+        while let Some(chunk) = from.poll_transmit() {
+            assert!(chunk.len() <= from.chunk_len);
+            chunks += 1;
+            frames.extend(to.receive(&chunk).unwrap());
+        }
+        (chunks, frames)
+    }
+
+    #[test]
+    fn frames_are_cut_to_the_mtu_and_reassembled() {
+        let (mut alice, mut bob) = (link(23), link(23));
+        let frame: Frame = (0..=255).collect();
+        let small = b"hi".to_vec();
+        alice.send_frame(&frame).unwrap();
+        alice.send_frame(&small).unwrap();
+        alice.send_frame(&[]).unwrap();
+
+        let mut received = Vec::new();
+        while alice.queued_chunks() > 0 {
+            received.extend(deliver(&mut alice, &mut bob).1);
+            deliver(&mut bob, &mut alice);
+        }
+        assert_eq!(received, [frame, small, Vec::new()]);
+    }
+
+    #[test]
+    fn sender_stops_at_its_credit_until_granted_more() {
+        let (mut alice, mut bob) = (link(23), link(23));
+        alice.send_frame(&[7; 200]).unwrap();
+        let total = alice.queued_chunks();
+        assert!(total > 4);
+
+        let (sent, _) = deliver(&mut alice, &mut bob);
+        assert_eq!(sent, 4);
+        assert!(alice.poll_transmit().is_none());
+
+        // bob grants two credits per two chunks consumed
+        assert_eq!(bob.poll_transmit(), Some(vec![CREDIT, 4]));
+        assert!(bob.poll_transmit().is_none());
+        alice.receive(&[CREDIT, 4]).unwrap();
+        let (sent, _) = deliver(&mut alice, &mut bob);
+        assert_eq!(sent, 4.min(total - 4));
+    }
+
+    #[test]
+    fn grants_beyond_the_window_are_capped() {
+        let mut alice = link(23);
+        alice.send_frame(&[7; 400]).unwrap();
+        for _ in 0..3 {
+            alice.receive(&[CREDIT, u8::MAX]).unwrap();
+        }
+        let sent = std::iter::from_fn(|| alice.poll_transmit()).count();
+        assert_eq!(sent, 4, "no more than the initial window");
+    }
+
+    #[test]
+    fn an_mtu_without_room_for_data_is_refused() {
+        assert!(matches!(
+            ChunkLink::new(&config(ATT_OVERHEAD + 1 + LENGTH_LEN)),
+            Err(GattError::MtuTooSmall(8))
+        ));
+        assert!(ChunkLink::new(&config(ATT_OVERHEAD + 2 + LENGTH_LEN)).is_ok());
+    }
+
+    #[test]
+    fn broken_chunk_streams_are_rejected() {
+        let mut alice = link(23);
+        alice.send_frame(&[1; 40]).unwrap();
+        let chunks: Vec<_> = std::iter::from_fn(|| alice.poll_transmit()).collect();
+
+        let mut bob = link(23);
+        assert_eq!(
+            bob.receive(&chunks[1]),
+            Err(ChunkError::OutOfSequence {
+                expected: 0,
+                got: 1
+            })
+        );
+
+        let mut bob = link(23);
+        let mut lying = chunks[0].clone();
+        lying[1..5].copy_from_slice(&5u32.to_be_bytes());
+        assert_eq!(bob.receive(&lying), Err(ChunkError::LengthMismatch));
+
+        let mut bob = link(23);
+        let mut huge = chunks[0].clone();
+        huge[1..5].copy_from_slice(&4096u32.to_be_bytes());
+        assert_eq!(
+            bob.receive(&huge),
+            Err(ChunkError::FrameTooLarge {
+                len: 4096,
+                max: 1024
+            })
+        );
+
+        assert_eq!(link(23).receive(&[]), Err(ChunkError::Malformed));
+    }
+
+    #[test]
+    fn connections_carry_frames_over_a_simulated_gatt_link() {
+        run(async {
+            // the air buffers what credits allow, like the BLE stack would
+            let (a_out, b_in) = mpsc::unbounded();
+            let (b_out, a_in) = mpsc::unbounded();
+            let (mut alice, alice_pump) = open_connection(&config(23), None, a_in, a_out).unwrap();
+            let (mut bob, bob_pump) = open_connection(&config(23), None, b_in, b_out).unwrap();
+            tokio::spawn(alice_pump);
+            tokio::spawn(bob_pump);
+
+            let frames: Vec<Frame> = (0..10u8).map(|i| vec![i; 100 * i as usize]).collect();
+            let sent = frames.clone();
+            let sender = tokio::spawn(async move {
+                for frame in sent {
+                    alice.send(frame).await.unwrap();
+                }
+                alice
+            });
+            for frame in &frames {
+                assert_eq!(&bob.next().await.unwrap(), frame);
+            }
+            let mut alice = sender.await.unwrap();
+
+            bob.send(b"thanks".to_vec()).await.unwrap();
+            assert_eq!(alice.next().await.unwrap(), b"thanks");
+
+            alice.close().await.unwrap();
+            assert_eq!(bob.next().await, None);
+            assert_eq!(bob.close_reason(), Some(CloseReason::Remote));
+        });
+    }
+}
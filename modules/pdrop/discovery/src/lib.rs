@@ -1 +1,12 @@
+pub mod adapter;
 pub mod ble;
+pub mod gatt;
+pub mod mdns;
+pub mod memory;
+pub mod schedule;
+pub mod udp;
+
+mod tracker;
+
+#[cfg(test)]
+mod testing;
@@ -0,0 +1,376 @@
+//! LAN discovery over mDNS/DNS-SD. Every node publishes a `_pdrop._udp`
+//! service whose TXT record carries its `PeerInfo`, and browses for the
+//! others.
+
+use core::{
+    discovery::{Advertiser, Discovery, DiscoveryEvent},
+    identity::{Capabilities, PeerInfo, TransportAddress},
+    types::{BoxFutureResponse, BoxStreamResponse, PeerId},
+};
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    thread,
+    time::SystemTime,
+};
+
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use mdns_sd::{IfKind, ServiceDaemon, ServiceEvent, ServiceInfo};
+
+pub const SERVICE_TYPE: &str = "_pdrop._udp.local.";
+
+const ID_KEY: &str = "id";
+const NAME_KEY: &str = "name";
+const CAPABILITIES_KEY: &str = "caps";
+const VERSIONS_KEY: &str = "versions";
+
+#[derive(Debug, Clone, Default)]
+pub struct MdnsConfig {
+    /// Port peers should connect to, published in the SRV record.
+    pub port: u16,
+    /// Addresses to publish. When empty the daemon follows the host's
+    /// interfaces.
+    pub addresses: Vec<IpAddr>,
+    /// Also run on loopback interfaces, which mDNS skips by default. Lets
+    /// several nodes in one process find each other.
+    pub loopback: bool,
+}
+
+#[derive(Debug)]
+pub enum MdnsError {
+    DaemonError(mdns_sd::Error),
+    /// The thread forwarding browse results could not be started.
+    SpawnError(io::Error),
+}
+
+impl fmt::Display for MdnsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MdnsError::DaemonError(err) => write!(f, "mdns daemon failed: {err}"),
+            MdnsError::SpawnError(err) => write!(f, "could not start mdns browsing: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for MdnsError {}
+
+impl From<mdns_sd::Error> for MdnsError {
+    fn from(err: mdns_sd::Error) -> Self {
+        MdnsError::DaemonError(err)
+    }
+}
+
+pub struct MdnsDiscovery {
+    daemon: ServiceDaemon,
+    local: PeerInfo,
+    config: MdnsConfig,
+    inbox: Arc<Mutex<UnboundedSender<DiscoveryEvent>>>,
+    events: Option<UnboundedReceiver<DiscoveryEvent>>,
+    /// Whether a browse is running, so scanning twice browses once.
+    browsing: Mutex<bool>,
+}
+
+/// TXT record properties describing `info`. Addresses are not among them:
+/// the SRV and address records carry those.
+pub fn peer_properties(info: &PeerInfo) -> Vec<(&'static str, String)> {
+    let versions: Vec<String> = info.protocol_versions.iter().map(u8::to_string).collect();
+    let mut properties = vec![
+        (ID_KEY, info.id.to_string()),
+        (CAPABILITIES_KEY, info.capabilities.bits().to_string()),
+        (VERSIONS_KEY, versions.join(",")),
+    ];
+    if let Some(name) = &info.display_name {
+        properties.push((NAME_KEY, name.clone()));
+    }
+    properties
+}
+
+/// Reads back a `PeerInfo` published with `peer_properties`, reachable at
+/// the resolved addresses and port. Signal strength has no meaning on a LAN
+/// and stays unset.
+pub fn peer_from_service(service: &ServiceInfo) -> Option<PeerInfo> {
+    let mut info = PeerInfo::new(service.get_property_val_str(ID_KEY)?.parse().ok()?);
+    info.display_name = service.get_property_val_str(NAME_KEY).map(str::to_string);
+    if let Some(bits) = service.get_property_val_str(CAPABILITIES_KEY) {
+        info.capabilities = Capabilities::from_bits(bits.parse().ok()?);
+    }
+    if let Some(versions) = service.get_property_val_str(VERSIONS_KEY) {
+        info.protocol_versions = versions
+            .split(',')
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .ok()?;
+    }
+    let mut addresses: Vec<SocketAddr> = service
+        .get_addresses()
+        .iter()
+        .map(|&ip| SocketAddr::new(ip, service.get_port()))
+        .collect();
+    addresses.sort();
+    info.addresses = addresses
+        .into_iter()
+        .map(TransportAddress::Socket)
+        .collect();
+    info.last_seen = Some(SystemTime::now());
+    Some(info)
+}
+
+/// DNS labels are limited to 63 bytes, short of a hex peer id; half of it
+/// is plenty to keep instances apart.
+fn instance_name(id: &PeerId) -> String {
+    id.to_string()[..32].to_string()
+}
+
+impl MdnsDiscovery {
+    pub fn new(local: PeerInfo, config: MdnsConfig) -> Result<Self, MdnsError> {
+        let daemon = ServiceDaemon::new()?;
+        if config.loopback {
+            daemon.enable_interface(IfKind::LoopbackV4)?;
+        }
+        let (inbox, events) = mpsc::unbounded();
+        Ok(MdnsDiscovery {
+            daemon,
+            local,
+            config,
+            inbox: Arc::new(Mutex::new(inbox)),
+            events: Some(events),
+            browsing: Mutex::new(false),
+        })
+    }
+
+    fn instance_fullname(&self) -> String {
+        format!("{}.{}", instance_name(&self.local.id), SERVICE_TYPE)
+    }
+
+    fn service_info(&self) -> Result<ServiceInfo, MdnsError> {
+        let instance = instance_name(&self.local.id);
+        let host = format!("{instance}.local.");
+        let properties = peer_properties(&self.local);
+        let info = ServiceInfo::new(
+            SERVICE_TYPE,
+            &instance,
+            &host,
+            &self.config.addresses[..],
+            self.config.port,
+            &properties[..],
+        )?;
+        Ok(if self.config.addresses.is_empty() {
+            info.enable_addr_auto()
+        } else {
+            info
+        })
+    }
+
+    fn browse(&self) -> Result<(), MdnsError> {
+        let mut browsing = self.browsing.lock().unwrap();
+        if *browsing {
+            return Ok(());
+        }
+        let browse = self.daemon.browse(SERVICE_TYPE)?;
+        let own_fullname = self.instance_fullname();
+        let inbox = self.inbox.clone();
+        let spawned = thread::Builder::new()
+            .name("pdrop-mdns-browse".to_string())
+            .spawn(move || forward_browse(browse, own_fullname, inbox));
+        if let Err(err) = spawned {
+            let _ = self.daemon.stop_browse(SERVICE_TYPE);
+            return Err(MdnsError::SpawnError(err));
+        }
+        *browsing = true;
+        Ok(())
+    }
+}
+
+impl Drop for MdnsDiscovery {
+    fn drop(&mut self) {
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// Forwards browse results as discovery events until the browse is stopped
+/// or the daemon goes away.
+fn forward_browse(
+    browse: mdns_sd::Receiver<ServiceEvent>,
+    own_fullname: String,
+    inbox: Arc<Mutex<UnboundedSender<DiscoveryEvent>>>,
+) {
+    let mut known: HashMap<String, PeerInfo> = HashMap::new();
+    while let Ok(event) = browse.recv() {
+        let event = match event {
+            ServiceEvent::ServiceResolved(service) => {
+                let fullname = service.get_fullname().to_string();
+                if fullname == own_fullname || known.contains_key(&fullname) {
+                    continue;
+                }
+                let Some(peer) = peer_from_service(&service) else {
+                    continue;
+                };
+                known.insert(fullname, peer.clone());
+                DiscoveryEvent::PeerDiscovered(peer)
+            }
+            ServiceEvent::ServiceRemoved(_, fullname) => match known.remove(&fullname) {
+                Some(peer) => DiscoveryEvent::PeerLost(peer),
+                None => continue,
+            },
+            ServiceEvent::SearchStopped(_) => break,
+            _ => continue,
+        };
+        // nobody listening for events is not an error for the browse
+        let _ = inbox.lock().unwrap().unbounded_send(event);
+    }
+}
+
+impl Discovery for MdnsDiscovery {
+    type Error = MdnsError;
+    type DiscoveryEvent = DiscoveryEvent;
+
+    /// Starts browsing unless already browsing.
+    fn start_scan(&self) -> BoxFutureResponse<(), Self::Error> {
+        let started = self.browse();
+        Box::pin(async move { started })
+    }
+
+    /// Stops browsing, if browsing.
+    fn stop_scan(&self) -> BoxFutureResponse<(), Self::Error> {
+        let mut browsing = self.browsing.lock().unwrap();
+        let stopped = if *browsing {
+            self.daemon
+                .stop_browse(SERVICE_TYPE)
+                .map_err(MdnsError::from)
+        } else {
+            Ok(())
+        };
+        *browsing &= stopped.is_err();
+        Box::pin(async move { stopped })
+    }
+
+    /// Events of this node. Each call takes over from the previous stream.
+    fn poll_events(&mut self) -> BoxStreamResponse<DiscoveryEvent> {
+        let events = self.events.take().unwrap_or_else(|| {
+            let (inbox, events) = mpsc::unbounded();
+            *self.inbox.lock().unwrap() = inbox;
+            events
+        });
+        Box::pin(events)
+    }
+}
+
+impl Advertiser for MdnsDiscovery {
+    type Error = MdnsError;
+
+    fn broadcast(&self) -> BoxFutureResponse<(), Self::Error> {
+        let registered = self
+            .service_info()
+            .and_then(|info| Ok(self.daemon.register(info)?));
+        Box::pin(async move { registered })
+    }
+
+    fn stop_broadcast(&self) -> BoxFutureResponse<(), Self::Error> {
+        let unregistered = self.daemon.unregister(&self.instance_fullname());
+        Box::pin(async move {
+            let status = unregistered?;
+            // wait for the goodbye packets so peers see the loss promptly
+            let _ = status.recv_async().await;
+            Ok(())
+        })
+    }
+}
+
+impl core::discovery::DiscoveryAdvertiser for MdnsDiscovery {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::run_live;
+    use futures::StreamExt;
+    use std::{net::Ipv4Addr, time::Duration};
+
+    fn peer(name: &str) -> PeerInfo {
+        PeerInfo {
+            display_name: Some(format!("{name}'s phone")),
+            ..PeerInfo::new(PeerId::from_public_key(name.as_bytes()))
+        }
+    }
+
+    fn node(name: &str) -> MdnsDiscovery {
+        let local = peer(name);
+        let config = MdnsConfig {
+            port: 7070,
+            addresses: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+            loopback: true,
+        };
+        MdnsDiscovery::new(local, config).unwrap()
+    }
+
+    async fn next_event(events: &mut BoxStreamResponse<DiscoveryEvent>) -> DiscoveryEvent {
+        tokio::time::timeout(Duration::from_secs(20), events.next())
+            .await
+            .expect("no mdns event before the deadline")
+            .unwrap()
+    }
+
+    #[test]
+    fn properties_round_trip_peer_info() {
+        let mut peer = PeerInfo {
+            capabilities: Capabilities::RECEIVE | Capabilities::SEND,
+            protocol_versions: vec![1, 2],
+            ..peer("alice")
+        };
+        let service = ServiceInfo::new(
+            SERVICE_TYPE,
+            &instance_name(&peer.id),
+            "alice.local.",
+            "127.0.0.1",
+            7070,
+            &peer_properties(&peer)[..],
+        )
+        .unwrap();
+
+        let resolved = peer_from_service(&service).unwrap();
+        peer.addresses = vec![TransportAddress::Socket("127.0.0.1:7070".parse().unwrap())];
+        peer.last_seen = resolved.last_seen;
+        assert_eq!(resolved, peer);
+    }
+
+    #[test]
+    fn scanning_twice_browses_once() {
+        run_live(async {
+            let bob = node("bob");
+            bob.start_scan().await.unwrap();
+            bob.start_scan().await.unwrap();
+            assert!(*bob.browsing.lock().unwrap());
+            bob.stop_scan().await.unwrap();
+            assert!(!*bob.browsing.lock().unwrap());
+            bob.stop_scan().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn nodes_on_loopback_discover_and_lose_each_other() {
+        run_live(async {
+            let alice = node("alice");
+            let mut bob = node("bob");
+            let mut bob_events = bob.poll_events();
+
+            bob.start_scan().await.unwrap();
+            bob.broadcast().await.unwrap();
+            alice.broadcast().await.unwrap();
+
+            match next_event(&mut bob_events).await {
+                DiscoveryEvent::PeerDiscovered(found) => {
+                    assert_eq!(found.id, peer("alice").id);
+                    assert_eq!(found.display_name.as_deref(), Some("alice's phone"));
+                }
+                event => panic!("unexpected {event:?}"),
+            }
+
+            alice.stop_broadcast().await.unwrap();
+            match next_event(&mut bob_events).await {
+                DiscoveryEvent::PeerLost(lost) => assert_eq!(lost.id, peer("alice").id),
+                event => panic!("unexpected {event:?}"),
+            }
+        });
+    }
+}
@@ -0,0 +1,624 @@
+//! In-process discovery and transport backend. Nodes joined to the same
+//! `MemoryAir` see each other's advertisements as if they shared a radio,
+//! and can connect to each other while in range, which lets the
+//! orchestrator be exercised in CI without Bluetooth hardware.
+
+use core::{
+    discovery::{Advertiser, Discovery, DiscoveryEvent},
+    identity::PeerInfo,
+    request::PaymentRequest,
+    transport::{Connection, Transport, TransportError},
+    types::{BoxFutureResponse, BoxStreamResponse},
+};
+use std::{
+    collections::{BTreeMap, HashSet},
+    convert::Infallible,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::{
+    StreamExt,
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+};
+use tokio::time::Instant;
+
+pub type NodeId = usize;
+
+/// Largest frame a memory connection carries.
+pub const MEMORY_MTU: usize = 4096;
+/// Frames a memory connection buffers per direction before pushing back.
+const CONNECTION_CAPACITY: usize = 16;
+
+#[derive(Debug, Clone, Default)]
+pub struct AirConditions {
+    /// Delay between an advertisement state change and observers hearing it.
+    pub latency: Duration,
+    /// Probability in `[0, 1]` that an advertisement is missed by an observer.
+    /// Losses are not subject to it: a real scanner notices a silent peer
+    /// through its inactivity timeout either way.
+    pub loss: f64,
+    /// Seed for the loss generator, so lossy runs are reproducible.
+    pub seed: u64,
+}
+
+/// Shared medium for simulated nodes. Cloning hands out another handle to
+/// the same air.
+#[derive(Clone, Default)]
+pub struct MemoryAir {
+    state: Arc<Mutex<AirState>>,
+}
+
+#[derive(Default)]
+struct AirState {
+    conditions: AirConditions,
+    rng: u64,
+    next_id: NodeId,
+    // ordered, so a seed replays the same losses
+    nodes: BTreeMap<NodeId, Node>,
+    /// Pairs that cannot hear each other, stored smallest id first.
+    out_of_range: HashSet<(NodeId, NodeId)>,
+    /// (observer, advertiser) pairs whose discovery has been delivered.
+    announced: HashSet<(NodeId, NodeId)>,
+    /// (observer, advertiser) pairs whose last advertisement was lost.
+    missed: HashSet<(NodeId, NodeId)>,
+}
+
+struct Node {
+    info: PeerInfo,
+    payment_request: Option<PaymentRequest>,
+    advertising: bool,
+    scanning: bool,
+    inbox: UnboundedSender<Delivery>,
+    listener: Option<UnboundedSender<Connection>>,
+}
+
+struct Delivery {
+    at: Instant,
+    event: DiscoveryEvent,
+}
+
+fn pair(a: NodeId, b: NodeId) -> (NodeId, NodeId) {
+    (a.min(b), a.max(b))
+}
+
+impl AirState {
+    /// splitmix64, good enough to decide packet loss.
+    fn roll(&mut self) -> f64 {
+        self.rng = self.rng.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn can_hear(&self, observer: NodeId, advertiser: NodeId) -> bool {
+        let (Some(o), Some(a)) = (self.nodes.get(&observer), self.nodes.get(&advertiser)) else {
+            return false;
+        };
+        observer != advertiser
+            && o.scanning
+            && a.advertising
+            && !self.out_of_range.contains(&pair(observer, advertiser))
+    }
+
+    fn deliver(&self, observer: NodeId, event: DiscoveryEvent) {
+        if let Some(node) = self.nodes.get(&observer) {
+            let at = Instant::now() + self.conditions.latency;
+            // the receiving half may be gone; the node just isn't listening
+            let _ = node.inbox.unbounded_send(Delivery { at, event });
+        }
+    }
+
+    /// Reconciles what every observer has been told with who it can hear.
+    /// `readvertise` gives observers that missed that advertiser another
+    /// chance; other missed pairs stay missed.
+    fn propagate(&mut self, readvertise: Option<NodeId>) {
+        let ids: Vec<NodeId> = self.nodes.keys().copied().collect();
+        for &observer in &ids {
+            for &advertiser in &ids {
+                let key = (observer, advertiser);
+                let audible = self.can_hear(observer, advertiser);
+                if !audible {
+                    self.missed.remove(&key);
+                    if self.announced.remove(&key) {
+                        let info = self.nodes[&advertiser].info.clone();
+                        self.deliver(observer, DiscoveryEvent::PeerLost(info));
+                    }
+                    continue;
+                }
+                if self.announced.contains(&key)
+                    || (self.missed.contains(&key) && readvertise != Some(advertiser))
+                {
+                    continue;
+                }
+                if self.roll() < self.conditions.loss {
+                    self.missed.insert(key);
+                    continue;
+                }
+                self.missed.remove(&key);
+                self.announced.insert(key);
+                let node = &self.nodes[&advertiser];
+                let (info, request) = (node.info.clone(), node.payment_request.clone());
+                self.deliver(observer, DiscoveryEvent::PeerDiscovered(info));
+                if let Some(request) = request {
+                    self.deliver(
+                        observer,
+                        DiscoveryEvent::RequestAdvertised(Box::new(request)),
+                    );
+                }
+            }
+        }
+    }
+}
+
+impl MemoryAir {
+    pub fn new(conditions: AirConditions) -> Self {
+        let air = MemoryAir::default();
+        air.set_conditions(conditions);
+        air
+    }
+
+    pub fn set_conditions(&self, conditions: AirConditions) {
+        let mut state = self.state.lock().unwrap();
+        state.rng = conditions.seed;
+        state.conditions = conditions;
+    }
+
+    /// Adds a node that will advertise itself as `info`.
+    pub fn join(&self, info: PeerInfo) -> MemoryDiscovery {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        let (inbox, events) = mpsc::unbounded();
+        state.nodes.insert(
+            id,
+            Node {
+                info,
+                payment_request: None,
+                advertising: false,
+                scanning: false,
+                inbox,
+                listener: None,
+            },
+        );
+        MemoryDiscovery {
+            air: self.clone(),
+            id,
+            events: Some(events),
+        }
+    }
+
+    /// Puts every node of `left` out of range of every node of `right`.
+    pub fn partition(&self, left: &[NodeId], right: &[NodeId]) {
+        let mut state = self.state.lock().unwrap();
+        for &a in left {
+            for &b in right {
+                state.out_of_range.insert(pair(a, b));
+            }
+        }
+        state.propagate(None);
+    }
+
+    /// Brings every node back in range of every other.
+    pub fn heal(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.out_of_range.clear();
+        state.propagate(None);
+    }
+
+    fn update(&self, id: NodeId, change: impl FnOnce(&mut Node)) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(node) = state.nodes.get_mut(&id) {
+            change(node);
+        }
+        state.propagate(None);
+    }
+}
+
+/// A simulated node on a `MemoryAir`.
+pub struct MemoryDiscovery {
+    air: MemoryAir,
+    id: NodeId,
+    events: Option<UnboundedReceiver<Delivery>>,
+}
+
+impl MemoryDiscovery {
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+}
+
+impl Drop for MemoryDiscovery {
+    fn drop(&mut self) {
+        let mut state = self.air.state.lock().unwrap();
+        if let Some(node) = state.nodes.get_mut(&self.id) {
+            node.advertising = false;
+            node.scanning = false;
+        }
+        state.propagate(None);
+        state.nodes.remove(&self.id);
+        state
+            .announced
+            .retain(|&(o, a)| o != self.id && a != self.id);
+        state.missed.retain(|&(o, a)| o != self.id && a != self.id);
+    }
+}
+
+impl Discovery for MemoryDiscovery {
+    type Error = Infallible;
+    type DiscoveryEvent = DiscoveryEvent;
+
+    fn start_scan(&self) -> BoxFutureResponse<(), Self::Error> {
+        let (air, id) = (self.air.clone(), self.id);
+        Box::pin(async move {
+            air.update(id, |node| node.scanning = true);
+            Ok(())
+        })
+    }
+
+    fn stop_scan(&self) -> BoxFutureResponse<(), Self::Error> {
+        let (air, id) = (self.air.clone(), self.id);
+        // every peer this node had heard is lost, and a later scan starts
+        // from a clean slate, like a fresh radio scan
+        Box::pin(async move {
+            air.update(id, |node| node.scanning = false);
+            Ok(())
+        })
+    }
+
+    /// Events of this node. Each call takes over the node's inbox from the
+    /// previous one.
+    fn poll_events(&mut self) -> BoxStreamResponse<DiscoveryEvent> {
+        let events = match self.events.take() {
+            Some(events) => events,
+            None => {
+                let (inbox, events) = mpsc::unbounded();
+                let mut state = self.air.state.lock().unwrap();
+                if let Some(node) = state.nodes.get_mut(&self.id) {
+                    node.inbox = inbox;
+                }
+                events
+            }
+        };
+        Box::pin(events.then(|delivery| async move {
+            tokio::time::sleep_until(delivery.at).await;
+            delivery.event
+        }))
+    }
+}
+
+impl Advertiser for MemoryDiscovery {
+    type Error = Infallible;
+
+    /// Every call is one advertisement: broadcasting again while already
+    /// advertising re-announces this node to scanners that missed it.
+    fn broadcast(&self) -> BoxFutureResponse<(), Self::Error> {
+        let (air, id) = (self.air.clone(), self.id);
+        Box::pin(async move {
+            let mut state = air.state.lock().unwrap();
+            let Some(node) = state.nodes.get_mut(&id) else {
+                return Ok(());
+            };
+            if node.advertising {
+                state.propagate(Some(id));
+            } else {
+                node.advertising = true;
+                state.propagate(None);
+            }
+            Ok(())
+        })
+    }
+
+    fn stop_broadcast(&self) -> BoxFutureResponse<(), Self::Error> {
+        let (air, id) = (self.air.clone(), self.id);
+        Box::pin(async move {
+            air.update(id, |node| node.advertising = false);
+            Ok(())
+        })
+    }
+
+    /// Observers that already saw this node hear the request right away,
+    /// others along with the node.
+    fn set_payment_request(
+        &self,
+        request: Option<PaymentRequest>,
+    ) -> BoxFutureResponse<(), Self::Error> {
+        let (air, id) = (self.air.clone(), self.id);
+        Box::pin(async move {
+            let mut state = air.state.lock().unwrap();
+            let Some(node) = state.nodes.get_mut(&id) else {
+                return Ok(());
+            };
+            node.payment_request = request.clone();
+            if let Some(request) = request {
+                for &(observer, advertiser) in &state.announced {
+                    if advertiser == id {
+                        state.deliver(
+                            observer,
+                            DiscoveryEvent::RequestAdvertised(Box::new(request.clone())),
+                        );
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+impl core::discovery::DiscoveryAdvertiser for MemoryDiscovery {}
+
+impl Transport for MemoryDiscovery {
+    type Error = TransportError;
+
+    /// Connects to the node advertising `peer`'s id, if it is in range and
+    /// listening. Connections already open survive partitions.
+    fn dial(&self, peer: &PeerInfo) -> BoxFutureResponse<Connection, Self::Error> {
+        let (air, id, target) = (self.air.clone(), self.id, peer.id);
+        Box::pin(async move {
+            let state = air.state.lock().unwrap();
+            let listener = state
+                .nodes
+                .iter()
+                .find(|&(&other, node)| {
+                    other != id
+                        && node.info.id == target
+                        && !state.out_of_range.contains(&pair(id, other))
+                })
+                .and_then(|(_, node)| node.listener.as_ref())
+                .ok_or(TransportError::Unreachable)?;
+            let (local, remote) = Connection::pair(MEMORY_MTU, CONNECTION_CAPACITY);
+            listener
+                .unbounded_send(remote)
+                .map_err(|_| TransportError::Unreachable)?;
+            Ok(local)
+        })
+    }
+
+    fn listen(&self) -> BoxFutureResponse<BoxStreamResponse<Connection>, Self::Error> {
+        let (air, id) = (self.air.clone(), self.id);
+        Box::pin(async move {
+            let (listener, incoming) = mpsc::unbounded();
+            air.update(id, |node| node.listener = Some(listener));
+            Ok(Box::pin(incoming) as BoxStreamResponse<Connection>)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::run;
+    use core::types::PeerId;
+
+    fn peer(name: &str) -> PeerInfo {
+        PeerInfo {
+            display_name: Some(name.to_uppercase()),
+            ..PeerInfo::new(PeerId::from_public_key(name.as_bytes()))
+        }
+    }
+
+    /// Events already delivered to a node, without waiting for more.
+    async fn drain(events: &mut BoxStreamResponse<DiscoveryEvent>) -> Vec<DiscoveryEvent> {
+        let mut seen = Vec::new();
+        while let Ok(Some(event)) =
+            tokio::time::timeout(Duration::from_secs(60), events.next()).await
+        {
+            seen.push(event);
+        }
+        seen
+    }
+
+    #[test]
+    fn advertising_is_seen_by_scanners_and_stopping_is_a_loss() {
+        run(async {
+            let air = MemoryAir::default();
+            let alice = air.join(peer("alice"));
+            let mut bob = air.join(peer("bob"));
+            let mut bob_events = bob.poll_events();
+
+            bob.start_scan().await.unwrap();
+            alice.broadcast().await.unwrap();
+            alice.stop_broadcast().await.unwrap();
+
+            assert_eq!(
+                drain(&mut bob_events).await,
+                [
+                    DiscoveryEvent::PeerDiscovered(peer("alice")),
+                    DiscoveryEvent::PeerLost(peer("alice")),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn stopping_a_scan_loses_the_peers_it_found() {
+        run(async {
+            let air = MemoryAir::default();
+            let alice = air.join(peer("alice"));
+            let mut bob = air.join(peer("bob"));
+            let mut bob_events = bob.poll_events();
+
+            alice.broadcast().await.unwrap();
+            bob.start_scan().await.unwrap();
+            bob.stop_scan().await.unwrap();
+            bob.start_scan().await.unwrap();
+
+            assert_eq!(
+                drain(&mut bob_events).await,
+                [
+                    DiscoveryEvent::PeerDiscovered(peer("alice")),
+                    DiscoveryEvent::PeerLost(peer("alice")),
+                    DiscoveryEvent::PeerDiscovered(peer("alice")),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn scanners_pick_up_existing_advertisers_but_not_themselves() {
+        run(async {
+            let air = MemoryAir::default();
+            let mut alice = air.join(peer("alice"));
+            let bob = air.join(peer("bob"));
+            let mut alice_events = alice.poll_events();
+
+            alice.broadcast().await.unwrap();
+            bob.broadcast().await.unwrap();
+            alice.start_scan().await.unwrap();
+
+            assert_eq!(
+                drain(&mut alice_events).await,
+                [DiscoveryEvent::PeerDiscovered(peer("bob"))]
+            );
+        });
+    }
+
+    #[test]
+    fn events_arrive_after_the_injected_latency() {
+        run(async {
+            let air = MemoryAir::new(AirConditions {
+                latency: Duration::from_millis(250),
+                ..Default::default()
+            });
+            let alice = air.join(peer("alice"));
+            let mut bob = air.join(peer("bob"));
+            let mut bob_events = bob.poll_events();
+            bob.start_scan().await.unwrap();
+
+            let sent = Instant::now();
+            alice.broadcast().await.unwrap();
+            bob_events.next().await.unwrap();
+            assert_eq!(sent.elapsed(), Duration::from_millis(250));
+        });
+    }
+
+    #[test]
+    fn lost_advertisements_are_recovered_by_readvertising() {
+        run(async {
+            let air = MemoryAir::new(AirConditions {
+                loss: 1.0,
+                ..Default::default()
+            });
+            let alice = air.join(peer("alice"));
+            let mut bob = air.join(peer("bob"));
+            let mut bob_events = bob.poll_events();
+            bob.start_scan().await.unwrap();
+
+            alice.broadcast().await.unwrap();
+            assert!(drain(&mut bob_events).await.is_empty());
+
+            air.set_conditions(AirConditions::default());
+            alice.broadcast().await.unwrap();
+            assert_eq!(
+                drain(&mut bob_events).await,
+                [DiscoveryEvent::PeerDiscovered(peer("alice"))]
+            );
+        });
+    }
+
+    #[test]
+    fn partial_loss_is_reproducible_from_the_seed() {
+        run(async {
+            let heard = |seed| async move {
+                let air = MemoryAir::new(AirConditions {
+                    loss: 0.5,
+                    seed,
+                    ..Default::default()
+                });
+                let mut scanners: Vec<_> =
+                    (0..16).map(|i| air.join(peer(&i.to_string()))).collect();
+                let mut streams: Vec<_> = scanners.iter_mut().map(|s| s.poll_events()).collect();
+                for scanner in &scanners {
+                    scanner.start_scan().await.unwrap();
+                }
+                air.join(peer("alice")).broadcast().await.unwrap();
+                let mut heard = Vec::new();
+                for events in &mut streams {
+                    heard.push(!drain(events).await.is_empty());
+                }
+                heard
+            };
+
+            let first = heard(7).await;
+            assert_eq!(first, heard(7).await);
+            assert!(first.contains(&true) && first.contains(&false));
+        });
+    }
+
+    #[test]
+    fn partitions_lose_peers_and_healing_rediscovers_them() {
+        run(async {
+            let air = MemoryAir::default();
+            let alice = air.join(peer("alice"));
+            let mut bob = air.join(peer("bob"));
+            let mut bob_events = bob.poll_events();
+            bob.start_scan().await.unwrap();
+            alice.broadcast().await.unwrap();
+
+            air.partition(&[alice.id()], &[bob.id()]);
+            alice.broadcast().await.unwrap();
+            air.heal();
+
+            assert_eq!(
+                drain(&mut bob_events).await,
+                [
+                    DiscoveryEvent::PeerDiscovered(peer("alice")),
+                    DiscoveryEvent::PeerLost(peer("alice")),
+                    DiscoveryEvent::PeerDiscovered(peer("alice")),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn leaving_the_air_is_a_loss_for_observers() {
+        run(async {
+            let air = MemoryAir::default();
+            let alice = air.join(peer("alice"));
+            let mut bob = air.join(peer("bob"));
+            let mut bob_events = bob.poll_events();
+            bob.start_scan().await.unwrap();
+            alice.broadcast().await.unwrap();
+
+            drop(alice);
+
+            assert_eq!(
+                drain(&mut bob_events).await,
+                [
+                    DiscoveryEvent::PeerDiscovered(peer("alice")),
+                    DiscoveryEvent::PeerLost(peer("alice")),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn nodes_in_range_connect_and_partitions_refuse_new_connections() {
+        run(async {
+            use futures::SinkExt;
+
+            let air = MemoryAir::default();
+            let alice = air.join(peer("alice"));
+            let bob = air.join(peer("bob"));
+            let mut incoming = alice.listen().await.unwrap();
+
+            let mut to_alice = bob.dial(&peer("alice")).await.unwrap();
+            let mut to_bob = incoming.next().await.unwrap();
+            to_alice.send(b"hi".to_vec()).await.unwrap();
+            assert_eq!(to_bob.next().await.unwrap(), b"hi");
+            assert_eq!(to_alice.mtu(), MEMORY_MTU);
+
+            assert!(matches!(
+                bob.dial(&peer("carol")).await,
+                Err(TransportError::Unreachable)
+            ));
+            air.partition(&[alice.id()], &[bob.id()]);
+            assert!(matches!(
+                bob.dial(&peer("alice")).await,
+                Err(TransportError::Unreachable)
+            ));
+        });
+    }
+}
@@ -0,0 +1,646 @@
+//! Duty-cycled scanning and advertising. A `Scheduler` turns a backend's
+//! scan and advertisement on and off following the duty cycle of the
+//! current `Profile`, so a phone in a pocket does not keep its radio busy,
+//! and switches profile on command, e.g. when the send screen opens.
+//!
+//! Cycles run against a `Clock`; `SimulatedClock` only moves when told to,
+//! which makes schedules testable step by step.
+
+use core::discovery::{Advertiser, Discovery};
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::{
+    FutureExt, StreamExt,
+    channel::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    future::{self, Either},
+};
+use tokio::{task::JoinHandle, time::Instant};
+
+/// Source of time for a scheduler, counted from the clock's own origin.
+pub trait Clock: Send + Sync + 'static {
+    fn now(&self) -> Duration;
+    /// Completes once `now()` has reached `deadline`.
+    fn sleep_until(&self, deadline: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>>;
+}
+
+/// The tokio clock, so paused test runtimes apply too.
+#[derive(Debug, Clone)]
+pub struct TokioClock {
+    origin: Instant,
+}
+
+impl Default for TokioClock {
+    fn default() -> Self {
+        TokioClock {
+            origin: Instant::now(),
+        }
+    }
+}
+
+impl Clock for TokioClock {
+    fn now(&self) -> Duration {
+        self.origin.elapsed()
+    }
+
+    fn sleep_until(&self, deadline: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(tokio::time::sleep_until(self.origin + deadline))
+    }
+}
+
+/// A clock that stands still until `advance` moves it. Cloning hands out
+/// another handle to the same clock.
+#[derive(Clone, Default)]
+pub struct SimulatedClock {
+    state: Arc<Mutex<SimulatedTime>>,
+}
+
+#[derive(Default)]
+struct SimulatedTime {
+    now: Duration,
+    sleepers: Vec<(Duration, oneshot::Sender<()>)>,
+}
+
+impl SimulatedClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Moves the clock forward, waking whoever sleeps until then.
+    pub fn advance(&self, by: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.now += by;
+        let now = state.now;
+        let (due, waiting) = std::mem::take(&mut state.sleepers)
+            .into_iter()
+            .partition(|(deadline, _)| *deadline <= now);
+        state.sleepers = waiting;
+        drop(state);
+        for (_, wake) in due {
+            // the sleeper may have been dropped
+            let _ = wake.send(());
+        }
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> Duration {
+        self.state.lock().unwrap().now
+    }
+
+    fn sleep_until(&self, deadline: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        let mut state = self.state.lock().unwrap();
+        if deadline <= state.now {
+            return Box::pin(future::ready(()));
+        }
+        // forget sleepers that gave up
+        state.sleepers.retain(|(_, wake)| !wake.is_canceled());
+        let (wake, woken) = oneshot::channel();
+        state.sleepers.push((deadline, wake));
+        Box::pin(woken.map(|_| ()))
+    }
+}
+
+/// How much of the time the radio scans and advertises: each is on for its
+/// window at the start of every interval. A window as long as its interval
+/// means always on; an empty one, never.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DutyCycle {
+    pub scan_window: Duration,
+    pub scan_interval: Duration,
+    pub advertise_window: Duration,
+    pub advertise_interval: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    /// Scanning and advertising all the time, while the user waits on the
+    /// send screen.
+    Aggressive,
+    /// The app is open but not looking for anyone in particular.
+    Balanced,
+    /// The app is in the background; just enough to be found eventually.
+    Background,
+}
+
+impl Profile {
+    pub fn duty_cycle(self) -> DutyCycle {
+        let secs = Duration::from_secs;
+        match self {
+            Profile::Aggressive => DutyCycle {
+                scan_window: secs(1),
+                scan_interval: secs(1),
+                advertise_window: secs(1),
+                advertise_interval: secs(1),
+            },
+            Profile::Balanced => DutyCycle {
+                scan_window: secs(2),
+                scan_interval: secs(10),
+                advertise_window: secs(1),
+                advertise_interval: secs(5),
+            },
+            Profile::Background => DutyCycle {
+                scan_window: secs(2),
+                scan_interval: secs(60),
+                advertise_window: secs(1),
+                advertise_interval: secs(30),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    StartScan,
+    StopScan,
+    Broadcast,
+    StopBroadcast,
+}
+
+/// One activity's window within its interval, counted from `origin`.
+#[derive(Debug, Clone, Copy)]
+struct Cycle {
+    window: Duration,
+    interval: Duration,
+    origin: Duration,
+}
+
+impl Cycle {
+    /// The scan and advertising cycles of `profile`, beginning at `origin`.
+    fn of(profile: Profile, origin: Duration) -> (Cycle, Cycle) {
+        let cycle = profile.duty_cycle();
+        let scan = Cycle {
+            window: cycle.scan_window,
+            interval: cycle.scan_interval,
+            origin,
+        };
+        let advertise = Cycle {
+            window: cycle.advertise_window,
+            interval: cycle.advertise_interval,
+            origin,
+        };
+        (scan, advertise)
+    }
+
+    /// Whether the activity is on at `now`, and when that next changes.
+    fn at(&self, now: Duration) -> (bool, Duration) {
+        if self.window.is_zero() {
+            return (false, Duration::MAX);
+        }
+        if self.window >= self.interval {
+            return (true, Duration::MAX);
+        }
+        let elapsed = now.saturating_sub(self.origin).as_nanos();
+        let position = Duration::from_nanos((elapsed % self.interval.as_nanos()) as u64);
+        if position < self.window {
+            (true, now + (self.window - position))
+        } else {
+            (false, now + (self.interval - position))
+        }
+    }
+}
+
+/// The sans-IO core of a `Scheduler`: given the time, the actions due and
+/// when to look again.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    profile: Profile,
+    scan: Cycle,
+    advertise: Cycle,
+    scanning: bool,
+    advertising: bool,
+    next_wake: Duration,
+}
+
+impl Schedule {
+    /// A schedule whose cycles begin at `now`, with the radio still off.
+    pub fn new(profile: Profile, now: Duration) -> Self {
+        let (scan, advertise) = Cycle::of(profile, now);
+        Schedule {
+            profile,
+            scan,
+            advertise,
+            scanning: false,
+            advertising: false,
+            next_wake: now,
+        }
+    }
+
+    pub fn profile(&self) -> Profile {
+        self.profile
+    }
+
+    /// Restarts the cycles under `profile` from `now`. Whatever is running
+    /// and stays on under the new profile is left running.
+    pub fn switch(&mut self, profile: Profile, now: Duration) {
+        self.profile = profile;
+        (self.scan, self.advertise) = Cycle::of(profile, now);
+        self.next_wake = now;
+    }
+
+    /// When `poll` next has something to do.
+    pub fn next_wake(&self) -> Duration {
+        self.next_wake
+    }
+
+    /// Actions that bring the radio to where the schedule wants it at
+    /// `now`. Deadlines missed while nobody polled are not replayed.
+    pub fn poll(&mut self, now: Duration) -> Vec<Action> {
+        let mut actions = Vec::new();
+        let (scan, scan_change) = self.scan.at(now);
+        if scan != self.scanning {
+            self.scanning = scan;
+            actions.push(if scan {
+                Action::StartScan
+            } else {
+                Action::StopScan
+            });
+        }
+        let (advertise, advertise_change) = self.advertise.at(now);
+        if advertise != self.advertising {
+            self.advertising = advertise;
+            actions.push(if advertise {
+                Action::Broadcast
+            } else {
+                Action::StopBroadcast
+            });
+        }
+        self.next_wake = scan_change.min(advertise_change);
+        actions
+    }
+
+    /// Takes back `action`, which the backend failed to carry out at `now`,
+    /// so that the next poll finding it still due tries again. An activity
+    /// that never cycles is polled again one interval later.
+    pub fn failed(&mut self, action: Action, now: Duration) {
+        let cycle = match action {
+            Action::StartScan | Action::StopScan => {
+                self.scanning = action == Action::StopScan;
+                self.scan
+            }
+            Action::Broadcast | Action::StopBroadcast => {
+                self.advertising = action == Action::StopBroadcast;
+                self.advertise
+            }
+        };
+        // a zero interval would retry in a busy loop
+        let retry = now.saturating_add(cycle.interval.max(Duration::from_secs(1)));
+        self.next_wake = self.next_wake.min(retry);
+    }
+
+    /// Actions that turn the radio off for good.
+    pub fn stop(&mut self) -> Vec<Action> {
+        let mut actions = Vec::new();
+        if std::mem::take(&mut self.scanning) {
+            actions.push(Action::StopScan);
+        }
+        if std::mem::take(&mut self.advertising) {
+            actions.push(Action::StopBroadcast);
+        }
+        self.next_wake = Duration::MAX;
+        actions
+    }
+}
+
+enum Command {
+    Switch(Profile),
+}
+
+/// Runs a backend on a schedule. Dropping the scheduler stops scanning and
+/// advertising on the backend.
+pub struct Scheduler {
+    commands: UnboundedSender<Command>,
+    task: JoinHandle<()>,
+}
+
+impl Scheduler {
+    /// Starts scheduling `backend` under `profile`. Take its event stream
+    /// before handing it over.
+    pub fn spawn<B, C>(backend: B, clock: C, profile: Profile) -> Self
+    where
+        B: Discovery + Advertiser + Send + Sync + 'static,
+        <B as Discovery>::Error: fmt::Display,
+        <B as Advertiser>::Error: fmt::Display,
+        C: Clock,
+    {
+        let (commands, received) = mpsc::unbounded();
+        let schedule = Schedule::new(profile, clock.now());
+        let task = tokio::spawn(drive(backend, clock, schedule, received));
+        Scheduler { commands, task }
+    }
+
+    pub fn switch(&self, profile: Profile) {
+        // the task only ends once the scheduler is dropped
+        let _ = self.commands.unbounded_send(Command::Switch(profile));
+    }
+
+    /// Stops the radio and waits until it is off.
+    pub async fn stop(self) {
+        let Scheduler { commands, task } = self;
+        drop(commands);
+        let _ = task.await;
+    }
+}
+
+/// Carries out `action` on the backend, describing why it failed if it did.
+async fn apply<B>(backend: &B, action: Action) -> Result<(), String>
+where
+    B: Discovery + Advertiser,
+    <B as Discovery>::Error: fmt::Display,
+    <B as Advertiser>::Error: fmt::Display,
+{
+    let done = match action {
+        Action::StartScan => backend.start_scan().await.map_err(|err| err.to_string()),
+        Action::StopScan => backend.stop_scan().await.map_err(|err| err.to_string()),
+        Action::Broadcast => backend.broadcast().await.map_err(|err| err.to_string()),
+        Action::StopBroadcast => backend
+            .stop_broadcast()
+            .await
+            .map_err(|err| err.to_string()),
+    };
+    done.map_err(|err| format!("{action:?} failed: {err}"))
+}
+
+async fn drive<B, C>(
+    backend: B,
+    clock: C,
+    mut schedule: Schedule,
+    mut commands: UnboundedReceiver<Command>,
+) where
+    B: Discovery + Advertiser + Send + Sync + 'static,
+    <B as Discovery>::Error: fmt::Display,
+    <B as Advertiser>::Error: fmt::Display,
+    C: Clock,
+{
+    loop {
+        for action in schedule.poll(clock.now()) {
+            if let Err(err) = apply(&backend, action).await {
+                tracing::warn!("scheduled {err}; retrying next cycle");
+                schedule.failed(action, clock.now());
+            }
+        }
+        let wake = clock.sleep_until(schedule.next_wake());
+        match future::select(wake, commands.next()).await {
+            Either::Left(_) => {}
+            Either::Right((Some(Command::Switch(profile)), _)) => {
+                schedule.switch(profile, clock.now());
+            }
+            Either::Right((None, _)) => {
+                for action in schedule.stop() {
+                    if let Err(err) = apply(&backend, action).await {
+                        tracing::warn!("stopping the schedule: {err}");
+                    }
+                }
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::run;
+    use core::{
+        discovery::DiscoveryEvent,
+        types::{BoxFutureResponse, BoxStreamResponse},
+    };
+    use futures::stream;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    /// Actions over the first `until` seconds, polling every 500 ms.
+    fn timeline(schedule: &mut Schedule, from: u64, until: u64) -> Vec<(u64, Action)> {
+        let mut seen = Vec::new();
+        for step in 2 * from..=2 * until {
+            let now = Duration::from_millis(500 * step);
+            for action in schedule.poll(now) {
+                seen.push((now.as_millis() as u64, action));
+            }
+        }
+        seen
+    }
+
+    #[test]
+    fn balanced_profile_duty_cycles_both_activities() {
+        let mut schedule = Schedule::new(Profile::Balanced, Duration::ZERO);
+        assert_eq!(
+            timeline(&mut schedule, 0, 12),
+            [
+                (0, Action::StartScan),
+                (0, Action::Broadcast),
+                (1_000, Action::StopBroadcast),
+                (2_000, Action::StopScan),
+                (5_000, Action::Broadcast),
+                (6_000, Action::StopBroadcast),
+                (10_000, Action::StartScan),
+                (10_000, Action::Broadcast),
+                (11_000, Action::StopBroadcast),
+                (12_000, Action::StopScan),
+            ]
+        );
+        assert_eq!(schedule.next_wake(), secs(15));
+    }
+
+    #[test]
+    fn switching_profiles_only_changes_what_differs() {
+        let mut schedule = Schedule::new(Profile::Background, Duration::ZERO);
+        assert_eq!(
+            schedule.poll(Duration::ZERO),
+            [Action::StartScan, Action::Broadcast]
+        );
+        assert_eq!(schedule.poll(secs(1)), [Action::StopBroadcast]);
+        assert_eq!(schedule.next_wake(), secs(2));
+
+        // the send screen opens: scanning just stays on
+        schedule.switch(Profile::Aggressive, secs(1));
+        assert_eq!(schedule.poll(secs(1)), [Action::Broadcast]);
+        assert_eq!(schedule.next_wake(), Duration::MAX);
+        assert_eq!(schedule.poll(secs(100)), []);
+
+        schedule.switch(Profile::Background, secs(100));
+        assert_eq!(schedule.poll(secs(100)), []);
+        assert_eq!(
+            schedule.poll(secs(102)),
+            [Action::StopScan, Action::StopBroadcast]
+        );
+        assert_eq!(schedule.stop(), []);
+    }
+
+    #[test]
+    fn failed_actions_are_retried_while_still_due() {
+        let mut schedule = Schedule::new(Profile::Balanced, Duration::ZERO);
+        assert_eq!(
+            schedule.poll(Duration::ZERO),
+            [Action::StartScan, Action::Broadcast]
+        );
+        schedule.failed(Action::StartScan, Duration::ZERO);
+        assert_eq!(
+            schedule.poll(secs(1)),
+            [Action::StartScan, Action::StopBroadcast]
+        );
+        assert_eq!(schedule.poll(secs(2)), [Action::StopScan]);
+        schedule.failed(Action::StopScan, secs(2));
+        assert_eq!(
+            schedule.poll(secs(5)),
+            [Action::StopScan, Action::Broadcast]
+        );
+
+        // a radio that is always on is retried one interval later
+        schedule.switch(Profile::Aggressive, secs(10));
+        assert_eq!(schedule.poll(secs(10)), [Action::StartScan]);
+        schedule.failed(Action::Broadcast, secs(10));
+        assert_eq!(schedule.next_wake(), secs(11));
+        assert_eq!(schedule.poll(secs(11)), [Action::Broadcast]);
+    }
+
+    #[test]
+    fn simulated_clock_wakes_sleepers_only_when_advanced() {
+        let clock = SimulatedClock::new();
+        let mut early = clock.sleep_until(secs(1));
+        let mut late = clock.sleep_until(secs(5));
+        assert!((&mut early).now_or_never().is_none());
+
+        clock.advance(secs(2));
+        assert_eq!(clock.now(), secs(2));
+        assert!(early.now_or_never().is_some());
+        assert!((&mut late).now_or_never().is_none());
+        clock.advance(secs(3));
+        assert!(late.now_or_never().is_some());
+        assert!(clock.sleep_until(secs(4)).now_or_never().is_some());
+    }
+
+    /// Records what the scheduler asks for, and when, failing the actions
+    /// it is told to fail once each.
+    #[derive(Clone)]
+    struct Recorder {
+        clock: SimulatedClock,
+        log: Arc<Mutex<Vec<(u64, Action)>>>,
+        failing: Arc<Mutex<Vec<Action>>>,
+    }
+
+    impl Recorder {
+        fn new(clock: &SimulatedClock) -> Self {
+            Recorder {
+                clock: clock.clone(),
+                log: Arc::default(),
+                failing: Arc::default(),
+            }
+        }
+
+        fn record(&self, action: Action) -> BoxFutureResponse<(), String> {
+            let at = self.clock.now().as_secs();
+            self.log.lock().unwrap().push((at, action));
+            let mut failing = self.failing.lock().unwrap();
+            let failed = match failing.iter().position(|failing| *failing == action) {
+                Some(at) => Err(format!("radio refused {:?}", failing.remove(at))),
+                None => Ok(()),
+            };
+            Box::pin(future::ready(failed))
+        }
+    }
+
+    impl Discovery for Recorder {
+        type Error = String;
+        type DiscoveryEvent = DiscoveryEvent;
+
+        fn start_scan(&self) -> BoxFutureResponse<(), Self::Error> {
+            self.record(Action::StartScan)
+        }
+
+        fn stop_scan(&self) -> BoxFutureResponse<(), Self::Error> {
+            self.record(Action::StopScan)
+        }
+
+        fn poll_events(&mut self) -> BoxStreamResponse<DiscoveryEvent> {
+            Box::pin(stream::empty())
+        }
+    }
+
+    impl Advertiser for Recorder {
+        type Error = String;
+
+        fn broadcast(&self) -> BoxFutureResponse<(), Self::Error> {
+            self.record(Action::Broadcast)
+        }
+
+        fn stop_broadcast(&self) -> BoxFutureResponse<(), Self::Error> {
+            self.record(Action::StopBroadcast)
+        }
+    }
+
+    /// Lets the scheduler task catch up with the clock.
+    async fn settle() {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[test]
+    fn scheduler_follows_the_clock_and_stops_the_radio() {
+        run(async {
+            let clock = SimulatedClock::new();
+            let recorder = Recorder::new(&clock);
+            let scheduler = Scheduler::spawn(recorder.clone(), clock.clone(), Profile::Background);
+            settle().await;
+            for _ in 0..3 {
+                clock.advance(secs(1));
+                settle().await;
+            }
+            scheduler.switch(Profile::Aggressive);
+            settle().await;
+            clock.advance(secs(10));
+            settle().await;
+            scheduler.stop().await;
+
+            assert_eq!(
+                *recorder.log.lock().unwrap(),
+                [
+                    (0, Action::StartScan),
+                    (0, Action::Broadcast),
+                    (1, Action::StopBroadcast),
+                    (2, Action::StopScan),
+                    (3, Action::StartScan),
+                    (3, Action::Broadcast),
+                    (13, Action::StopScan),
+                    (13, Action::StopBroadcast),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn scheduler_retries_what_the_backend_refused() {
+        run(async {
+            let clock = SimulatedClock::new();
+            let recorder = Recorder::new(&clock);
+            recorder.failing.lock().unwrap().push(Action::StartScan);
+            let scheduler = Scheduler::spawn(recorder.clone(), clock.clone(), Profile::Aggressive);
+            settle().await;
+            clock.advance(secs(1));
+            settle().await;
+            scheduler.stop().await;
+
+            assert_eq!(
+                *recorder.log.lock().unwrap(),
+                [
+                    (0, Action::StartScan),
+                    (0, Action::Broadcast),
+                    (1, Action::StartScan),
+                    (1, Action::StopScan),
+                    (1, Action::StopBroadcast),
+                ]
+            );
+        });
+    }
+}
@@ -0,0 +1,59 @@
+use core::{discovery::DiscoveryEvent, identity::PeerInfo, request::PaymentRequest};
+use std::{collections::HashMap, hash::Hash, time::Duration};
+
+use tokio::time::Instant;
+
+/// Peer table shared by the backends that only learn about peers from
+/// repeated sightings: reports first sightings, payment requests not
+/// advertised by the peer before, and peers that went quiet for longer than
+/// the inactivity timeout.
+pub(crate) struct PeerTracker<K> {
+    inactivity_timeout: Duration,
+    peers: HashMap<K, (PeerInfo, Option<PaymentRequest>, Instant)>,
+}
+
+impl<K: Hash + Eq + Clone> PeerTracker<K> {
+    pub(crate) fn new(inactivity_timeout: Duration) -> Self {
+        PeerTracker {
+            inactivity_timeout,
+            peers: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn observe(
+        &mut self,
+        key: K,
+        info: PeerInfo,
+        request: Option<PaymentRequest>,
+        now: Instant,
+    ) -> Vec<DiscoveryEvent> {
+        let mut events = Vec::new();
+        let seen = match self.peers.insert(key, (info.clone(), request.clone(), now)) {
+            Some((_, seen, _)) => seen,
+            None => {
+                events.push(DiscoveryEvent::PeerDiscovered(info));
+                None
+            }
+        };
+        if let Some(request) = request
+            && seen.as_ref() != Some(&request)
+        {
+            events.push(DiscoveryEvent::RequestAdvertised(Box::new(request)));
+        }
+        events
+    }
+
+    pub(crate) fn sweep(&mut self, now: Instant) -> Vec<DiscoveryEvent> {
+        let timeout = self.inactivity_timeout;
+        let lost: Vec<K> = self
+            .peers
+            .iter()
+            .filter(|(_, (_, _, last_seen))| now.duration_since(*last_seen) >= timeout)
+            .map(|(key, _)| key.clone())
+            .collect();
+        lost.into_iter()
+            .filter_map(|key| self.peers.remove(&key))
+            .map(|(info, _, _)| DiscoveryEvent::PeerLost(info))
+            .collect()
+    }
+}
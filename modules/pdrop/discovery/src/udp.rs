@@ -0,0 +1,359 @@
+//! Lightweight discovery for relayers and kiosks: every node multicasts a
+//! signed beacon at a fixed interval and listens for everyone else's.
+//! Peers whose beacons stop are expired.
+
+use core::{
+    advertisement::{Advertisement, AdvertisementError, Beacon},
+    discovery::{Advertiser, Discovery, DiscoveryEvent},
+    identity::{DeviceIdentity, PeerInfo, TransportAddress},
+    request::PaymentRequest,
+    types::{BoxFutureResponse, BoxStreamResponse, PeerId},
+};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{net::UdpSocket, task::JoinHandle, time::Instant};
+
+use crate::tracker::PeerTracker;
+
+/// Larger datagrams are not pdrop beacons.
+const MAX_BEACON_LEN: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct UdpConfig {
+    /// Multicast group and port beacons are sent to and heard on.
+    pub group: SocketAddrV4,
+    /// Local interface the group is joined and sent on.
+    pub interface: Ipv4Addr,
+    pub beacon_interval: Duration,
+    /// A peer not heard from for this long is reported as lost.
+    pub expiry: Duration,
+}
+
+impl Default for UdpConfig {
+    fn default() -> Self {
+        UdpConfig {
+            group: SocketAddrV4::new(Ipv4Addr::new(239, 255, 80, 68), 47_470),
+            interface: Ipv4Addr::UNSPECIFIED,
+            beacon_interval: Duration::from_secs(2),
+            expiry: Duration::from_secs(7),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum UdpDiscoveryError {
+    SocketError(io::Error),
+    AdvertisementError(AdvertisementError),
+}
+
+impl From<io::Error> for UdpDiscoveryError {
+    fn from(err: io::Error) -> Self {
+        UdpDiscoveryError::SocketError(err)
+    }
+}
+
+impl From<AdvertisementError> for UdpDiscoveryError {
+    fn from(err: AdvertisementError) -> Self {
+        UdpDiscoveryError::AdvertisementError(err)
+    }
+}
+
+pub struct UdpDiscovery {
+    local: PeerInfo,
+    payment_request: Arc<Mutex<Option<PaymentRequest>>>,
+    identity: DeviceIdentity,
+    config: UdpConfig,
+    inbox: Arc<Mutex<UnboundedSender<DiscoveryEvent>>>,
+    events: Option<UnboundedReceiver<DiscoveryEvent>>,
+    listener: Arc<Mutex<Option<JoinHandle<()>>>>,
+    beacon: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+impl UdpDiscovery {
+    /// `local` is advertised under the id of `identity`, whatever its own.
+    /// Socket addresses with an unspecified ip are completed by receivers
+    /// with the address the beacon came from.
+    pub fn new(mut local: PeerInfo, identity: DeviceIdentity, config: UdpConfig) -> Self {
+        local.id = identity.peer_id();
+        let (inbox, events) = mpsc::unbounded();
+        UdpDiscovery {
+            local,
+            payment_request: Arc::default(),
+            identity,
+            config,
+            inbox: Arc::new(Mutex::new(inbox)),
+            events: Some(events),
+            listener: Arc::default(),
+            beacon: Arc::default(),
+        }
+    }
+}
+
+impl Drop for UdpDiscovery {
+    fn drop(&mut self) {
+        for task in [&self.listener, &self.beacon] {
+            if let Some(task) = task.lock().unwrap().take() {
+                task.abort();
+            }
+        }
+    }
+}
+
+/// Group member socket. Address and port reuse let several nodes share the
+/// group port on one host.
+fn listener_socket(config: &UdpConfig) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    let bind = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, config.group.port());
+    socket.bind(&SocketAddr::V4(bind).into())?;
+    socket.join_multicast_v4(config.group.ip(), &config.interface)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+fn sender_socket(config: &UdpConfig) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_multicast_if_v4(&config.interface)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_multicast_ttl_v4(1)?;
+    let bind = SocketAddrV4::new(config.interface, 0);
+    socket.bind(&SocketAddr::V4(bind).into())?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Fills in what only the receiver knows about a beacon's sender.
+fn received_peer(mut info: PeerInfo, source: IpAddr) -> PeerInfo {
+    for address in &mut info.addresses {
+        if let TransportAddress::Socket(socket) = address
+            && socket.ip().is_unspecified()
+        {
+            socket.set_ip(source);
+        }
+    }
+    info.last_seen = Some(SystemTime::now());
+    info
+}
+
+async fn listen(
+    socket: UdpSocket,
+    own_id: PeerId,
+    config: UdpConfig,
+    inbox: Arc<Mutex<UnboundedSender<DiscoveryEvent>>>,
+) {
+    let mut tracker = PeerTracker::new(config.expiry);
+    let mut sweep = tokio::time::interval(config.beacon_interval);
+    let mut buf = [0u8; MAX_BEACON_LEN];
+    loop {
+        let events = tokio::select! {
+            received = socket.recv_from(&mut buf) => {
+                let Ok((len, source)) = received else { continue };
+                // anything that is not a valid signed beacon is noise
+                let Ok(beacon) = Beacon::decode(&buf[..len]) else { continue };
+                let Advertisement { info, payment_request } = beacon.advertisement;
+                let info = received_peer(info, source.ip());
+                if info.id == own_id {
+                    continue;
+                }
+                tracker.observe(info.id, info, payment_request, Instant::now())
+            }
+            _ = sweep.tick() => tracker.sweep(Instant::now()),
+        };
+        let inbox = inbox.lock().unwrap();
+        for event in events {
+            let _ = inbox.unbounded_send(event);
+        }
+    }
+}
+
+async fn send_beacons(socket: UdpSocket, beacon: Vec<u8>, config: UdpConfig) {
+    let mut interval = tokio::time::interval(config.beacon_interval);
+    loop {
+        interval.tick().await;
+        // a missed beacon is covered by the next one
+        let _ = socket.send_to(&beacon, config.group).await;
+    }
+}
+
+/// Replaces the task in `slot`, stopping the previous one.
+fn replace_task(slot: &Mutex<Option<JoinHandle<()>>>, task: Option<JoinHandle<()>>) {
+    if let Some(previous) = std::mem::replace(&mut *slot.lock().unwrap(), task) {
+        previous.abort();
+    }
+}
+
+impl Discovery for UdpDiscovery {
+    type Error = UdpDiscoveryError;
+    type DiscoveryEvent = DiscoveryEvent;
+
+    fn start_scan(&self) -> BoxFutureResponse<(), Self::Error> {
+        let (own_id, config) = (self.local.id, self.config.clone());
+        let (inbox, listener) = (self.inbox.clone(), self.listener.clone());
+        Box::pin(async move {
+            let socket = listener_socket(&config)?;
+            let task = tokio::spawn(listen(socket, own_id, config, inbox));
+            replace_task(&listener, Some(task));
+            Ok(())
+        })
+    }
+
+    fn stop_scan(&self) -> BoxFutureResponse<(), Self::Error> {
+        let listener = self.listener.clone();
+        Box::pin(async move {
+            replace_task(&listener, None);
+            Ok(())
+        })
+    }
+
+    /// Events of this node. Each call takes over from the previous stream.
+    fn poll_events(&mut self) -> BoxStreamResponse<DiscoveryEvent> {
+        let events = self.events.take().unwrap_or_else(|| {
+            let (inbox, events) = mpsc::unbounded();
+            *self.inbox.lock().unwrap() = inbox;
+            events
+        });
+        Box::pin(events)
+    }
+}
+
+impl Advertiser for UdpDiscovery {
+    type Error = UdpDiscoveryError;
+
+    fn broadcast(&self) -> BoxFutureResponse<(), Self::Error> {
+        let advertisement = Advertisement {
+            info: self.local.clone(),
+            payment_request: self.payment_request.lock().unwrap().clone(),
+        };
+        let beacon = Beacon::encode(&advertisement, self.identity.signing_key());
+        let (config, slot) = (self.config.clone(), self.beacon.clone());
+        Box::pin(async move {
+            let beacon = beacon?;
+            let socket = sender_socket(&config)?;
+            let task = tokio::spawn(send_beacons(socket, beacon, config));
+            replace_task(&slot, Some(task));
+            Ok(())
+        })
+    }
+
+    fn stop_broadcast(&self) -> BoxFutureResponse<(), Self::Error> {
+        let slot = self.beacon.clone();
+        Box::pin(async move {
+            replace_task(&slot, None);
+            Ok(())
+        })
+    }
+
+    fn set_payment_request(
+        &self,
+        request: Option<PaymentRequest>,
+    ) -> BoxFutureResponse<(), Self::Error> {
+        *self.payment_request.lock().unwrap() = request;
+        Box::pin(async { Ok(()) })
+    }
+}
+
+impl core::discovery::DiscoveryAdvertiser for UdpDiscovery {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::run_live;
+    use ed25519_dalek::SigningKey;
+    use futures::StreamExt;
+
+    fn config(port: u16) -> UdpConfig {
+        UdpConfig {
+            group: SocketAddrV4::new(Ipv4Addr::new(239, 255, 80, 68), port),
+            interface: Ipv4Addr::LOCALHOST,
+            beacon_interval: Duration::from_millis(100),
+            expiry: Duration::from_millis(500),
+        }
+    }
+
+    fn node(name: &str, seed: u8, port: u16) -> UdpDiscovery {
+        let identity = DeviceIdentity::from_secret_keys([seed; 32], [seed; 32], [seed; 32]);
+        let local = PeerInfo {
+            display_name: Some(name.to_uppercase()),
+            addresses: vec![TransportAddress::Socket("0.0.0.0:7070".parse().unwrap())],
+            ..PeerInfo::new(identity.peer_id())
+        };
+        UdpDiscovery::new(local, identity, config(port))
+    }
+
+    async fn next_event(events: &mut BoxStreamResponse<DiscoveryEvent>) -> DiscoveryEvent {
+        tokio::time::timeout(Duration::from_secs(5), events.next())
+            .await
+            .expect("no beacon event before the deadline")
+            .unwrap()
+    }
+
+    #[test]
+    fn beacons_are_discovered_and_expire_when_they_stop() {
+        run_live(async {
+            let alice = node("alice", 1, 47_471);
+            let mut bob = node("bob", 2, 47_471);
+            let mut bob_events = bob.poll_events();
+
+            bob.start_scan().await.unwrap();
+            bob.broadcast().await.unwrap();
+            alice.broadcast().await.unwrap();
+
+            let DiscoveryEvent::PeerDiscovered(found) = next_event(&mut bob_events).await else {
+                panic!("alice was not discovered first");
+            };
+            assert_eq!(found.id, alice.local.id);
+            assert_eq!(found.display_name.as_deref(), Some("ALICE"));
+            assert_eq!(
+                found.addresses,
+                [TransportAddress::Socket("127.0.0.1:7070".parse().unwrap())]
+            );
+            assert!(found.last_seen.is_some());
+
+            alice.stop_broadcast().await.unwrap();
+            let stopped = Instant::now();
+            assert_eq!(
+                next_event(&mut bob_events).await,
+                DiscoveryEvent::PeerLost(found)
+            );
+            assert!(stopped.elapsed() >= Duration::from_millis(300));
+        });
+    }
+
+    #[test]
+    fn unsigned_datagrams_are_ignored() {
+        run_live(async {
+            let mut bob = node("bob", 2, 47_472);
+            let mut bob_events = bob.poll_events();
+            bob.start_scan().await.unwrap();
+
+            let mallory = SigningKey::from_bytes(&[3; 32]);
+            let forged = Advertisement::new(PeerInfo::new(PeerId::from_public_key(
+                mallory.verifying_key().as_bytes(),
+            )));
+            let socket = sender_socket(&config(47_472)).unwrap();
+            let mut beacon = Beacon::encode(&forged, &mallory).unwrap();
+            *beacon.last_mut().unwrap() ^= 1;
+            // alice's id under mallory's signature
+            let impostor = Advertisement::new(node("alice", 1, 47_472).local.clone());
+            let impersonation = Beacon::encode(&impostor, &mallory).unwrap();
+            for datagram in [forged.encode().unwrap(), beacon, impersonation] {
+                socket
+                    .send_to(&datagram, config(47_472).group)
+                    .await
+                    .unwrap();
+            }
+
+            let next = tokio::time::timeout(Duration::from_millis(500), bob_events.next()).await;
+            assert!(next.is_err(), "unexpected event {next:?}");
+        });
+    }
+}
@@ -0,0 +1,8 @@
+//! Ties discovery backends and transports together into note drops between
+//! nearby devices.
+
+pub mod orchestrator;
+#[cfg(test)]
+mod testing;
+
+pub use orchestrator::Orchestrator;
@@ -0,0 +1,1570 @@
+//! The orchestrator runs any number of discovery backends and transports
+//! as one: their events are merged into a single peer table, and notes are
+//! dropped to peers from it over whichever transport reaches them first.
+//!
+//! A drop is one connection: a Noise session, then the exchange of
+//! `core::protocol`. The sender offers the note, the recipient decides
+//! through `Orchestrator::offers`, and only once it accepts does the sealed
+//! `NoteDrop` follow. The recipient hands it to `Orchestrator::incoming`
+//! and records it in its `TransferHistory` before answering with a signed
+//! `DeliveryReceipt`, so it never signs for a note it lost; the sender
+//! records the drop with the receipt as proof.
+//!
+//! Since a sender could still spend a note it dropped, the recipient refuses
+//! notes its cached `NullifierSet` knows are spent, the sender locks every
+//! note whose payload it sent, and `Orchestrator::sync_nullifiers` reports
+//! received notes spent by someone else since.
+//!
+//! Payment requests run the other way: a device advertises or sends a
+//! signed `PaymentRequest`, and the payer answers it with a drop naming the
+//! request, which the requesting device accepts without asking again.
+
+use core::{
+    contacts::ContactBook,
+    discovery::{Advertiser, Discovery, DiscoveryAdvertiser, DiscoveryEvent},
+    history::{Direction, Transfer, TransferHistory},
+    identity::{DeviceIdentity, PeerInfo},
+    note::{Fr, Note},
+    payload::{MAX_PAYLOAD_LEN, NoteDrop, PayloadError},
+    protocol::{Message, Offer, ProtocolError, RejectReason},
+    receipt::DeliveryReceipt,
+    request::{PaymentRequest, RequestError, RequestId},
+    rotation::RotationConfig,
+    session::{Handshake, Session, SessionConfig, SessionError, handshake},
+    spend::{Conflict, NullifierSet, SpendLocks},
+    transport::{Connection, Transport},
+    types::{BoxFutureResponse, BoxStreamResponse, PeerId},
+};
+use std::{
+    collections::{BTreeSet, HashMap},
+    error::Error,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use futures::{
+    SinkExt, StreamExt,
+    channel::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+};
+use tokio::task::JoinHandle;
+use x25519_dalek::PublicKey;
+
+pub type BoxError = Box<dyn Error + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Idle,
+    /// Announcing this device and accepting drops.
+    Advertising,
+    /// Looking for peers to drop to.
+    Scanning,
+    /// A drop is in progress, in either direction.
+    Connected,
+}
+
+#[derive(Debug)]
+pub enum OrchestratorError {
+    /// The peer is not in the peer table.
+    UnknownPeer(PeerId),
+    NoTransport,
+    BackendError(BoxError),
+    TransportError(BoxError),
+    SessionError(SessionError),
+    PayloadError(PayloadError),
+    ProtocolError(ProtocolError),
+    /// The peer sent a message out of turn.
+    UnexpectedMessage,
+    /// The recipient turned the offer down, or let it lapse.
+    Rejected(RejectReason),
+    /// The payload is not the note that was offered.
+    OfferMismatch,
+    InvalidRequest(RequestError),
+    /// The note does not pay the request it is meant to answer.
+    RequestMismatch,
+    /// The note was already handed off, so it is locked.
+    NoteLocked,
+    /// The peer dialed proved to be someone else.
+    UnexpectedPeer(PeerId),
+    /// The recipient did not return a valid receipt for the note it was
+    /// sent.
+    NotAcknowledged,
+    Timeout,
+}
+
+impl fmt::Display for OrchestratorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OrchestratorError::UnknownPeer(id) => write!(f, "unknown peer {id}"),
+            OrchestratorError::NoTransport => write!(f, "no transport is configured"),
+            OrchestratorError::BackendError(err) => write!(f, "discovery backend failed: {err}"),
+            OrchestratorError::TransportError(err) => write!(f, "transport failed: {err}"),
+            OrchestratorError::SessionError(err) => write!(f, "session failed: {err}"),
+            OrchestratorError::PayloadError(err) => write!(f, "invalid payload: {err}"),
+            OrchestratorError::ProtocolError(err) => write!(f, "invalid message: {err}"),
+            OrchestratorError::UnexpectedMessage => write!(f, "peer sent a message out of turn"),
+            OrchestratorError::Rejected(reason) => write!(f, "offer rejected: {reason}"),
+            OrchestratorError::OfferMismatch => write!(f, "payload does not match the offer"),
+            OrchestratorError::InvalidRequest(err) => write!(f, "invalid payment request: {err}"),
+            OrchestratorError::RequestMismatch => write!(f, "note does not pay the request"),
+            OrchestratorError::NoteLocked => write!(f, "note was already handed off"),
+            OrchestratorError::UnexpectedPeer(id) => {
+                write!(f, "connected to {id} instead of the intended peer")
+            }
+            OrchestratorError::NotAcknowledged => write!(f, "peer did not acknowledge the note"),
+            OrchestratorError::Timeout => write!(f, "drop timed out"),
+        }
+    }
+}
+
+impl std::error::Error for OrchestratorError {}
+
+impl From<SessionError> for OrchestratorError {
+    fn from(err: SessionError) -> Self {
+        OrchestratorError::SessionError(err)
+    }
+}
+
+impl From<PayloadError> for OrchestratorError {
+    fn from(err: PayloadError) -> Self {
+        OrchestratorError::PayloadError(err)
+    }
+}
+
+impl From<ProtocolError> for OrchestratorError {
+    fn from(err: ProtocolError) -> Self {
+        OrchestratorError::ProtocolError(err)
+    }
+}
+
+impl From<RequestError> for OrchestratorError {
+    fn from(err: RequestError) -> Self {
+        OrchestratorError::InvalidRequest(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct OrchestratorConfig {
+    pub session: SessionConfig,
+    /// Time a whole drop may take, handshake included, besides waiting for
+    /// the recipient to decide.
+    pub drop_timeout: Duration,
+    /// Time a recipient has to accept an offer before it lapses.
+    pub offer_timeout: Duration,
+    /// Epochs contacts rotate their advertisement tokens at, to recognise
+    /// them by.
+    pub rotation: RotationConfig,
+}
+
+impl Default for OrchestratorConfig {
+    fn default() -> Self {
+        OrchestratorConfig {
+            session: SessionConfig::default(),
+            drop_timeout: Duration::from_secs(30),
+            offer_timeout: Duration::from_secs(60),
+            rotation: RotationConfig::default(),
+        }
+    }
+}
+
+/// A discovery backend with its errors boxed, so backends of different
+/// types can sit side by side.
+trait Backend: Send + Sync {
+    fn start_scan(&self) -> BoxFutureResponse<(), BoxError>;
+    fn stop_scan(&self) -> BoxFutureResponse<(), BoxError>;
+    fn broadcast(&self) -> BoxFutureResponse<(), BoxError>;
+    fn stop_broadcast(&self) -> BoxFutureResponse<(), BoxError>;
+    fn set_payment_request(
+        &self,
+        request: Option<PaymentRequest>,
+    ) -> BoxFutureResponse<(), BoxError>;
+}
+
+fn boxed<E: Error + Send + Sync + 'static>(
+    future: BoxFutureResponse<(), E>,
+) -> BoxFutureResponse<(), BoxError> {
+    Box::pin(async move { future.await.map_err(BoxError::from) })
+}
+
+impl<B> Backend for B
+where
+    B: DiscoveryAdvertiser + Send + Sync,
+    <B as Discovery>::Error: Error + Send + Sync + 'static,
+    <B as Advertiser>::Error: Error + Send + Sync + 'static,
+{
+    fn start_scan(&self) -> BoxFutureResponse<(), BoxError> {
+        boxed(Discovery::start_scan(self))
+    }
+
+    fn stop_scan(&self) -> BoxFutureResponse<(), BoxError> {
+        boxed(Discovery::stop_scan(self))
+    }
+
+    fn broadcast(&self) -> BoxFutureResponse<(), BoxError> {
+        boxed(Advertiser::broadcast(self))
+    }
+
+    fn stop_broadcast(&self) -> BoxFutureResponse<(), BoxError> {
+        boxed(Advertiser::stop_broadcast(self))
+    }
+
+    fn set_payment_request(
+        &self,
+        request: Option<PaymentRequest>,
+    ) -> BoxFutureResponse<(), BoxError> {
+        boxed(Advertiser::set_payment_request(self, request))
+    }
+}
+
+/// A transport with its errors boxed.
+trait Dialer: Send + Sync {
+    fn dial(&self, peer: &PeerInfo) -> BoxFutureResponse<Connection, BoxError>;
+    fn listen(&self) -> BoxFutureResponse<BoxStreamResponse<Connection>, BoxError>;
+}
+
+impl<T> Dialer for T
+where
+    T: Transport + Send + Sync,
+    T::Error: Error + Send + Sync + 'static,
+{
+    fn dial(&self, peer: &PeerInfo) -> BoxFutureResponse<Connection, BoxError> {
+        let dial = Transport::dial(self, peer);
+        Box::pin(async move { dial.await.map_err(BoxError::from) })
+    }
+
+    fn listen(&self) -> BoxFutureResponse<BoxStreamResponse<Connection>, BoxError> {
+        let listen = Transport::listen(self);
+        Box::pin(async move { listen.await.map_err(BoxError::from) })
+    }
+}
+
+/// Peers as seen through all backends together. A peer is discovered when
+/// the first backend sees it and lost when the last one loses it; what the
+/// backends report about it in between is merged.
+#[derive(Default)]
+struct PeerTable {
+    peers: HashMap<PeerId, Entry>,
+    /// Real ids of peers sighted under provisional ones, by provisional id.
+    aliases: HashMap<PeerId, PeerId>,
+}
+
+struct Entry {
+    info: PeerInfo,
+    seen_by: BTreeSet<usize>,
+}
+
+fn merge(into: &mut PeerInfo, from: PeerInfo) {
+    into.display_name = from.display_name.or(into.display_name.take());
+    if !from.protocol_versions.is_empty() {
+        into.protocol_versions = from.protocol_versions;
+    }
+    into.capabilities |= from.capabilities;
+    into.rssi = from.rssi.or(into.rssi);
+    into.tx_power = from.tx_power.or(into.tx_power);
+    into.last_seen = from.last_seen.or(into.last_seen);
+    for address in from.addresses {
+        if !into.addresses.contains(&address) {
+            into.addresses.push(address);
+        }
+    }
+}
+
+impl PeerTable {
+    /// The entry of the peer known as `id`, be it a provisional id of it.
+    fn get(&self, id: &PeerId) -> Option<&Entry> {
+        self.peers.get(self.aliases.get(id).unwrap_or(id))
+    }
+
+    /// Applies an event from backend `backend`, returning the merged event
+    /// to pass on, if any. Peers sighted under a provisional id whose token
+    /// a contact's rotation secret derives are taken to be that contact.
+    fn apply(
+        &mut self,
+        backend: usize,
+        event: DiscoveryEvent,
+        contacts: &ContactBook,
+        rotation: &RotationConfig,
+    ) -> Option<DiscoveryEvent> {
+        match event {
+            DiscoveryEvent::PeerDiscovered(mut info) => {
+                let contact = (info.provisional && !self.aliases.contains_key(&info.id))
+                    .then(|| contacts.resolve(&info, rotation))
+                    .flatten();
+                if let Some(contact) = contact {
+                    self.aliases.insert(info.id, contact.peer_id());
+                    if info.display_name.is_none() && !contact.nickname.is_empty() {
+                        info.display_name = Some(contact.nickname.clone());
+                    }
+                }
+                if let Some(&id) = self.aliases.get(&info.id) {
+                    info.id = id;
+                    info.provisional = false;
+                }
+                self.discovered(backend, info)
+            }
+            DiscoveryEvent::PeerLost(info) => {
+                let id = self.aliases.get(&info.id).copied().unwrap_or(info.id);
+                let entry = self.peers.get_mut(&id)?;
+                entry.seen_by.remove(&backend);
+                if !entry.seen_by.is_empty() {
+                    return None;
+                }
+                let entry = self.peers.remove(&id)?;
+                self.aliases.retain(|_, real| *real != id);
+                Some(DiscoveryEvent::PeerLost(entry.info))
+            }
+            DiscoveryEvent::RequestAdvertised(request) => self
+                .peers
+                .contains_key(&request.requester_id())
+                .then_some(DiscoveryEvent::RequestAdvertised(request)),
+        }
+    }
+
+    fn discovered(&mut self, backend: usize, info: PeerInfo) -> Option<DiscoveryEvent> {
+        match self.peers.get_mut(&info.id) {
+            Some(entry) => {
+                entry.seen_by.insert(backend);
+                merge(&mut entry.info, info);
+                None
+            }
+            None => {
+                let entry = Entry {
+                    info: info.clone(),
+                    seen_by: BTreeSet::from([backend]),
+                };
+                self.peers.insert(info.id, entry);
+                Some(DiscoveryEvent::PeerDiscovered(info))
+            }
+        }
+    }
+
+    /// Binds the peer sighted as `provisional` to the id it proved, `id`,
+    /// returning the events that make of it: the provisional peer is lost,
+    /// and the real one discovered unless it was known already.
+    fn bind(&mut self, provisional: PeerId, id: PeerId) -> Vec<DiscoveryEvent> {
+        let Some(Entry { mut info, seen_by }) = self.peers.remove(&provisional) else {
+            return Vec::new();
+        };
+        self.aliases.insert(provisional, id);
+        let mut events = vec![DiscoveryEvent::PeerLost(info.clone())];
+        info.id = id;
+        info.provisional = false;
+        match self.peers.get_mut(&id) {
+            Some(known) => {
+                known.seen_by.extend(seen_by);
+                merge(&mut known.info, info);
+            }
+            None => {
+                events.push(DiscoveryEvent::PeerDiscovered(info.clone()));
+                self.peers.insert(id, Entry { info, seen_by });
+            }
+        }
+        events
+    }
+}
+
+/// An offer awaiting this device's decision. Dropping it declines the
+/// offer; leaving it undecided past `offer_timeout` lets it lapse.
+#[derive(Debug)]
+pub struct IncomingOffer {
+    pub offer: Offer,
+    decision: oneshot::Sender<bool>,
+}
+
+impl IncomingOffer {
+    /// Accepts the offer; the note arrives on `Orchestrator::incoming`.
+    pub fn accept(self) {
+        let _ = self.decision.send(true);
+    }
+
+    pub fn reject(self) {
+        let _ = self.decision.send(false);
+    }
+}
+
+struct Shared {
+    peers: PeerTable,
+    events: UnboundedSender<DiscoveryEvent>,
+    offers: UnboundedSender<IncomingOffer>,
+    incoming: UnboundedSender<NoteDrop>,
+    requests: UnboundedSender<PaymentRequest>,
+    /// Requests this device made and that are still open, by id. Offers
+    /// paying one of them are accepted without asking.
+    issued: HashMap<RequestId, PaymentRequest>,
+    /// The request announced through the backends.
+    advertised: Option<RequestId>,
+    history: TransferHistory,
+    contacts: ContactBook,
+    nullifiers: NullifierSet,
+    locks: SpendLocks,
+    mode: State,
+    connections: usize,
+}
+
+/// Counts a connection towards `State::Connected` while alive.
+struct ConnectionGuard(Arc<Mutex<Shared>>);
+
+impl ConnectionGuard {
+    fn new(shared: &Arc<Mutex<Shared>>) -> Self {
+        shared.lock().unwrap().connections += 1;
+        ConnectionGuard(shared.clone())
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.lock().unwrap().connections -= 1;
+    }
+}
+
+pub struct Orchestrator {
+    identity: DeviceIdentity,
+    config: OrchestratorConfig,
+    backends: Vec<Arc<dyn Backend>>,
+    transports: Vec<Arc<dyn Dialer>>,
+    shared: Arc<Mutex<Shared>>,
+    events: Option<UnboundedReceiver<DiscoveryEvent>>,
+    offers: Option<UnboundedReceiver<IncomingOffer>>,
+    incoming: Option<UnboundedReceiver<NoteDrop>>,
+    requests: Option<UnboundedReceiver<PaymentRequest>>,
+    /// Tasks feeding backend events into the peer table.
+    watchers: Vec<JoinHandle<()>>,
+    /// Tasks accepting connections while advertising.
+    listeners: Vec<JoinHandle<()>>,
+}
+
+impl Orchestrator {
+    pub fn new(identity: DeviceIdentity, config: OrchestratorConfig) -> Self {
+        let (events_tx, events) = mpsc::unbounded();
+        let (offers_tx, offers) = mpsc::unbounded();
+        let (incoming_tx, incoming) = mpsc::unbounded();
+        let (requests_tx, requests) = mpsc::unbounded();
+        let shared = Shared {
+            peers: PeerTable::default(),
+            events: events_tx,
+            offers: offers_tx,
+            incoming: incoming_tx,
+            requests: requests_tx,
+            issued: HashMap::new(),
+            advertised: None,
+            history: TransferHistory::new(),
+            contacts: ContactBook::new(),
+            nullifiers: NullifierSet::new(),
+            locks: SpendLocks::new(),
+            mode: State::Idle,
+            connections: 0,
+        };
+        Orchestrator {
+            identity,
+            config,
+            backends: Vec::new(),
+            transports: Vec::new(),
+            shared: Arc::new(Mutex::new(shared)),
+            events: Some(events),
+            offers: Some(offers),
+            incoming: Some(incoming),
+            requests: Some(requests),
+            watchers: Vec::new(),
+            listeners: Vec::new(),
+        }
+    }
+
+    /// Adds a discovery backend. Must be called within a tokio runtime.
+    pub fn add_backend<B>(&mut self, backend: B)
+    where
+        B: DiscoveryAdvertiser + Send + Sync + 'static,
+        <B as Discovery>::Error: Error + Send + Sync + 'static,
+        <B as Advertiser>::Error: Error + Send + Sync + 'static,
+    {
+        self.watch(backend);
+    }
+
+    pub fn add_transport<T>(&mut self, transport: T)
+    where
+        T: Transport + Send + Sync + 'static,
+        T::Error: Error + Send + Sync + 'static,
+    {
+        self.transports.push(Arc::new(transport));
+    }
+
+    /// Adds a backend that also connects to the peers it discovers, like
+    /// BLE. Must be called within a tokio runtime.
+    pub fn add_backend_with_transport<B>(&mut self, backend: B)
+    where
+        B: DiscoveryAdvertiser + Transport + Send + Sync + 'static,
+        <B as Discovery>::Error: Error + Send + Sync + 'static,
+        <B as Advertiser>::Error: Error + Send + Sync + 'static,
+        <B as Transport>::Error: Error + Send + Sync + 'static,
+    {
+        let backend = self.watch(backend);
+        self.transports.push(backend);
+    }
+
+    fn watch<B>(&mut self, mut backend: B) -> Arc<B>
+    where
+        B: DiscoveryAdvertiser + Send + Sync + 'static,
+        <B as Discovery>::Error: Error + Send + Sync + 'static,
+        <B as Advertiser>::Error: Error + Send + Sync + 'static,
+    {
+        let index = self.backends.len();
+        let mut events = backend.poll_events();
+        let shared = self.shared.clone();
+        let rotation = self.config.rotation;
+        self.watchers.push(tokio::spawn(async move {
+            while let Some(event) = events.next().await {
+                let mut guard = shared.lock().unwrap();
+                let shared = &mut *guard;
+                if let Some(event) = shared
+                    .peers
+                    .apply(index, event, &shared.contacts, &rotation)
+                {
+                    // nobody may be listening; the table is still current
+                    let _ = shared.events.unbounded_send(event);
+                }
+            }
+        }));
+        let backend = Arc::new(backend);
+        self.backends.push(backend.clone());
+        backend
+    }
+
+    pub fn state(&self) -> State {
+        let shared = self.shared.lock().unwrap();
+        if shared.connections > 0 {
+            State::Connected
+        } else {
+            shared.mode
+        }
+    }
+
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        let shared = self.shared.lock().unwrap();
+        shared
+            .peers
+            .peers
+            .values()
+            .map(|entry| entry.info.clone())
+            .collect()
+    }
+
+    pub fn peer(&self, id: &PeerId) -> Option<PeerInfo> {
+        let shared = self.shared.lock().unwrap();
+        shared.peers.get(id).map(|entry| entry.info.clone())
+    }
+
+    /// Peers discovered and lost, merged across backends, and the payment
+    /// requests they advertise. Each call takes over from the previous
+    /// stream.
+    pub fn events(&mut self) -> BoxStreamResponse<DiscoveryEvent> {
+        let events = self.events.take().unwrap_or_else(|| {
+            let (events_tx, events) = mpsc::unbounded();
+            self.shared.lock().unwrap().events = events_tx;
+            events
+        });
+        Box::pin(events)
+    }
+
+    /// Drops offered to this device, to accept or reject. Each call takes
+    /// over from the previous stream.
+    pub fn offers(&mut self) -> BoxStreamResponse<IncomingOffer> {
+        let offers = self.offers.take().unwrap_or_else(|| {
+            let (offers_tx, offers) = mpsc::unbounded();
+            self.shared.lock().unwrap().offers = offers_tx;
+            offers
+        });
+        Box::pin(offers)
+    }
+
+    /// Notes dropped to this device, already opened and checked. A drop is
+    /// only acknowledged once its note is queued here, so with the stream
+    /// dropped drops are declined. Each call takes over from the previous
+    /// stream.
+    pub fn incoming(&mut self) -> BoxStreamResponse<NoteDrop> {
+        let incoming = self.incoming.take().unwrap_or_else(|| {
+            let (incoming_tx, incoming) = mpsc::unbounded();
+            self.shared.lock().unwrap().incoming = incoming_tx;
+            incoming
+        });
+        Box::pin(incoming)
+    }
+
+    /// Payment requests sent to this device, their signatures checked. Each
+    /// call takes over from the previous stream.
+    pub fn requests(&mut self) -> BoxStreamResponse<PaymentRequest> {
+        let requests = self.requests.take().unwrap_or_else(|| {
+            let (requests_tx, requests) = mpsc::unbounded();
+            self.shared.lock().unwrap().requests = requests_tx;
+            requests
+        });
+        Box::pin(requests)
+    }
+
+    /// The drops completed so far, in either direction.
+    pub fn history(&self) -> TransferHistory {
+        self.shared.lock().unwrap().history.clone()
+    }
+
+    /// Replaces the transfer history, typically with one loaded from disk.
+    /// Later drops are recorded on top of it.
+    pub fn set_history(&self, history: TransferHistory) {
+        self.shared.lock().unwrap().history = history;
+    }
+
+    pub fn contacts(&self) -> ContactBook {
+        self.shared.lock().unwrap().contacts.clone()
+    }
+
+    /// Replaces the contact book, typically with one loaded from disk. Peers
+    /// sighted from then on under a contact's rotating token are reported
+    /// as that contact.
+    pub fn set_contacts(&self, contacts: ContactBook) {
+        self.shared.lock().unwrap().contacts = contacts;
+    }
+
+    /// Notes handed off by this device, and notes received and not spent
+    /// yet.
+    pub fn spend_locks(&self) -> SpendLocks {
+        self.shared.lock().unwrap().locks.clone()
+    }
+
+    /// Replaces the spend locks, typically with ones loaded from disk.
+    pub fn set_spend_locks(&self, locks: SpendLocks) {
+        self.shared.lock().unwrap().locks = locks;
+    }
+
+    /// The nullifiers drops are checked against.
+    pub fn nullifiers(&self) -> NullifierSet {
+        self.shared.lock().unwrap().nullifiers.clone()
+    }
+
+    /// Replaces the cached nullifier set, typically with one loaded from
+    /// disk.
+    pub fn set_nullifiers(&self, nullifiers: NullifierSet) {
+        self.shared.lock().unwrap().nullifiers = nullifiers;
+    }
+
+    /// Adds `nullifiers`, freshly fetched from the chain or a relayer, to
+    /// the cached set, returning the received notes someone else spent
+    /// first.
+    pub fn sync_nullifiers(&self, nullifiers: impl IntoIterator<Item = Fr>) -> Vec<Conflict> {
+        let mut shared = self.shared.lock().unwrap();
+        let Shared {
+            nullifiers: cached,
+            locks,
+            ..
+        } = &mut *shared;
+        cached.sync(nullifiers, SystemTime::now());
+        locks.sync(cached)
+    }
+
+    /// Marks the received note committed to by `commitment` as spent by
+    /// this device, so syncing does not report it as a conflict.
+    pub fn settle(&self, commitment: &Fr) -> bool {
+        self.shared
+            .lock()
+            .unwrap()
+            .locks
+            .settle(commitment)
+            .is_some()
+    }
+
+    /// Stops scanning, then announces this device on every backend and
+    /// accepts drops on every transport.
+    pub async fn advertise(&mut self) -> Result<(), OrchestratorError> {
+        self.each_backend(|backend| backend.stop_scan()).await?;
+        if self.listeners.is_empty() {
+            for transport in &self.transports {
+                let connections = transport
+                    .listen()
+                    .await
+                    .map_err(OrchestratorError::TransportError)?;
+                self.listeners.push(tokio::spawn(accept(
+                    connections,
+                    self.identity.clone(),
+                    self.config,
+                    self.shared.clone(),
+                )));
+            }
+        }
+        self.each_backend(|backend| backend.broadcast()).await?;
+        self.shared.lock().unwrap().mode = State::Advertising;
+        Ok(())
+    }
+
+    /// Stops advertising and looks for peers on every backend.
+    pub async fn scan(&mut self) -> Result<(), OrchestratorError> {
+        self.stop_listening();
+        self.each_backend(|backend| backend.stop_broadcast())
+            .await?;
+        self.each_backend(|backend| backend.start_scan()).await?;
+        self.shared.lock().unwrap().mode = State::Scanning;
+        Ok(())
+    }
+
+    /// Stops advertising and scanning. Drops in progress carry on.
+    pub async fn stop(&mut self) -> Result<(), OrchestratorError> {
+        self.stop_listening();
+        self.each_backend(|backend| backend.stop_broadcast())
+            .await?;
+        self.each_backend(|backend| backend.stop_scan()).await?;
+        self.shared.lock().unwrap().mode = State::Idle;
+        Ok(())
+    }
+
+    async fn each_backend(
+        &self,
+        action: impl Fn(&dyn Backend) -> BoxFutureResponse<(), BoxError>,
+    ) -> Result<(), OrchestratorError> {
+        for backend in &self.backends {
+            action(backend.as_ref())
+                .await
+                .map_err(OrchestratorError::BackendError)?;
+        }
+        Ok(())
+    }
+
+    fn stop_listening(&mut self) {
+        for listener in self.listeners.drain(..) {
+            listener.abort();
+        }
+    }
+
+    /// Announces `request` on every backend with room for it, or withdraws
+    /// the announced one with `None`. A payment answering it is accepted
+    /// without asking.
+    pub async fn advertise_request(
+        &mut self,
+        request: Option<PaymentRequest>,
+    ) -> Result<(), OrchestratorError> {
+        let advertising = {
+            let mut shared = self.shared.lock().unwrap();
+            if let Some(id) = shared.advertised.take() {
+                shared.issued.remove(&id);
+            }
+            if let Some(request) = &request {
+                shared.advertised = Some(request.id());
+                shared.issued.insert(request.id(), request.clone());
+            }
+            shared.mode == State::Advertising
+        };
+        self.each_backend(|backend| backend.set_payment_request(request.clone()))
+            .await?;
+        if advertising {
+            self.each_backend(|backend| backend.broadcast()).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends `request` to `peer`, returning once the peer has received it. A
+    /// payment answering it is accepted without asking.
+    pub async fn send_request(
+        &self,
+        peer: &PeerId,
+        request: &PaymentRequest,
+    ) -> Result<(), OrchestratorError> {
+        let info = self
+            .peer(peer)
+            .ok_or(OrchestratorError::UnknownPeer(*peer))?;
+        let mut connection = self.dial(&info).await?;
+        let _guard = ConnectionGuard::new(&self.shared);
+        self.shared
+            .lock()
+            .unwrap()
+            .issued
+            .insert(request.id(), request.clone());
+        let exchange = async {
+            let mut session = self.open_session(&mut connection, &info).await?;
+            let message = Message::Request(Box::new(request.clone()));
+            send(&mut session, &mut connection, &message).await?;
+            match receive(&mut session, &mut connection).await {
+                Ok(Message::Accept) => Ok(()),
+                _ => Err(OrchestratorError::NotAcknowledged),
+            }
+        };
+        let result = tokio::time::timeout(self.config.drop_timeout, exchange)
+            .await
+            .unwrap_or(Err(OrchestratorError::Timeout));
+        let _ = connection.close().await;
+        result
+    }
+
+    /// Pays `request` with `note`, which must be made out as it asks, by
+    /// dropping it to the requester. The requester must be in the peer
+    /// table.
+    pub async fn pay_request(
+        &self,
+        request: &PaymentRequest,
+        note: &Note,
+    ) -> Result<DeliveryReceipt, OrchestratorError> {
+        request.check(SystemTime::now())?;
+        if !request.is_paid_by(note) {
+            return Err(OrchestratorError::RequestMismatch);
+        }
+        let peer = request.requester_id();
+        self.drop_note(&peer, note, request.memo(), Some(request.id()))
+            .await
+    }
+
+    /// Offers `note` to `peer` and drops it once accepted, returning the
+    /// peer's receipt for it, which is also kept in the history. The note
+    /// is locked once its payload is sent, whatever comes of the drop, and
+    /// a locked note is not offered again.
+    pub async fn send_note(
+        &self,
+        peer: &PeerId,
+        note: &Note,
+        memo: Option<&str>,
+    ) -> Result<DeliveryReceipt, OrchestratorError> {
+        self.drop_note(peer, note, memo, None).await
+    }
+
+    async fn drop_note(
+        &self,
+        peer: &PeerId,
+        note: &Note,
+        memo: Option<&str>,
+        request: Option<RequestId>,
+    ) -> Result<DeliveryReceipt, OrchestratorError> {
+        if self.shared.lock().unwrap().locks.is_locked(&note.commit()) {
+            return Err(OrchestratorError::NoteLocked);
+        }
+        let info = self
+            .peer(peer)
+            .ok_or(OrchestratorError::UnknownPeer(*peer))?;
+        let mut connection = self.dial(&info).await?;
+        let _guard = ConnectionGuard::new(&self.shared);
+        let exchange = async {
+            let mut session = self.open_session(&mut connection, &info).await?;
+            let peer = session.remote_id();
+            let recipient = PublicKey::from(session.remote().agreement_key);
+            let sealed = NoteDrop::seal(note, memo, &self.identity, &recipient)?;
+            let offer = Offer {
+                request,
+                ..Offer::new(&self.identity, note, memo, sealed.len())
+            };
+            send(&mut session, &mut connection, &Message::Offer(offer)).await?;
+            match receive(&mut session, &mut connection).await? {
+                Message::Accept => {}
+                Message::Reject(reason) => return Err(OrchestratorError::Rejected(reason)),
+                _ => return Err(OrchestratorError::UnexpectedMessage),
+            }
+            // the peer may hold the secret from here on
+            let locked = self
+                .shared
+                .lock()
+                .unwrap()
+                .locks
+                .lock(note, peer, SystemTime::now());
+            if !locked {
+                return Err(OrchestratorError::NoteLocked);
+            }
+            send(&mut session, &mut connection, &Message::Payload(sealed)).await?;
+            let receipt = match receive(&mut session, &mut connection).await {
+                Ok(Message::Receipt(receipt)) => receipt,
+                Ok(Message::Reject(reason)) => return Err(OrchestratorError::Rejected(reason)),
+                _ => return Err(OrchestratorError::NotAcknowledged),
+            };
+            receipt
+                .verify(&peer, &self.identity.peer_id(), &note.commit())
+                .map_err(|_| OrchestratorError::NotAcknowledged)?;
+            Ok(*receipt)
+        };
+        let timeout = self.config.drop_timeout + self.config.offer_timeout;
+        let result = tokio::time::timeout(timeout, exchange)
+            .await
+            .unwrap_or(Err(OrchestratorError::Timeout));
+        let _ = connection.close().await;
+        let receipt = result?;
+        self.shared.lock().unwrap().history.record(Transfer {
+            direction: Direction::Sent,
+            peer: receipt.recipient_id(),
+            commitment: note.commit(),
+            amount: note.value,
+            memo: memo.filter(|memo| !memo.is_empty()).map(str::to_string),
+            at: receipt.timestamp(),
+            receipt: Some(receipt.clone()),
+        });
+        Ok(receipt)
+    }
+
+    /// Runs the handshake as initiator, checking the peer is who was dialed.
+    /// A peer sighted under a provisional id is whoever proves a key at its
+    /// address, and is known by that key's id from then on.
+    async fn open_session(
+        &self,
+        connection: &mut Connection,
+        peer: &PeerInfo,
+    ) -> Result<Session, OrchestratorError> {
+        let initiator = Handshake::initiator(&self.identity, self.config.session)?;
+        let session = handshake(initiator, connection).await?;
+        let remote = session.remote_id();
+        if !peer.provisional {
+            if remote != peer.id {
+                return Err(OrchestratorError::UnexpectedPeer(remote));
+            }
+            return Ok(session);
+        }
+        let mut shared = self.shared.lock().unwrap();
+        for event in shared.peers.bind(peer.id, remote) {
+            // nobody may be listening; the table is still current
+            let _ = shared.events.unbounded_send(event);
+        }
+        Ok(session)
+    }
+
+    /// Connects over the first transport that reaches `peer`.
+    async fn dial(&self, peer: &PeerInfo) -> Result<Connection, OrchestratorError> {
+        let mut failure = OrchestratorError::NoTransport;
+        for transport in &self.transports {
+            match transport.dial(peer).await {
+                Ok(connection) => return Ok(connection),
+                Err(err) => failure = OrchestratorError::TransportError(err),
+            }
+        }
+        Err(failure)
+    }
+}
+
+impl Drop for Orchestrator {
+    fn drop(&mut self) {
+        for task in self.watchers.drain(..).chain(self.listeners.drain(..)) {
+            task.abort();
+        }
+    }
+}
+
+async fn send(
+    session: &mut Session,
+    connection: &mut Connection,
+    message: &Message,
+) -> Result<(), OrchestratorError> {
+    let frame = session.encrypt(&message.encode()?)?;
+    connection
+        .send(frame)
+        .await
+        .map_err(|err| OrchestratorError::TransportError(err.into()))
+}
+
+async fn receive(
+    session: &mut Session,
+    connection: &mut Connection,
+) -> Result<Message, OrchestratorError> {
+    let frame = connection
+        .next()
+        .await
+        .ok_or(SessionError::TransportClosed)?;
+    Ok(Message::decode(&session.decrypt(&frame)?)?)
+}
+
+async fn accept(
+    mut connections: BoxStreamResponse<Connection>,
+    identity: DeviceIdentity,
+    config: OrchestratorConfig,
+    shared: Arc<Mutex<Shared>>,
+) {
+    while let Some(connection) = connections.next().await {
+        let (identity, shared) = (identity.clone(), shared.clone());
+        tokio::spawn(async move {
+            let _guard = ConnectionGuard::new(&shared);
+            let exchange = serve(connection, &identity, config, &shared);
+            let timeout = config.drop_timeout + config.offer_timeout;
+            // a failed exchange concerns only the peer, who gets no receipt
+            let _ = tokio::time::timeout(timeout, exchange).await;
+        });
+    }
+}
+
+async fn serve(
+    mut connection: Connection,
+    identity: &DeviceIdentity,
+    config: OrchestratorConfig,
+    shared: &Arc<Mutex<Shared>>,
+) -> Result<(), OrchestratorError> {
+    let responder = Handshake::responder(identity, config.session)?;
+    let mut session = handshake(responder, &mut connection).await?;
+    match receive(&mut session, &mut connection).await? {
+        Message::Offer(offer) => {
+            receive_note(session, connection, offer, identity, config, shared).await
+        }
+        Message::Request(request) => {
+            if request.requester_id() != session.remote_id() {
+                return Err(OrchestratorError::UnexpectedPeer(request.requester_id()));
+            }
+            request.check(SystemTime::now())?;
+            let handed_over = shared
+                .lock()
+                .unwrap()
+                .requests
+                .unbounded_send(*request)
+                .is_ok();
+            if !handed_over {
+                return decline(&mut session, &mut connection).await;
+            }
+            send(&mut session, &mut connection, &Message::Accept).await
+        }
+        _ => Err(OrchestratorError::UnexpectedMessage),
+    }
+}
+
+async fn receive_note(
+    mut session: Session,
+    mut connection: Connection,
+    offer: Offer,
+    identity: &DeviceIdentity,
+    config: OrchestratorConfig,
+    shared: &Arc<Mutex<Shared>>,
+) -> Result<(), OrchestratorError> {
+    if offer.sender != session.remote_id() {
+        return Err(OrchestratorError::UnexpectedPeer(offer.sender));
+    }
+    let request = offer
+        .request
+        .and_then(|id| shared.lock().unwrap().issued.get(&id).cloned());
+    let refusal = if offer.payload_len as usize > MAX_PAYLOAD_LEN {
+        Some(RejectReason::TooLarge)
+    } else if let Some(request) = &request {
+        // this device asked for the payment, so it is not asked again
+        let open = request.check(SystemTime::now()).is_ok();
+        (!open || offer.amount != request.amount()).then_some(RejectReason::Declined)
+    } else {
+        let (decision_tx, decision) = oneshot::channel();
+        let incoming = IncomingOffer {
+            offer: offer.clone(),
+            decision: decision_tx,
+        };
+        // with nobody listening the offer is dropped, and so declined
+        let _ = shared.lock().unwrap().offers.unbounded_send(incoming);
+        match tokio::time::timeout(config.offer_timeout, decision).await {
+            Ok(Ok(true)) => None,
+            Ok(_) => Some(RejectReason::Declined),
+            Err(_) => Some(RejectReason::Expired),
+        }
+    };
+    if let Some(reason) = refusal {
+        send(&mut session, &mut connection, &Message::Reject(reason)).await?;
+        return Err(OrchestratorError::Rejected(reason));
+    }
+    send(&mut session, &mut connection, &Message::Accept).await?;
+    let Message::Payload(sealed) = receive(&mut session, &mut connection).await? else {
+        return Err(OrchestratorError::UnexpectedMessage);
+    };
+    let drop = NoteDrop::open(&sealed, identity)?;
+    if drop.sender_id() != session.remote_id() {
+        return Err(OrchestratorError::UnexpectedPeer(drop.sender_id()));
+    }
+    if !offer.describes(&drop) {
+        return Err(OrchestratorError::OfferMismatch);
+    }
+    if shared.lock().unwrap().nullifiers.is_spent(&drop.note) {
+        let reason = RejectReason::Spent;
+        send(&mut session, &mut connection, &Message::Reject(reason)).await?;
+        return Err(OrchestratorError::Rejected(reason));
+    }
+    if let Some(request) = &request {
+        if !request.is_paid_by(&drop.note) {
+            return Err(OrchestratorError::OfferMismatch);
+        }
+        shared.lock().unwrap().issued.remove(&request.id());
+    }
+    let (at, sender, commitment) = (SystemTime::now(), drop.sender_id(), drop.commitment);
+    let transfer = Transfer {
+        direction: Direction::Received,
+        peer: sender,
+        commitment,
+        amount: drop.note.value,
+        memo: drop.memo.clone(),
+        at,
+        receipt: None,
+    };
+    let note = drop.note;
+    // the note is this device's before it signs for it
+    let handed_over = {
+        let mut shared = shared.lock().unwrap();
+        let queued = shared.incoming.unbounded_send(drop).is_ok();
+        if queued {
+            shared.locks.receive(&note, sender, at);
+            shared.history.record(transfer);
+        }
+        queued
+    };
+    if !handed_over {
+        return decline(&mut session, &mut connection).await;
+    }
+    let receipt = DeliveryReceipt::issue(identity, sender, commitment, at);
+    send(
+        &mut session,
+        &mut connection,
+        &Message::Receipt(Box::new(receipt)),
+    )
+    .await
+}
+
+/// Turns the peer down because nobody takes what it brought.
+async fn decline(
+    session: &mut Session,
+    connection: &mut Connection,
+) -> Result<(), OrchestratorError> {
+    let reason = RejectReason::Declined;
+    send(session, connection, &Message::Reject(reason)).await?;
+    Err(OrchestratorError::Rejected(reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::run;
+    use core::{
+        contacts::{ContactCard, Trust},
+        identity::TransportAddress,
+        note::Fr,
+    };
+    use discovery::memory::{MemoryAir, MemoryDiscovery};
+
+    fn info(identity: &DeviceIdentity, name: &str) -> PeerInfo {
+        PeerInfo {
+            display_name: Some(name.to_string()),
+            ..PeerInfo::new(identity.peer_id())
+        }
+    }
+
+    fn device(air: &MemoryAir, identity: &DeviceIdentity, name: &str) -> Orchestrator {
+        let mut orchestrator = Orchestrator::new(identity.clone(), OrchestratorConfig::default());
+        orchestrator.add_backend_with_transport(air.join(info(identity, name)));
+        orchestrator
+    }
+
+    async fn next_event(events: &mut BoxStreamResponse<DiscoveryEvent>) -> Option<DiscoveryEvent> {
+        tokio::time::timeout(Duration::from_secs(60), events.next())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    #[test]
+    fn a_peer_seen_by_several_backends_is_one_entry() {
+        run(async {
+            let (near, far) = (MemoryAir::default(), MemoryAir::default());
+            let bob = DeviceIdentity::generate();
+            let mut alice = Orchestrator::new(DeviceIdentity::generate(), Default::default());
+            alice.add_backend(near.join(PeerInfo::new(PeerId::from_public_key(b"alice"))));
+            alice.add_backend(far.join(PeerInfo::new(PeerId::from_public_key(b"alice"))));
+            let mut events = alice.events();
+
+            let mut bob_near = info(&bob, "bob");
+            bob_near.addresses = vec![TransportAddress::Ble([1; 6])];
+            let mut bob_far = PeerInfo::new(bob.peer_id());
+            bob_far.addresses = vec![TransportAddress::Socket("10.0.0.2:47470".parse().unwrap())];
+            let (bob_near, bob_far) = (near.join(bob_near), far.join(bob_far));
+
+            alice.scan().await.unwrap();
+            Advertiser::broadcast(&bob_near).await.unwrap();
+            Advertiser::broadcast(&bob_far).await.unwrap();
+            assert!(matches!(
+                next_event(&mut events).await,
+                Some(DiscoveryEvent::PeerDiscovered(peer)) if peer.id == bob.peer_id()
+            ));
+            Advertiser::stop_broadcast(&bob_near).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(1)).await;
+
+            let merged = alice.peer(&bob.peer_id()).unwrap();
+            assert_eq!(merged.display_name.as_deref(), Some("bob"));
+            assert_eq!(merged.addresses.len(), 2);
+            assert_eq!(alice.peers().len(), 1);
+
+            Advertiser::stop_broadcast(&bob_far).await.unwrap();
+            assert!(matches!(
+                next_event(&mut events).await,
+                Some(DiscoveryEvent::PeerLost(peer)) if peer.id == bob.peer_id()
+            ));
+            assert_eq!(next_event(&mut events).await, None);
+            assert!(alice.peers().is_empty());
+        });
+    }
+
+    #[test]
+    fn a_note_is_dropped_to_an_advertising_peer() {
+        run(async {
+            let air = MemoryAir::default();
+            let (alice_id, bob_id) = (DeviceIdentity::generate(), DeviceIdentity::generate());
+            let mut alice = device(&air, &alice_id, "alice");
+            let mut bob = device(&air, &bob_id, "bob");
+            let mut alice_events = alice.events();
+            let mut offers = bob.offers();
+            let mut received = bob.incoming();
+            assert_eq!(alice.state(), State::Idle);
+
+            bob.advertise().await.unwrap();
+            alice.scan().await.unwrap();
+            assert_eq!(bob.state(), State::Advertising);
+            assert_eq!(alice.state(), State::Scanning);
+            assert!(matches!(
+                next_event(&mut alice_events).await,
+                Some(DiscoveryEvent::PeerDiscovered(peer)) if peer.id == bob_id.peer_id()
+            ));
+
+            let (note, to) = (Note::new(1u64, 100u64, 1234u64), bob_id.peer_id());
+            let (sent, _) = futures::join!(alice.send_note(&to, &note, Some("lunch")), async {
+                let incoming = offers.next().await.unwrap();
+                assert_eq!(incoming.offer.sender, alice_id.peer_id());
+                assert_eq!(incoming.offer.amount, note.value);
+                assert_eq!(incoming.offer.memo.as_deref(), Some("lunch"));
+                assert!(incoming.offer.payload_len as usize <= MAX_PAYLOAD_LEN);
+                incoming.accept();
+            });
+            let receipt = sent.unwrap();
+            let drop = received.next().await.unwrap();
+            assert_eq!(drop.note, note);
+            assert_eq!(drop.memo.as_deref(), Some("lunch"));
+            assert_eq!(drop.sender_id(), alice_id.peer_id());
+
+            // the receipt proves the drop offline, and both sides recorded it
+            let encoded = DeliveryReceipt::decode(&receipt.encode()).unwrap();
+            assert_eq!(
+                encoded.verify(&to, &alice_id.peer_id(), &note.commit()),
+                Ok(())
+            );
+            let sent = alice.history().find(&note.commit()).cloned().unwrap();
+            assert_eq!(sent.direction, Direction::Sent);
+            assert_eq!((sent.peer, sent.amount), (to, note.value));
+            assert_eq!(sent.receipt, Some(receipt));
+            let got = bob.history().find(&note.commit()).cloned().unwrap();
+            assert_eq!(got.direction, Direction::Received);
+            assert_eq!(got.peer, alice_id.peer_id());
+            assert_eq!(got.memo.as_deref(), Some("lunch"));
+
+            alice.stop().await.unwrap();
+            assert_eq!(alice.state(), State::Idle);
+        });
+    }
+
+    /// Bob advertising and alice scanning, with bob in her peer table.
+    async fn pair(
+        air: &MemoryAir,
+        alice: &DeviceIdentity,
+        bob: &DeviceIdentity,
+    ) -> (Orchestrator, Orchestrator) {
+        let mut alice = device(air, alice, "alice");
+        let mut bob = device(air, bob, "bob");
+        let mut events = alice.events();
+        bob.advertise().await.unwrap();
+        alice.scan().await.unwrap();
+        next_event(&mut events).await.unwrap();
+        (alice, bob)
+    }
+
+    #[test]
+    fn a_peer_sighted_under_a_provisional_id_is_bound_to_its_key() {
+        run(async {
+            let air = MemoryAir::default();
+            let bob_id = DeviceIdentity::generate();
+            let mut alice = device(&air, &DeviceIdentity::generate(), "alice");
+            // like a BLE sighting without the peer's own advertisement
+            let sighted = PeerInfo {
+                provisional: true,
+                ..PeerInfo::new(PeerId::provisional(b"bob's radio"))
+            };
+            let mut bob = Orchestrator::new(bob_id.clone(), Default::default());
+            bob.add_backend_with_transport(air.join(sighted.clone()));
+            let (mut events, mut offers) = (alice.events(), bob.offers());
+            bob.advertise().await.unwrap();
+            alice.scan().await.unwrap();
+            assert_eq!(
+                next_event(&mut events).await,
+                Some(DiscoveryEvent::PeerDiscovered(sighted.clone()))
+            );
+
+            let note = Note::new(1u64, 100u64, 1234u64);
+            let (sent, _) = futures::join!(alice.send_note(&sighted.id, &note, None), async {
+                offers.next().await.unwrap().accept();
+            });
+            let receipt = sent.unwrap();
+            assert_eq!(receipt.recipient_id(), bob_id.peer_id());
+            assert_eq!(
+                alice.history().find(&note.commit()).unwrap().peer,
+                bob_id.peer_id()
+            );
+            assert_eq!(
+                next_event(&mut events).await,
+                Some(DiscoveryEvent::PeerLost(sighted.clone()))
+            );
+            let Some(DiscoveryEvent::PeerDiscovered(bound)) = next_event(&mut events).await else {
+                panic!("bob was not rediscovered under his key");
+            };
+            assert_eq!(bound.id, bob_id.peer_id());
+            assert!(!bound.provisional);
+            assert_eq!(alice.peer(&sighted.id), Some(bound.clone()));
+            assert_eq!(alice.peers(), [bound]);
+        });
+    }
+
+    #[test]
+    fn a_contact_sighted_by_token_surfaces_under_its_key() {
+        run(async {
+            let air = MemoryAir::default();
+            let bob_id = DeviceIdentity::generate();
+            let mut alice = device(&air, &DeviceIdentity::generate(), "alice");
+            let mut contacts = ContactBook::new();
+            let card = ContactCard::issue(&bob_id, "bob").unwrap();
+            contacts.import(card, Trust::Verified);
+            contacts.rename(&bob_id.peer_id(), "Bob").unwrap();
+            alice.set_contacts(contacts);
+            // like a BLE sighting of a compact advertisement
+            let now = SystemTime::now();
+            let sighted = PeerInfo {
+                provisional: true,
+                token: Some(RotationConfig::default().token(bob_id.rotation_secret(), now)),
+                last_seen: Some(now),
+                ..PeerInfo::new(PeerId::provisional(b"bob's radio"))
+            };
+            let mut bob = Orchestrator::new(bob_id.clone(), Default::default());
+            bob.add_backend_with_transport(air.join(sighted.clone()));
+            let mut events = alice.events();
+            bob.advertise().await.unwrap();
+            alice.scan().await.unwrap();
+
+            let Some(DiscoveryEvent::PeerDiscovered(found)) = next_event(&mut events).await else {
+                panic!("bob was not discovered");
+            };
+            assert_eq!(found.id, bob_id.peer_id());
+            assert!(!found.provisional);
+            assert_eq!(found.display_name.as_deref(), Some("Bob"));
+            assert_eq!(alice.peer(&sighted.id), Some(found.clone()));
+            assert_eq!(alice.peer(&bob_id.peer_id()), Some(found));
+        });
+    }
+
+    #[test]
+    fn a_note_nobody_takes_is_declined_unsigned() {
+        run(async {
+            let air = MemoryAir::default();
+            let bob_id = DeviceIdentity::generate();
+            let (alice, mut bob) = pair(&air, &DeviceIdentity::generate(), &bob_id).await;
+            let mut offers = bob.offers();
+            // the application stopped listening for notes
+            drop(bob.incoming());
+            let (note, bob_id) = (Note::new(1u64, 100u64, 1234u64), bob_id.peer_id());
+            let (sent, _) = futures::join!(alice.send_note(&bob_id, &note, None), async {
+                offers.next().await.unwrap().accept();
+            });
+            assert!(matches!(
+                sent,
+                Err(OrchestratorError::Rejected(RejectReason::Declined))
+            ));
+            assert!(bob.history().is_empty());
+            assert!(bob.spend_locks().pending().next().is_none());
+        });
+    }
+
+    #[test]
+    fn a_rejected_offer_drops_nothing() {
+        run(async {
+            let air = MemoryAir::default();
+            let bob_id = DeviceIdentity::generate();
+            let (alice, mut bob) = pair(&air, &DeviceIdentity::generate(), &bob_id).await;
+            let (mut offers, mut received) = (bob.offers(), bob.incoming());
+            let (note, bob_id) = (Note::new(1u64, 100u64, 1234u64), bob_id.peer_id());
+            let (sent, _) = futures::join!(alice.send_note(&bob_id, &note, None), async {
+                offers.next().await.unwrap().reject();
+            });
+            assert!(matches!(
+                sent,
+                Err(OrchestratorError::Rejected(RejectReason::Declined))
+            ));
+
+            // an offer nobody answers lapses
+            let start = tokio::time::Instant::now();
+            let (sent, _) = futures::join!(alice.send_note(&bob_id, &note, None), async {
+                let _undecided = offers.next().await.unwrap();
+                tokio::time::sleep(Duration::from_secs(3600)).await;
+            });
+            assert!(matches!(
+                sent,
+                Err(OrchestratorError::Rejected(RejectReason::Expired))
+            ));
+            assert!(start.elapsed() >= OrchestratorConfig::default().offer_timeout);
+
+            assert!(alice.history().is_empty());
+            assert!(bob.history().is_empty());
+            drop(bob);
+            assert!(received.next().await.is_none());
+        });
+    }
+
+    #[test]
+    fn handed_off_notes_are_locked_and_double_spends_surface() {
+        run(async {
+            let air = MemoryAir::default();
+            let (alice_id, bob_id) = (DeviceIdentity::generate(), DeviceIdentity::generate());
+            let (alice, mut bob) = pair(&air, &alice_id, &bob_id).await;
+            let (mut offers, mut received) = (bob.offers(), bob.incoming());
+            let accept = async {
+                while let Some(incoming) = offers.next().await {
+                    incoming.accept();
+                }
+            };
+            let (spent, note) = (Note::new(1u64, 5u64, 1u64), Note::new(1u64, 100u64, 2u64));
+            assert!(bob.sync_nullifiers([spent.nullifier()]).is_empty());
+            let to = bob_id.peer_id();
+            let drops = async {
+                // bob's cached set knows the first note is spent
+                assert!(matches!(
+                    alice.send_note(&to, &spent, None).await,
+                    Err(OrchestratorError::Rejected(RejectReason::Spent))
+                ));
+                alice.send_note(&to, &note, None).await.unwrap();
+                for note in [spent, note] {
+                    assert!(alice.spend_locks().is_locked(&note.commit()));
+                    assert!(matches!(
+                        alice.send_note(&to, &note, None).await,
+                        Err(OrchestratorError::NoteLocked)
+                    ));
+                }
+            };
+            futures::future::select(Box::pin(drops), Box::pin(accept)).await;
+            assert_eq!(received.next().await.unwrap().note, note);
+
+            // alice spends the note herself before bob gets to
+            let conflicts = bob.sync_nullifiers([note.nullifier()]);
+            assert_eq!(conflicts.len(), 1);
+            assert_eq!(conflicts[0].note.commitment, note.commit());
+            assert_eq!(conflicts[0].note.peer, alice_id.peer_id());
+            assert!(bob.spend_locks().pending().next().is_none());
+        });
+    }
+
+    fn issue(merchant: &DeviceIdentity, lifetime: Duration) -> PaymentRequest {
+        let expiry = SystemTime::now() + lifetime;
+        PaymentRequest::issue(
+            merchant,
+            Fr::from(7u64),
+            Fr::from(25u64),
+            expiry,
+            Some("coffee"),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn an_advertised_payment_request_is_paid_without_asking() {
+        run(async {
+            let air = MemoryAir::default();
+            let merchant = DeviceIdentity::generate();
+            let (mut alice, mut bob) = pair(&air, &DeviceIdentity::generate(), &merchant).await;
+            let (mut offers, mut received) = (bob.offers(), bob.incoming());
+            let mut events = alice.events();
+            let request = issue(&merchant, Duration::from_secs(600));
+            bob.advertise_request(Some(request.clone())).await.unwrap();
+
+            assert_eq!(
+                next_event(&mut events).await,
+                Some(DiscoveryEvent::RequestAdvertised(Box::new(request.clone())))
+            );
+            let wrong = Note::new(7u64, 24u64, 1234u64);
+            assert!(matches!(
+                alice.pay_request(&request, &wrong).await,
+                Err(OrchestratorError::RequestMismatch)
+            ));
+            let note = request.note(Fr::from(1234u64));
+            alice.pay_request(&request, &note).await.unwrap();
+            let drop = received.next().await.unwrap();
+            assert_eq!(drop.note, note);
+            assert_eq!(drop.memo.as_deref(), Some("coffee"));
+
+            // a request is settled once; paying it again needs consent
+            let again = request.note(Fr::from(5678u64));
+            let (sent, _) = futures::join!(alice.pay_request(&request, &again), async {
+                let incoming = offers.next().await.unwrap();
+                assert_eq!(incoming.offer.request, Some(request.id()));
+                incoming.reject();
+            });
+            assert!(matches!(
+                sent,
+                Err(OrchestratorError::Rejected(RejectReason::Declined))
+            ));
+
+            let expired = issue(&merchant, Duration::ZERO);
+            assert!(matches!(
+                alice.pay_request(&expired, &note).await,
+                Err(OrchestratorError::InvalidRequest(RequestError::Expired))
+            ));
+        });
+    }
+
+    #[test]
+    fn a_sent_payment_request_reaches_the_payer() {
+        run(async {
+            let air = MemoryAir::default();
+            let (merchant, customer) = (DeviceIdentity::generate(), DeviceIdentity::generate());
+            let (alice, mut bob) = pair(&air, &merchant, &customer).await;
+            let mut requests = bob.requests();
+
+            let request = issue(&merchant, Duration::from_secs(600));
+            alice
+                .send_request(&customer.peer_id(), &request)
+                .await
+                .unwrap();
+            assert_eq!(requests.next().await, Some(request));
+
+            let expired = issue(&merchant, Duration::ZERO);
+            assert!(matches!(
+                alice.send_request(&customer.peer_id(), &expired).await,
+                Err(OrchestratorError::NotAcknowledged)
+            ));
+        });
+    }
+
+    #[test]
+    fn drops_go_only_to_known_peers_with_the_advertised_identity() {
+        run(async {
+            let air = MemoryAir::default();
+            let mut alice = device(&air, &DeviceIdentity::generate(), "alice");
+            let note = Note::new(1u64, 100u64, 1234u64);
+            let stranger = PeerId::from_public_key(b"stranger");
+            assert!(matches!(
+                alice.send_note(&stranger, &note, None).await,
+                Err(OrchestratorError::UnknownPeer(id)) if id == stranger
+            ));
+
+            // mallory advertises bob's id but cannot prove it
+            let (bob, mallory) = (DeviceIdentity::generate(), DeviceIdentity::generate());
+            let mut impostor = Orchestrator::new(mallory.clone(), Default::default());
+            impostor.add_backend_with_transport(air.join(info(&bob, "bob")));
+            let mut events = alice.events();
+            impostor.advertise().await.unwrap();
+            alice.scan().await.unwrap();
+            next_event(&mut events).await.unwrap();
+            assert!(matches!(
+                alice.send_note(&bob.peer_id(), &note, None).await,
+                Err(OrchestratorError::UnexpectedPeer(id)) if id == mallory.peer_id()
+            ));
+        });
+    }
+
+    #[test]
+    fn a_drop_in_progress_is_connected() {
+        run(async {
+            let air = MemoryAir::default();
+            let bob = DeviceIdentity::generate();
+            let mut alice = device(&air, &DeviceIdentity::generate(), "alice");
+            let mut events = alice.events();
+            // bob accepts the connection but never answers
+            let silent: MemoryDiscovery = air.join(info(&bob, "bob"));
+            let mut connections = Transport::listen(&silent).await.unwrap();
+            Advertiser::broadcast(&silent).await.unwrap();
+            alice.scan().await.unwrap();
+            next_event(&mut events).await.unwrap();
+
+            let (note, bob) = (Note::new(1u64, 100u64, 1234u64), bob.peer_id());
+            let (sent, _) = futures::join!(alice.send_note(&bob, &note, None), async {
+                let connection = connections.next().await.unwrap();
+                assert_eq!(alice.state(), State::Connected);
+                connection
+            });
+            assert!(matches!(sent, Err(OrchestratorError::Timeout)));
+            assert_eq!(alice.state(), State::Scanning);
+        });
+    }
+}
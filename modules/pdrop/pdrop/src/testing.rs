@@ -0,0 +1,12 @@
+use std::future::Future;
+
+/// `#[tokio::test]` expands to `::core` paths, which resolve to the pdrop
+/// core crate here, so tests build their paused-clock runtime by hand.
+pub fn run<F: Future>(test: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .start_paused(true)
+        .build()
+        .unwrap()
+        .block_on(test)
+}
@@ -0,0 +1,3 @@
+pub mod tcp;
+#[cfg(test)]
+mod testing;
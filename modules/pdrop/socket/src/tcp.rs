@@ -0,0 +1,302 @@
+//! Connections to LAN peers over TCP, dialed at the socket addresses mDNS
+//! and UDP beacons report. On the wire each frame is a big-endian u32
+//! length followed by the frame.
+
+use core::{
+    identity::{PeerInfo, TransportAddress},
+    transport::{CloseHandle, CloseReason, Connection, Frame, Transport, TransportError},
+    types::{BoxFutureResponse, BoxStreamResponse},
+};
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+
+use futures::{
+    SinkExt, StreamExt,
+    channel::mpsc::{self, Receiver, Sender},
+    stream,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        TcpListener, TcpStream,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+    },
+};
+
+pub const DEFAULT_PORT: u16 = 47_470;
+
+/// Frames buffered per direction between a connection and its socket.
+const FRAME_CAPACITY: usize = 16;
+
+#[derive(Debug, Clone)]
+pub struct TcpConfig {
+    /// Address connections are accepted on. Port 0 picks a free one; see
+    /// `TcpTransport::local_addr`.
+    pub listen: SocketAddr,
+    /// Largest frame a connection carries. A peer announcing a larger one
+    /// is cut off.
+    pub max_frame_len: usize,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        TcpConfig {
+            listen: SocketAddr::from((Ipv4Addr::UNSPECIFIED, DEFAULT_PORT)),
+            max_frame_len: 1 << 20,
+        }
+    }
+}
+
+pub struct TcpTransport {
+    listener: Arc<TcpListener>,
+    config: TcpConfig,
+}
+
+impl TcpTransport {
+    pub async fn bind(config: TcpConfig) -> Result<Self, TransportError> {
+        let listener = TcpListener::bind(config.listen).await?;
+        Ok(TcpTransport {
+            listener: Arc::new(listener),
+            config,
+        })
+    }
+
+    /// The address actually listened on, to publish to peers.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+/// Reads one frame, or `None` if the peer closed between frames.
+async fn read_frame(reader: &mut OwnedReadHalf, max_len: usize) -> io::Result<Option<Frame>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > max_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("peer sent a frame of {len} bytes, over the limit of {max_len}"),
+        ));
+    }
+    let mut frame = vec![0; len];
+    reader.read_exact(&mut frame).await?;
+    Ok(Some(frame))
+}
+
+async fn read_frames(
+    mut reader: OwnedReadHalf,
+    mut frames_in: Sender<Frame>,
+    max_len: usize,
+    closed: CloseHandle,
+) {
+    loop {
+        match read_frame(&mut reader, max_len).await {
+            Ok(Some(frame)) => {
+                if frames_in.send(frame).await.is_err() {
+                    // the connection was dropped
+                    return;
+                }
+            }
+            Ok(None) => {
+                closed.close(CloseReason::Remote);
+                return;
+            }
+            Err(err) => {
+                closed.close(CloseReason::Failed(err.to_string()));
+                return;
+            }
+        }
+    }
+}
+
+async fn write_frames(
+    mut writer: OwnedWriteHalf,
+    mut frames_out: Receiver<Frame>,
+    closed: CloseHandle,
+) {
+    while let Some(frame) = frames_out.next().await {
+        let len = (frame.len() as u32).to_be_bytes();
+        let written = async {
+            writer.write_all(&len).await?;
+            writer.write_all(&frame).await
+        };
+        if let Err(err) = written.await {
+            closed.close(CloseReason::Failed(err.to_string()));
+            return;
+        }
+    }
+    // the connection was closed or dropped; let the peer know
+    let _ = writer.shutdown().await;
+}
+
+fn open_connection(stream: TcpStream, config: &TcpConfig) -> Connection {
+    // frames are whole messages; don't hold them back for coalescing
+    let _ = stream.set_nodelay(true);
+    let remote = stream.peer_addr().ok().map(TransportAddress::Socket);
+    let (reader, writer) = stream.into_split();
+    let (frames_out_tx, frames_out) = mpsc::channel(FRAME_CAPACITY);
+    let (frames_in, frames_in_rx) = mpsc::channel(FRAME_CAPACITY);
+    let closed = CloseHandle::default();
+
+    tokio::spawn(read_frames(
+        reader,
+        frames_in,
+        config.max_frame_len,
+        closed.clone(),
+    ));
+    tokio::spawn(write_frames(writer, frames_out, closed.clone()));
+
+    let sink_closed = closed.clone();
+    let sink = frames_out_tx.sink_map_err(move |_| {
+        TransportError::Closed(sink_closed.reason().unwrap_or(CloseReason::LinkLost))
+    });
+    Connection::new(
+        remote,
+        config.max_frame_len,
+        Box::pin(sink),
+        Box::pin(frames_in_rx),
+        closed,
+    )
+}
+
+impl Transport for TcpTransport {
+    type Error = TransportError;
+
+    /// Connects to the first of `peer`'s socket addresses that accepts.
+    fn dial(&self, peer: &PeerInfo) -> BoxFutureResponse<Connection, Self::Error> {
+        let addresses: Vec<SocketAddr> = peer
+            .addresses
+            .iter()
+            .filter_map(|address| match address {
+                TransportAddress::Socket(address) => Some(*address),
+                TransportAddress::Ble(_) => None,
+            })
+            .collect();
+        let config = self.config.clone();
+        Box::pin(async move {
+            let mut failure = TransportError::NoAddress;
+            for address in addresses {
+                match TcpStream::connect(address).await {
+                    Ok(stream) => return Ok(open_connection(stream, &config)),
+                    Err(err) => failure = err.into(),
+                }
+            }
+            Err(failure)
+        })
+    }
+
+    /// Accepted connections. Several streams share the one listener; each
+    /// ends when accepting fails.
+    fn listen(&self) -> BoxFutureResponse<BoxStreamResponse<Connection>, Self::Error> {
+        let (listener, config) = (self.listener.clone(), self.config.clone());
+        Box::pin(async move {
+            let accepted = stream::unfold(listener, move |listener| {
+                let config = config.clone();
+                async move {
+                    let (stream, _) = listener.accept().await.ok()?;
+                    Some((open_connection(stream, &config), listener))
+                }
+            });
+            Ok(Box::pin(accepted) as BoxStreamResponse<Connection>)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::run_live;
+    use core::types::PeerId;
+
+    async fn transport() -> TcpTransport {
+        TcpTransport::bind(TcpConfig {
+            listen: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+            max_frame_len: 1024,
+        })
+        .await
+        .unwrap()
+    }
+
+    fn peer_at(transport: &TcpTransport) -> PeerInfo {
+        PeerInfo {
+            addresses: vec![TransportAddress::Socket(transport.local_addr().unwrap())],
+            ..PeerInfo::new(PeerId::from_public_key(b"alice"))
+        }
+    }
+
+    #[test]
+    fn frames_cross_a_loopback_connection_both_ways() {
+        run_live(async {
+            let (alice, bob) = (transport().await, transport().await);
+            let mut incoming = alice.listen().await.unwrap();
+
+            let mut to_alice = bob.dial(&peer_at(&alice)).await.unwrap();
+            let mut to_bob = incoming.next().await.unwrap();
+            assert_eq!(
+                to_alice.remote_address(),
+                Some(TransportAddress::Socket(alice.local_addr().unwrap()))
+            );
+
+            let frames = [b"hello".to_vec(), Vec::new(), vec![7; 1024]];
+            for frame in &frames {
+                to_alice.send(frame.clone()).await.unwrap();
+            }
+            for frame in &frames {
+                assert_eq!(&to_bob.next().await.unwrap(), frame);
+            }
+            to_bob.send(b"hi bob".to_vec()).await.unwrap();
+            assert_eq!(to_alice.next().await.unwrap(), b"hi bob");
+
+            to_alice.close().await.unwrap();
+            assert_eq!(to_bob.next().await, None);
+            assert_eq!(to_bob.close_reason(), Some(CloseReason::Remote));
+        });
+    }
+
+    #[test]
+    fn oversized_frames_cut_the_connection() {
+        run_live(async {
+            let alice = transport().await;
+            let mut incoming = alice.listen().await.unwrap();
+
+            let mut raw = TcpStream::connect(alice.local_addr().unwrap())
+                .await
+                .unwrap();
+            raw.write_all(&4096u32.to_be_bytes()).await.unwrap();
+            let mut connection = incoming.next().await.unwrap();
+
+            assert_eq!(connection.next().await, None);
+            assert!(matches!(
+                connection.close_reason(),
+                Some(CloseReason::Failed(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn dialing_needs_a_reachable_socket_address() {
+        run_live(async {
+            let bob = transport().await;
+            let nowhere = PeerInfo::new(PeerId::from_public_key(b"carol"));
+            assert!(matches!(
+                bob.dial(&nowhere).await,
+                Err(TransportError::NoAddress)
+            ));
+
+            let closed = transport().await;
+            let gone = peer_at(&closed);
+            drop(closed);
+            assert!(matches!(
+                bob.dial(&gone).await,
+                Err(TransportError::IoError(_))
+            ));
+        });
+    }
+}
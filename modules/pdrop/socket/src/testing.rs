@@ -0,0 +1,11 @@
+use std::future::Future;
+
+/// `#[tokio::test]` expands to `::core` paths, which resolve to the pdrop
+/// core crate here, so tests build their runtime by hand.
+pub fn run_live<F: Future>(test: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(test)
+}